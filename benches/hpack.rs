@@ -0,0 +1,122 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HPACK benchmarks. Run with `cargo bench --bench hpack`.
+//!
+//! Encodes a typical response header block with an encoder whose dynamic table is filled the
+//! way it is on a long-lived connection, so header lookups dominate the cost. The lookups of that
+//! block are then timed on their own, against the same table scanned linearly as it was before
+//! it was indexed. Finally decodes a typical request header block of static table hits and raw
+//! literals, with and without copying the headers.
+
+extern crate tokio_http2;
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use tokio_http2::hpack::{Decoder, Encoder, HuffmanPolicy, IndexingPolicy, DEFAULT_TABLE_SIZE};
+
+const ITERATIONS: u32 = 100000;
+
+fn main() {
+    let response: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (b":status".to_vec(), b"200".to_vec()),
+        (b"date".to_vec(), b"Mon, 21 Oct 2013 20:13:21 GMT".to_vec()),
+        (b"server".to_vec(), b"tokio-http2".to_vec()),
+        (b"content-type".to_vec(), b"text/html; charset=utf-8".to_vec()),
+        (b"content-length".to_vec(), b"1024".to_vec()),
+        (b"cache-control".to_vec(), b"private".to_vec()),
+        (b"vary".to_vec(), b"accept-encoding".to_vec()),
+        (b"x-request-id".to_vec(), b"5c9b3f1e".to_vec()),
+    ];
+
+    // Index the response headers, then fill the rest of the dynamic table with entries that
+    // never match, so they sit in front of the response headers.
+    let mut encoder = Encoder::new();
+    encoder.encode(response.iter().map(|h| (&h.0[..], &h.1[..])));
+    for i in 0.. {
+        let name = format!("x-filler-{}", i);
+        // An entry takes the length of its name and value plus 32 octets.
+        if encoder.dynamic_table_size() + name.len() + 1 + 32 > DEFAULT_TABLE_SIZE {
+            break;
+        }
+        encoder.encode(vec![(name.as_bytes(), &b"v"[..])]);
+    }
+    let entries = encoder.dynamic_table().len();
+
+    let start = Instant::now();
+    let mut bytes = 0;
+    for _ in 0..ITERATIONS {
+        bytes += encoder.encode(response.iter().map(|h| (&h.0[..], &h.1[..]))).len();
+    }
+    let elapsed = start.elapsed();
+
+    println!("encode response headers: {} ns/iter ({} bytes/iter)",
+             nanos(elapsed) / ITERATIONS as u64,
+             bytes / ITERATIONS as usize);
+
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..ITERATIONS {
+        found += response.iter().filter_map(|h| encoder.find_header((&h.0, &h.1))).count();
+    }
+    let indexed = nanos(start.elapsed()) / ITERATIONS as u64;
+
+    let start = Instant::now();
+    let mut found_linear = 0;
+    for _ in 0..ITERATIONS {
+        found_linear += response.iter().filter_map(|h| encoder.find_header_linear((&h.0, &h.1))).count();
+    }
+    let linear = nanos(start.elapsed()) / ITERATIONS as u64;
+    assert_eq!(found, found_linear);
+
+    println!("find response headers, indexed: {} ns/iter ({} dynamic table entries)", indexed, entries);
+    println!("find response headers, linear scan: {} ns/iter ({:.1}x slower)",
+             linear, linear as f64 / cmp::max(indexed, 1) as f64);
+
+    let request: Vec<(&[u8], &[u8])> = vec![
+        (b":method", b"GET"),
+        (b":scheme", b"https"),
+        (b":path", b"/static/css/site.css?v=20161201"),
+        (b":authority", b"www.example.com"),
+        (b"accept", b"text/css,*/*;q=0.1"),
+        (b"accept-encoding", b"gzip, deflate, br"),
+        (b"user-agent", b"Mozilla/5.0 (X11; Linux x86_64; rv:50.0) Gecko/20100101 Firefox/50.0"),
+        (b"referer", b"https://www.example.com/index.html"),
+    ];
+    // Nothing is indexed, so every iteration decodes the same thing.
+    let mut encoder = Encoder::new();
+    encoder.set_huffman_policy(HuffmanPolicy::Never);
+    for &(name, _) in &request {
+        encoder.set_indexing_policy(name, IndexingPolicy::NoIndex);
+    }
+    let block = encoder.encode(request.iter().cloned());
+    let mut decoder = Decoder::new();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(decoder.decode(&block).unwrap().len(), request.len());
+    }
+    println!("decode request headers: {} ns/iter", nanos(start.elapsed()) / ITERATIONS as u64);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(decoder.decode_borrowed(&block).unwrap().len(), request.len());
+    }
+    println!("decode_borrowed request headers: {} ns/iter", nanos(start.elapsed()) / ITERATIONS as u64);
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000000 + duration.subsec_nanos() as u64
+}
@@ -21,7 +21,7 @@ use std::string::FromUtf8Error;
 
 use httparse;
 use url;
-// use native_tls::Error as TlsError;
+use native_tls::Error as TlsError;
 
 use self::Error::{
     Method,
@@ -31,7 +31,7 @@ use self::Error::{
     Status,
     Timeout,
     Io,
-    // Tls,
+    Tls,
     TooLarge,
     Incomplete,
     Utf8
@@ -53,7 +53,7 @@ pub enum Error {
     Version,
     /// An invalid `Header`.
     Header,
-    /// A message head is too large to be reasonable.
+    /// A message head, or a body that has to be buffered, is too large to be reasonable.
     TooLarge,
     /// A message reached EOF, but is not complete.
     Incomplete,
@@ -64,7 +64,7 @@ pub enum Error {
     /// An `io::Error` that occurred while trying to read or write to a network stream.
     Io(IoError),
     /// An error from a SSL library.
-    // Tls(TlsError),
+    Tls(TlsError),
     /// Parsing a field as string failed
     Utf8(Utf8Error),
 
@@ -86,7 +86,7 @@ impl fmt::Display for Error {
         match *self {
             Uri(ref e) => fmt::Display::fmt(e, f),
             Io(ref e) => fmt::Display::fmt(e, f),
-            // Tls(ref e) => fmt::Display::fmt(e, f),
+            Tls(ref e) => fmt::Display::fmt(e, f),
             Utf8(ref e) => fmt::Display::fmt(e, f),
             ref e => f.write_str(e.description()),
         }
@@ -99,13 +99,13 @@ impl StdError for Error {
             Method => "Invalid Method specified",
             Version => "Invalid HTTP version specified",
             Header => "Invalid Header provided",
-            TooLarge => "Message head is too large",
+            TooLarge => "Message is too large",
             Status => "Invalid Status provided",
             Incomplete => "Message is incomplete",
             Timeout => "Timeout",
             Uri(ref e) => e.description(),
             Io(ref e) => e.description(),
-            // Tls(ref e) => e.description(),
+            Tls(ref e) => e.description(),
             Utf8(ref e) => e.description(),
             Error::__Nonexhaustive(ref void) =>  match *void {}
         }
@@ -114,7 +114,7 @@ impl StdError for Error {
     fn cause(&self) -> Option<&StdError> {
         match *self {
             Io(ref error) => Some(error),
-            // Tls(ref error) => Some(error),
+            Tls(ref error) => Some(error),
             Uri(ref error) => Some(error),
             Utf8(ref error) => Some(error),
             Error::__Nonexhaustive(ref void) =>  match *void {},
@@ -135,11 +135,11 @@ impl From<url::ParseError> for Error {
     }
 }
 
-// impl From<TlsError> for Error {
-//     fn from(err: TlsError) -> Error {
-//         Tls(err)
-//     }
-// }
+impl From<TlsError> for Error {
+    fn from(err: TlsError) -> Error {
+        Tls(err)
+    }
+}
 
 impl From<Utf8Error> for Error {
     fn from(err: Utf8Error) -> Error {
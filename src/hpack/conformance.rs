@@ -0,0 +1,354 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conformance tests for the HPACK encoder and decoder.
+//!
+//! The examples of RFC 7541 Appendix C are checked byte for byte in both directions, along with
+//! the dynamic table after every header block. The stories of the hpack-test-case corpus
+//! (https://github.com/http2jp/hpack-test-case) vendored under `tests/fixtures/hpack-test-case`
+//! are decoded and round-tripped as well.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+
+use super::{Decoder, Encoder, HuffmanPolicy, IndexingPolicy};
+
+/// One header block of an Appendix C example.
+struct Step {
+    /// The encoded block as printed in the RFC, i.e. hex with spaces.
+    wire: &'static str,
+    headers: &'static [(&'static str, &'static str)],
+    /// The dynamic table after the block, newest entry first.
+    table: &'static [(&'static str, &'static str)],
+    table_size: usize,
+}
+
+fn hex(wire: &str) -> Vec<u8> {
+    wire.replace(" ", "").from_hex().unwrap()
+}
+
+fn to_bytes(headers: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    headers.iter().map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+}
+
+fn to_owned(headers: Vec<(&[u8], &[u8])>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    headers.into_iter().map(|(n, v)| (n.to_vec(), v.to_vec())).collect()
+}
+
+/// Encodes and decodes the steps in sequence, each with the same encoder and decoder, and checks
+/// the wire format and both dynamic tables after every step.
+fn check_sequence(steps: &[Step], max_table_size: usize, huffman: HuffmanPolicy) {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+    encoder.set_huffman_policy(huffman);
+    // The examples index every header, cookies included.
+    encoder.set_indexing_policy(b"set-cookie", IndexingPolicy::Index);
+    if max_table_size != encoder.max_table_size() {
+        // The examples start out with the smaller table, so the size update goes in a block of
+        // its own.
+        encoder.set_max_allowed_table_size(max_table_size);
+        decoder.set_max_table_size(max_table_size);
+        let update = encoder.encode(Vec::new());
+        assert!(decoder.decode(&update).unwrap().is_empty());
+    }
+
+    for (i, step) in steps.iter().enumerate() {
+        let wire = hex(step.wire);
+        let headers = to_bytes(step.headers);
+        let table = to_bytes(step.table);
+
+        let encoded = encoder.encode(step.headers.iter().map(|&(n, v)| (n.as_bytes(), v.as_bytes())));
+        assert_eq!(encoded, wire, "encoding step {}", i);
+        assert_eq!(to_owned(encoder.dynamic_table()), table, "encoder table after step {}", i);
+        assert_eq!(encoder.dynamic_table_size(), step.table_size);
+
+        assert_eq!(decoder.decode(&wire).unwrap(), headers, "decoding step {}", i);
+        assert_eq!(to_owned(decoder.dynamic_table()), table, "decoder table after step {}", i);
+        assert_eq!(decoder.dynamic_table_size(), step.table_size);
+    }
+}
+
+const REQUESTS: [&'static [(&'static str, &'static str)]; 3] = [
+    &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")],
+    &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"),
+      ("cache-control", "no-cache")],
+    &[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+      (":authority", "www.example.com"), ("custom-key", "custom-value")],
+];
+
+const REQUEST_TABLES: [&'static [(&'static str, &'static str)]; 3] = [
+    &[(":authority", "www.example.com")],
+    &[("cache-control", "no-cache"), (":authority", "www.example.com")],
+    &[("custom-key", "custom-value"), ("cache-control", "no-cache"), (":authority", "www.example.com")],
+];
+
+const RESPONSES: [&'static [(&'static str, &'static str)]; 3] = [
+    &[(":status", "302"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+      ("location", "https://www.example.com")],
+    &[(":status", "307"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+      ("location", "https://www.example.com")],
+    &[(":status", "200"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+      ("location", "https://www.example.com"), ("content-encoding", "gzip"),
+      ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1")],
+];
+
+const RESPONSE_TABLES: [&'static [(&'static str, &'static str)]; 3] = [
+    &[("location", "https://www.example.com"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+      ("cache-control", "private"), (":status", "302")],
+    &[(":status", "307"), ("location", "https://www.example.com"),
+      ("date", "Mon, 21 Oct 2013 20:13:21 GMT"), ("cache-control", "private")],
+    &[("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+      ("content-encoding", "gzip"), ("date", "Mon, 21 Oct 2013 20:13:22 GMT")],
+];
+
+/// C.2: header field representations, each with a fresh context.
+#[test]
+fn test_rfc7541_c2_field_representations() {
+    let examples: &[(&str, &str, IndexingPolicy, &str, &[(&str, &str)], usize)] = &[
+        ("custom-key", "custom-header", IndexingPolicy::Index,
+         "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+         &[("custom-key", "custom-header")], 55),
+        (":path", "/sample/path", IndexingPolicy::NoIndex,
+         "040c 2f73 616d 706c 652f 7061 7468", &[], 0),
+        ("password", "secret", IndexingPolicy::NeverIndex,
+         "1008 7061 7373 776f 7264 0673 6563 7265 74", &[], 0),
+        (":method", "GET", IndexingPolicy::Index, "82", &[], 0),
+    ];
+
+    for &(name, value, policy, wire, table, table_size) in examples {
+        let mut encoder = Encoder::new();
+        encoder.set_huffman_policy(HuffmanPolicy::Never);
+        let mut encoded = Vec::new();
+        encoder.encode_header_with_policy_into((name.as_bytes(), value.as_bytes()), policy, &mut encoded)
+               .unwrap();
+        assert_eq!(encoded, hex(wire), "encoding {}", name);
+        assert_eq!(to_owned(encoder.dynamic_table()), to_bytes(table));
+        assert_eq!(encoder.dynamic_table_size(), table_size);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&hex(wire)).unwrap(), to_bytes(&[(name, value)]));
+        assert_eq!(to_owned(decoder.dynamic_table()), to_bytes(table));
+        assert_eq!(decoder.dynamic_table_size(), table_size);
+    }
+}
+
+/// C.3: requests without Huffman coding.
+#[test]
+fn test_rfc7541_c3_requests() {
+    check_sequence(&[
+        Step {
+            wire: "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            headers: REQUESTS[0],
+            table: REQUEST_TABLES[0],
+            table_size: 57,
+        },
+        Step {
+            wire: "8286 84be 5808 6e6f 2d63 6163 6865",
+            headers: REQUESTS[1],
+            table: REQUEST_TABLES[1],
+            table_size: 110,
+        },
+        Step {
+            wire: "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+            headers: REQUESTS[2],
+            table: REQUEST_TABLES[2],
+            table_size: 164,
+        },
+    ], 4096, HuffmanPolicy::Never);
+}
+
+/// C.4: requests with Huffman coding.
+#[test]
+fn test_rfc7541_c4_requests_huffman() {
+    check_sequence(&[
+        Step {
+            wire: "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            headers: REQUESTS[0],
+            table: REQUEST_TABLES[0],
+            table_size: 57,
+        },
+        Step {
+            wire: "8286 84be 5886 a8eb 1064 9cbf",
+            headers: REQUESTS[1],
+            table: REQUEST_TABLES[1],
+            table_size: 110,
+        },
+        Step {
+            wire: "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            headers: REQUESTS[2],
+            table: REQUEST_TABLES[2],
+            table_size: 164,
+        },
+    ], 4096, HuffmanPolicy::Always);
+}
+
+/// C.5: responses without Huffman coding, with a 256 octet table that entries get evicted from.
+#[test]
+fn test_rfc7541_c5_responses() {
+    check_sequence(&[
+        Step {
+            wire: "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 \
+                   3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 \
+                   7861 6d70 6c65 2e63 6f6d",
+            headers: RESPONSES[0],
+            table: RESPONSE_TABLES[0],
+            table_size: 222,
+        },
+        Step {
+            wire: "4803 3330 37c1 c0bf",
+            headers: RESPONSES[1],
+            table: RESPONSE_TABLES[1],
+            table_size: 222,
+        },
+        Step {
+            wire: "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 \
+                   474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 \
+                   454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 \
+                   6572 7369 6f6e 3d31",
+            headers: RESPONSES[2],
+            table: RESPONSE_TABLES[2],
+            table_size: 215,
+        },
+    ], 256, HuffmanPolicy::Never);
+}
+
+/// C.6: responses with Huffman coding, with a 256 octet table that entries get evicted from.
+#[test]
+fn test_rfc7541_c6_responses_huffman() {
+    check_sequence(&[
+        Step {
+            wire: "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 \
+                   82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            headers: RESPONSES[0],
+            table: RESPONSE_TABLES[0],
+            table_size: 222,
+        },
+        Step {
+            wire: "4883 640e ffc1 c0bf",
+            headers: RESPONSES[1],
+            table: RESPONSE_TABLES[1],
+            table_size: 222,
+        },
+        Step {
+            wire: "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b \
+                   d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 \
+                   0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+            headers: RESPONSES[2],
+            table: RESPONSE_TABLES[2],
+            table_size: 215,
+        },
+    ], 256, HuffmanPolicy::Always);
+}
+
+/// A header block of a hpack-test-case story.
+struct Case {
+    /// The table size to use from this block on, if it changes.
+    header_table_size: Option<usize>,
+    /// The encoded block. Stories made from captured headers, rather than by an encoder, have
+    /// none.
+    wire: Option<Vec<u8>>,
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Loads a story, i.e. a sequence of header blocks to be decoded with the same context.
+fn load_story(path: &Path) -> Vec<Case> {
+    let mut json = String::new();
+    File::open(path).unwrap().read_to_string(&mut json).unwrap();
+    let story = Json::from_str(&json).unwrap();
+
+    story.find("cases").and_then(Json::as_array).unwrap().iter().map(|case| {
+        let headers = case.find("headers").and_then(Json::as_array).unwrap().iter().map(|header| {
+            // Each header is an object with a single field.
+            let (name, value) = header.as_object().and_then(|h| h.iter().next()).unwrap();
+            (name.as_bytes().to_vec(), value.as_string().unwrap().as_bytes().to_vec())
+        }).collect();
+        Case {
+            header_table_size: case.find("header_table_size").and_then(Json::as_u64).map(|s| s as usize),
+            wire: case.find("wire").and_then(Json::as_string).map(hex),
+            headers: headers,
+        }
+    }).collect()
+}
+
+/// Loads every story of the vendored corpus, which keeps the stories of each implementation in a
+/// directory of their own.
+fn load_stories() -> Vec<(PathBuf, Vec<Case>)> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/hpack-test-case");
+    let mut paths = Vec::new();
+    for dir in fs::read_dir(root).unwrap() {
+        let dir = dir.unwrap().path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for story in fs::read_dir(dir).unwrap() {
+            let story = story.unwrap().path();
+            if story.extension().map_or(false, |e| e == "json") {
+                paths.push(story);
+            }
+        }
+    }
+    paths.sort();
+
+    paths.into_iter().map(|path| {
+        let story = load_story(&path);
+        (path, story)
+    }).collect()
+}
+
+#[test]
+fn test_hpack_test_case_decode() {
+    let stories = load_stories();
+    assert!(stories.iter().any(|&(_, ref cases)| cases.iter().any(|c| c.wire.is_some())));
+    // The stories of real encoders are there, not just the RFC examples.
+    for dir in &["go-hpack", "nghttp2"] {
+        assert!(stories.iter().any(|&(ref path, _)| path.parent().map_or(false, |p| p.ends_with(dir))),
+                "no stories in {}", dir);
+    }
+
+    for (path, cases) in stories {
+        let mut decoder = Decoder::new();
+        for (seqno, case) in cases.into_iter().enumerate() {
+            let wire = match case.wire {
+                Some(wire) => wire,
+                None => break,
+            };
+            if let Some(size) = case.header_table_size {
+                decoder.set_max_table_size(size);
+            }
+            assert_eq!(decoder.decode(&wire).unwrap(), case.headers,
+                       "{} seqno {}", path.display(), seqno);
+        }
+    }
+}
+
+#[test]
+fn test_hpack_test_case_roundtrip() {
+    for (path, cases) in load_stories() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        for (seqno, case) in cases.into_iter().enumerate() {
+            if let Some(size) = case.header_table_size {
+                encoder.set_max_allowed_table_size(size);
+                decoder.set_max_table_size(size);
+            }
+            let encoded = encoder.encode(case.headers.iter().map(|h| (&h.0[..], &h.1[..])));
+            assert_eq!(decoder.decode(&encoded).unwrap(), case.headers,
+                       "{} seqno {}", path.display(), seqno);
+        }
+    }
+}
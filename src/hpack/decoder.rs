@@ -10,7 +10,7 @@
 //! A simple example of using the decoder that demonstrates its API:
 //!
 //! ```rust
-//! use hpack::Decoder;
+//! use tokio_http2::hpack::Decoder;
 //! let mut decoder = Decoder::new();
 //!
 //! let header_list = decoder.decode(&[0x82, 0x84]).unwrap();
@@ -25,7 +25,7 @@
 //! borrowed representation of each header, rather than an owned representation.
 //!
 //! ```rust
-//! use hpack::Decoder;
+//! use tokio_http2::hpack::Decoder;
 //! let mut decoder = Decoder::new();
 //!
 //! let mut count = 0;
@@ -47,13 +47,25 @@
 
 use std::num::Wrapping;
 use std::borrow::Cow;
+use std::cell::RefCell;
 
 use super::huffman::HuffmanDecoder;
 use super::huffman::HuffmanDecoderError;
 
 use super::STATIC_TABLE;
+use super::DEFAULT_TABLE_SIZE;
 use super::{StaticTable, HeaderTable};
 
+/// Default limit on the size of a decoded header list, counted as in
+/// `DecoderLimits::max_header_list_size`.
+pub const DEFAULT_MAX_HEADER_LIST_SIZE: usize = 65536;
+
+/// Default limit on the length of a single decoded name or value.
+pub const DEFAULT_MAX_STRING_LENGTH: usize = 16384;
+
+/// Default limit on the number of headers in a header list.
+pub const DEFAULT_MAX_HEADERS: usize = 1000;
+
 /// Decodes an integer encoded with a given prefix size (in bits).
 /// Assumes that the buffer `buf` contains the integer to be decoded,
 /// with the first byte representing the octet that contains the
@@ -121,15 +133,21 @@ fn decode_integer(buf: &[u8], prefix_size: u8)
             IntegerDecodingError::NotEnoughOctets))
 }
 
+// Building the decoding table takes far longer than decoding a string with it.
+thread_local!(static HUFFMAN: RefCell<HuffmanDecoder> = RefCell::new(HuffmanDecoder::new()));
+
 /// Decodes an octet string under HPACK rules of encoding found in the given
 /// buffer `buf`.
 ///
 /// It is assumed that the first byte in the buffer represents the start of the
 /// encoded octet string.
 ///
+/// Strings longer than `max_len` octets are rejected before they are
+/// decoded.
+///
 /// Returns the decoded string in a newly allocated `Vec` and the number of
 /// bytes consumed from the given buffer.
-fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderError> {
+fn decode_string<'a>(buf: &'a [u8], max_len: usize) -> Result<(Cow<'a, [u8]>, usize), DecoderError> {
     let (len, consumed) = try!(decode_integer(buf, 7));
     // debug!("decode_string: Consumed = {}, len = {}", consumed, len);
     if consumed + len > buf.len() {
@@ -137,19 +155,27 @@ fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderErr
             DecoderError::StringDecodingError(
                 StringDecodingError::NotEnoughOctets));
     }
+    // The longest Huffman code has 30 bits, which bounds the decoded length
+    // from below without decoding anything.
+    let min_len = if buf[0] & 128 == 128 { len * 8 / 30 } else { len };
+    if min_len > max_len {
+        return Err(DecoderError::LimitExceeded(DecoderLimit::StringLength));
+    }
     let raw_string = &buf[consumed..consumed + len];
     if buf[0] & 128 == 128 {
         // debug!("decode_string: Using the Huffman code");
         // Huffman coding used: pass the raw octets to the Huffman decoder
         // and return its result.
-        let mut decoder = HuffmanDecoder::new();
-        let decoded = match decoder.decode(raw_string) {
+        let decoded = match HUFFMAN.with(|decoder| decoder.borrow_mut().decode(raw_string)) {
             Err(e) => {
                 return Err(DecoderError::StringDecodingError(
                     StringDecodingError::HuffmanDecoderError(e)));
             },
             Ok(res) => res,
         };
+        if decoded.len() > max_len {
+            return Err(DecoderError::LimitExceeded(DecoderLimit::StringLength));
+        }
         Ok((Cow::Owned(decoded), consumed + len))
     } else {
         // The octets were transmitted raw
@@ -193,6 +219,52 @@ impl FieldRepresentation {
     }
 }
 
+/// A decoded header name or value: a reference to an entry of the header
+/// table, or the octets read from the header block.
+enum Octets<'b> {
+    /// The name of the table entry with the given index.
+    Name(usize),
+    /// The value of the table entry with the given index.
+    Value(usize),
+    Literal(Cow<'b, [u8]>),
+}
+
+impl<'b> Octets<'b> {
+    /// Returns the octets, looking them up in `table` if needed. The index
+    /// has to be in the table.
+    fn get<'t>(&'t self, table: &'t HeaderTable) -> &'t [u8] {
+        match *self {
+            Octets::Name(index) => table.get_from_table(index).unwrap().0,
+            Octets::Value(index) => table.get_from_table(index).unwrap().1,
+            Octets::Literal(ref octets) => octets,
+        }
+    }
+
+    /// Converts into a `Cow` that borrows entries of the header table.
+    fn into_cow<'t>(self, table: &'t HeaderTable) -> Cow<'t, [u8]> where 'b: 't {
+        match self {
+            Octets::Name(index) => Cow::Borrowed(table.get_from_table(index).unwrap().0),
+            Octets::Value(index) => Cow::Borrowed(table.get_from_table(index).unwrap().1),
+            Octets::Literal(octets) => octets,
+        }
+    }
+
+    /// Converts into a `Cow` that only borrows entries of the static table,
+    /// since the dynamic table may change with the next header.
+    fn into_static_cow<'a>(self, table: &HeaderTable<'a>) -> Cow<'b, [u8]> where 'a: 'b {
+        match self {
+            Octets::Name(index) if index <= table.static_table.len() => {
+                Cow::Borrowed(table.static_table[index - 1].0)
+            },
+            Octets::Value(index) if index <= table.static_table.len() => {
+                Cow::Borrowed(table.static_table[index - 1].1)
+            },
+            Octets::Literal(octets) => octets,
+            dynamic_octets => Cow::Owned(dynamic_octets.get(table).to_vec()),
+        }
+    }
+}
+
 /// Represents all errors that can be encountered while decoding an
 /// integer.
 #[derive(PartialEq)]
@@ -226,6 +298,17 @@ pub enum StringDecodingError {
     HuffmanDecoderError(HuffmanDecoderError),
 }
 
+/// The limit of `DecoderLimits` that a header block exceeded.
+#[derive(PartialEq)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum DecoderLimit {
+    HeaderListSize,
+    StringLength,
+    HeaderCount,
+}
+
 /// Represents all errors that can be encountered while performing the decoding
 /// of an HPACK header set.
 #[derive(PartialEq)]
@@ -240,6 +323,40 @@ pub enum DecoderError {
     /// size mandated to the decoder by the protocol. (by perfroming changes
     /// made by SizeUpdate blocks).
     InvalidMaxDynamicSize,
+    /// The header block decodes to more than the decoder is willing to
+    /// accept. Decoding stops before the offending header is handed out.
+    LimitExceeded(DecoderLimit),
+}
+
+/// Bounds on what a single header block may decode to.
+///
+/// HPACK lets a small block expand into a very large header list, e.g. by
+/// referencing a large dynamic table entry over and over. These limits are
+/// checked as the block is decoded, so such a block fails with
+/// `DecoderError::LimitExceeded` instead of being expanded in memory.
+#[derive(PartialEq)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct DecoderLimits {
+    /// The maximum size of the decoded header list, counted as in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE: the length of each name and value plus
+    /// 32 octets per header.
+    pub max_header_list_size: usize,
+    /// The maximum length of a single decoded name or value.
+    pub max_string_length: usize,
+    /// The maximum number of headers in the list.
+    pub max_headers: usize,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> DecoderLimits {
+        DecoderLimits {
+            max_header_list_size: DEFAULT_MAX_HEADER_LIST_SIZE,
+            max_string_length: DEFAULT_MAX_STRING_LENGTH,
+            max_headers: DEFAULT_MAX_HEADERS,
+        }
+    }
 }
 
 /// The result returned by the `decode` method of the `Decoder`.
@@ -253,6 +370,9 @@ pub type DecoderResult = Result<Vec<(Vec<u8>, Vec<u8>)>, DecoderError>;
 pub struct Decoder<'a> {
     // The dynamic table will own its own copy of headers
     header_table: HeaderTable<'a>,
+    limits: DecoderLimits,
+    /// The largest table size that a `SizeUpdate` may ask for.
+    max_allowed_table_size: usize,
 }
 
 /// Represents a decoder of HPACK encoded headers. Maintains the state
@@ -274,15 +394,40 @@ impl<'a> Decoder<'a> {
     ///       the one defined in the HPACK spec.
     fn with_static_table(static_table: StaticTable<'a>) -> Decoder<'a> {
         Decoder {
-            header_table: HeaderTable::with_static_table(static_table)
+            header_table: HeaderTable::with_static_table(static_table),
+            limits: DecoderLimits::default(),
+            max_allowed_table_size: DEFAULT_TABLE_SIZE,
         }
     }
 
-    /// Sets a new maximum dynamic table size for the decoder.
+    /// Sets a new maximum dynamic table size for the decoder. The peer's
+    /// `SizeUpdate`s may not go above it.
     pub fn set_max_table_size(&mut self, new_max_size: usize) {
+        self.max_allowed_table_size = new_max_size;
         self.header_table.dynamic_table.set_max_table_size(new_max_size);
     }
 
+    /// Returns the limits that header blocks are decoded under.
+    pub fn limits(&self) -> DecoderLimits {
+        self.limits
+    }
+
+    /// Sets the limits that subsequent header blocks are decoded under.
+    pub fn set_limits(&mut self, limits: DecoderLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns the headers in the dynamic table, newest first.
+    pub fn dynamic_table(&self) -> Vec<(&[u8], &[u8])> {
+        self.header_table.dynamic_table.iter().collect()
+    }
+
+    /// Returns the size of the dynamic table as defined by the HPACK spec,
+    /// i.e. the length of every name and value plus 32 octets per entry.
+    pub fn dynamic_table_size(&self) -> usize {
+        self.header_table.dynamic_table.get_size()
+    }
+
     /// Decodes the headers found in the given buffer `buf`. Invokes the callback `cb` for each
     /// decoded header in turn, by providing it the header name and value as `Cow` byte array
     /// slices.
@@ -297,9 +442,68 @@ impl<'a> Decoder<'a> {
     /// decoding begins, meaning until the end of the callback's body.
     ///
     /// If an error is encountered during the decoding of any header, decoding halts and the
-    /// appropriate error is returned as the `Err` variant of the `Result`.
+    /// appropriate error is returned as the `Err` variant of the `Result`. This includes exceeding
+    /// the decoder's `DecoderLimits`, which is detected before the callback sees the header.
     pub fn decode_with_cb<F>(&mut self, buf: &[u8], mut cb: F) -> Result<(), DecoderError>
             where F: FnMut(Cow<[u8]>, Cow<[u8]>) {
+        self.decode_fields(buf, |table, name, value| cb(name.into_cow(table), value.into_cow(table)))
+    }
+
+    /// Decode the header block found in the given buffer.
+    ///
+    /// The decoded representation is returned as a sequence of headers, where both the name and
+    /// value of each header is represented by an owned byte sequence (i.e. `Vec<u8>`).
+    ///
+    /// The buffer should represent the entire block that should be decoded.
+    /// For example, in HTTP/2, all continuation frames need to be concatenated
+    /// to a single buffer before passing them to the decoder.
+    pub fn decode(&mut self, buf: &[u8]) -> DecoderResult {
+        let header_list = try!(self.decode_borrowed(buf));
+
+        Ok(header_list.into_iter().map(|(n, v)| (n.into_owned(), v.into_owned())).collect())
+    }
+
+    /// Decode the header block found in the given buffer, without copying what does not have to
+    /// be copied.
+    ///
+    /// Names and values found in the static table are borrowed from it, and literals that are not
+    /// Huffman encoded are borrowed from `buf`. Only Huffman encoded literals and headers taken
+    /// from the dynamic table, which may change with the next header, are owned.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use tokio_http2::hpack::Decoder;
+    ///
+    /// let mut decoder = Decoder::new();
+    /// let block = [0x82, 0x04, 0x05, b'/', b'a', b'b', b'c', b'd'];
+    /// let header_list = decoder.decode_borrowed(&block).unwrap();
+    ///
+    /// assert_eq!(header_list, [
+    ///     (Cow::Borrowed(&b":method"[..]), Cow::Borrowed(&b"GET"[..])),
+    ///     (Cow::Borrowed(&b":path"[..]), Cow::Borrowed(&block[3..])),
+    /// ]);
+    /// ```
+    pub fn decode_borrowed<'b>(&mut self, buf: &'b [u8]) -> Result<Vec<(Cow<'b, [u8]>, Cow<'b, [u8]>)>, DecoderError>
+            where 'a: 'b {
+        let mut header_list = Vec::new();
+
+        try!(self.decode_fields(buf, |table, name, value| {
+            header_list.push((name.into_static_cow(table), value.into_static_cow(table)));
+        }));
+
+        Ok(header_list)
+    }
+
+    /// Decodes the header block in `buf` and hands each header to `emit`, along with the header
+    /// table its names and values may refer to. Headers to be indexed are added to the dynamic
+    /// table after `emit` returns.
+    fn decode_fields<'b, F>(&mut self, buf: &'b [u8], mut emit: F) -> Result<(), DecoderError>
+            where F: FnMut(&HeaderTable<'a>, Octets<'b>, Octets<'b>) {
+        let limits = self.limits;
+        let mut header_count = 0;
+        let mut header_list_size = 0;
         let mut current_octet_index = 0;
 
         while current_octet_index < buf.len() {
@@ -309,91 +513,66 @@ impl<'a> Decoder<'a> {
             // byte.
             let initial_octet = buf[current_octet_index];
             let buffer_leftover = &buf[current_octet_index..];
-            let consumed = match FieldRepresentation::new(initial_octet) {
+            let (name, value, index, consumed) = match FieldRepresentation::new(initial_octet) {
                 FieldRepresentation::Indexed => {
-                    let ((name, value), consumed) =
-                        try!(self.decode_indexed(buffer_leftover));
-                    cb(Cow::Borrowed(name), Cow::Borrowed(value));
-
-                    consumed
+                    let (index, consumed) = try!(self.decode_indexed(buffer_leftover));
+                    (Octets::Name(index), Octets::Value(index), false, consumed)
                 },
                 FieldRepresentation::LiteralWithIncrementalIndexing => {
-                    let ((name, value), consumed) = {
-                        let ((name, value), consumed) = try!(
-                            self.decode_literal(buffer_leftover, true));
-                        cb(Cow::Borrowed(&name), Cow::Borrowed(&value));
-
-                        // Since we are to add the decoded header to the header table, we need to
-                        // convert them into owned buffers that the decoder can keep internally.
-                        let name = name.into_owned();
-                        let value = value.into_owned();
-
-                        ((name, value), consumed)
-                    };
-                    // This cannot be done in the same scope as the `decode_literal` call, since
-                    // Rust cannot figure out that the `into_owned` calls effectively drop the
-                    // borrow on `self` that the `decode_literal` return value had. Since adding
-                    // a header to the table requires a `&mut self`, it fails to compile.
-                    // Manually separating it out here works around it...
-                    self.header_table.add_header(name, value);
-
-                    consumed
-                },
-                FieldRepresentation::LiteralWithoutIndexing => {
-                    let ((name, value), consumed) =
-                        try!(self.decode_literal(buffer_leftover, false));
-                    cb(name, value);
-
-                    consumed
+                    let ((name, value), consumed) = try!(self.decode_literal(buffer_leftover, true));
+                    (name, Octets::Literal(value), true, consumed)
                 },
+                FieldRepresentation::LiteralWithoutIndexing |
                 FieldRepresentation::LiteralNeverIndexed => {
-                    // Same as the previous one, except if we were also a proxy
-                    // we would need to make sure not to change the
-                    // representation received here. We don't care about this
-                    // for now.
-                    let ((name, value), consumed) =
-                        try!(self.decode_literal(buffer_leftover, false));
-                    cb(name, value);
-
-                    consumed
+                    // If we were also a proxy, we would need to make sure not to change the
+                    // representation of never indexed fields. We don't care about this for now.
+                    let ((name, value), consumed) = try!(self.decode_literal(buffer_leftover, false));
+                    (name, Octets::Literal(value), false, consumed)
                 },
                 FieldRepresentation::SizeUpdate => {
                     // Handle the dynamic table size update...
-                    self.update_max_dynamic_size(buffer_leftover)
+                    current_octet_index += try!(self.update_max_dynamic_size(buffer_leftover));
+                    continue;
                 }
             };
 
+            header_count += 1;
+            header_list_size += name.get(&self.header_table).len() + value.get(&self.header_table).len() + 32;
+            if header_count > limits.max_headers {
+                return Err(DecoderError::LimitExceeded(DecoderLimit::HeaderCount));
+            }
+            if header_list_size > limits.max_header_list_size {
+                return Err(DecoderError::LimitExceeded(DecoderLimit::HeaderListSize));
+            }
+
+            // The dynamic table needs its own copy of headers to be indexed.
+            let entry = if index {
+                Some((name.get(&self.header_table).to_vec(), value.get(&self.header_table).to_vec()))
+            } else {
+                None
+            };
+            emit(&self.header_table, name, value);
+            if let Some((name, value)) = entry {
+                self.header_table.add_header(name, value);
+            }
+
             current_octet_index += consumed;
         }
 
         Ok(())
     }
 
-    /// Decode the header block found in the given buffer.
-    ///
-    /// The decoded representation is returned as a sequence of headers, where both the name and
-    /// value of each header is represented by an owned byte sequence (i.e. `Vec<u8>`).
-    ///
-    /// The buffer should represent the entire block that should be decoded.
-    /// For example, in HTTP/2, all continuation frames need to be concatenated
-    /// to a single buffer before passing them to the decoder.
-    pub fn decode(&mut self, buf: &[u8]) -> DecoderResult {
-        let mut header_list = Vec::new();
-
-        try!(self.decode_with_cb(buf, |n, v| header_list.push((n.into_owned(), v.into_owned()))));
-
-        Ok(header_list)
-    }
-
     /// Decodes an indexed header representation.
-    fn decode_indexed(&self, buf: &[u8])
-            -> Result<((&[u8], &[u8]), usize), DecoderError> {
+    ///
+    /// Returns the index, which is known to be in the table, and the number
+    /// of octets consumed.
+    fn decode_indexed(&self, buf: &[u8]) -> Result<(usize, usize), DecoderError> {
         let (index, consumed) = try!(decode_integer(buf, 7));
         // debug!("Decoding indexed: index = {}, consumed = {}", index, consumed);
 
-        let (name, value) = try!(self.get_from_table(index));
+        try!(self.get_from_table(index));
 
-        Ok(((name, value), consumed))
+        Ok((index, consumed))
     }
 
     /// Gets the header (name, value) pair with the given index from the table.
@@ -413,8 +592,8 @@ impl<'a> Decoder<'a> {
     ///
     /// - index: whether or not the decoded value should be indexed (i.e.
     ///   included in the dynamic table).
-    fn decode_literal<'b>(&'b self, buf: &'b [u8], index: bool)
-            -> Result<((Cow<[u8]>, Cow<[u8]>), usize), DecoderError> {
+    fn decode_literal<'b>(&self, buf: &'b [u8], index: bool)
+            -> Result<((Octets<'b>, Cow<'b, [u8]>), usize), DecoderError> {
         let prefix = if index {
             6
         } else {
@@ -425,17 +604,17 @@ impl<'a> Decoder<'a> {
         // First read the name appropriately
         let name = if table_index == 0 {
             // Read name string as literal
-            let (name, name_len) = try!(decode_string(&buf[consumed..]));
+            let (name, name_len) = try!(decode_string(&buf[consumed..], self.limits.max_string_length));
             consumed += name_len;
-            name
+            Octets::Literal(name)
         } else {
             // Read name indexed from the table
-            let (name, _) = try!(self.get_from_table(table_index));
-            Cow::Borrowed(name)
+            try!(self.get_from_table(table_index));
+            Octets::Name(table_index)
         };
 
         // Now read the value as a literal...
-        let (value, value_len) = try!(decode_string(&buf[consumed..]));
+        let (value, value_len) = try!(decode_string(&buf[consumed..], self.limits.max_string_length));
         consumed += value_len;
 
         Ok(((name, value), consumed))
@@ -448,15 +627,20 @@ impl<'a> Decoder<'a> {
     /// Assumes that the first byte in the given buffer `buf` is the first
     /// octet in the `SizeUpdate` block.
     ///
-    /// Returns the number of octets consumed from the given buffer.
-    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> usize {
-        let (new_size, consumed) = decode_integer(buf, 5).ok().unwrap();
+    /// Returns the number of octets consumed from the given buffer, or
+    /// `InvalidMaxDynamicSize` if the new size is larger than the decoder
+    /// allows.
+    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> Result<usize, DecoderError> {
+        let (new_size, consumed) = try!(decode_integer(buf, 5));
+        if new_size > self.max_allowed_table_size {
+            return Err(DecoderError::InvalidMaxDynamicSize);
+        }
         self.header_table.dynamic_table.set_max_table_size(new_size);
 
         // info!("Decoder changed max table size from {} to {}",
             //   self.header_table.dynamic_table.get_size(),
             //   new_size);
 
-        consumed
+        Ok(consumed)
     }
 }
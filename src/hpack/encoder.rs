@@ -9,7 +9,7 @@
 //! Encodes a header using a literal encoding.
 //!
 //! ```rust
-//! use hpack::Encoder;
+//! use tokio_http2::hpack::Encoder;
 //!
 //! let mut encoder = Encoder::new();
 //!
@@ -20,19 +20,19 @@
 //! let result = encoder.encode(headers);
 //! // The result is a literal encoding of the header name and value, with an
 //! // initial byte representing the type of the encoding
-//! // (incremental indexing).
+//! // (incremental indexing). Both strings are shorter when Huffman encoded,
+//! // which the high bit of their length prefix indicates (RFC 7541 C.4.1).
 //! assert_eq!(
 //!     vec![0x40,
-//!          10, b'c', b'u', b's', b't', b'o', b'm', b'-', b'k', b'e', b'y',
-//!          12, b'c', b'u', b's', b't', b'o', b'm', b'-', b'v', b'a', b'l',
-//!          b'u', b'e'],
+//!          0x80 | 8, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+//!          0x80 | 9, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf],
 //!     result);
 //! ```
 //!
 //! Encodes some pseudo-headers that are already found in the static table.
 //!
 //! ```rust
-//! use hpack::Encoder;
+//! use tokio_http2::hpack::Encoder;
 //!
 //! let mut encoder = Encoder::new();
 //! let headers = vec![
@@ -44,11 +44,15 @@
 //! // indicating that the indexed representation is used).
 //! assert_eq!(encoder.encode(headers), vec![2 | 0x80, 4 | 0x80]);
 //! ```
+use std::cmp;
 use std::io;
 use std::num::Wrapping;
+use std::collections::HashMap;
 
 use super::STATIC_TABLE;
 use super::HeaderTable;
+use super::huffman::HuffmanEncoder;
+use super::DEFAULT_TABLE_SIZE;
 
 /// Encode an integer to the representation defined by HPACK, writing it into the provider
 /// `io::Write` instance. Also allows the caller to specify the leading bits of the first
@@ -60,7 +64,7 @@ use super::HeaderTable;
 /// # Example
 ///
 /// ```rust
-/// use hpack::encoder::encode_integer_into;
+/// use tokio_http2::hpack::encoder::encode_integer_into;
 ///
 /// {
 ///     // No bits specified in the 3 most significant bits of the first octet
@@ -130,6 +134,39 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
     res
 }
 
+/// Header fields that are encoded as "never indexed" literals by default. Their values are
+/// secrets that compression based attacks like CRIME could otherwise recover.
+pub const SENSITIVE_HEADERS: &'static [&'static [u8]] = &[
+    b"authorization",
+    b"cookie",
+    b"proxy-authorization",
+    b"set-cookie",
+];
+
+/// How a header field that is not taken from the header table is represented (HPACK spec
+/// section 6.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexingPolicy {
+    /// Literal with incremental indexing: the field is added to the dynamic table.
+    Index,
+    /// Literal without indexing: the field is sent as a literal and not added to the table.
+    NoIndex,
+    /// Literal never indexed: like `NoIndex`, but intermediaries must not index the field
+    /// either when forwarding it.
+    NeverIndex,
+}
+
+/// When string literals are Huffman encoded (HPACK spec section 5.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HuffmanPolicy {
+    /// Huffman encode a string only if that makes it shorter.
+    WhenShorter,
+    /// Always Huffman encode strings.
+    Always,
+    /// Never Huffman encode strings.
+    Never,
+}
+
 /// Represents an HPACK encoder. Allows clients to encode arbitrary header sets
 /// and tracks the encoding context. That is, encoding subsequent header sets
 /// will use the context built by previous encode calls.
@@ -142,7 +179,7 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 /// representations, due to the utilization of HPACK compression.
 ///
 /// ```rust
-/// use hpack::Encoder;
+/// use tokio_http2::hpack::Encoder;
 ///
 /// let mut encoder = Encoder::new();
 ///
@@ -156,9 +193,8 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 /// // (incremental indexing).
 /// assert_eq!(
 ///     vec![0x40,
-///          10, b'c', b'u', b's', b't', b'o', b'm', b'-', b'k', b'e', b'y',
-///          12, b'c', b'u', b's', b't', b'o', b'm', b'-', b'v', b'a', b'l',
-///          b'u', b'e'],
+///          0x80 | 8, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+///          0x80 | 9, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf],
 ///     result);
 ///
 /// // Encode the same headers again!
@@ -170,6 +206,18 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+    huffman: HuffmanEncoder,
+    huffman_policy: HuffmanPolicy,
+    /// Indexing policies by header name, for names that are not indexed as usual.
+    policies: HashMap<Vec<u8>, IndexingPolicy>,
+    /// The dynamic table size the encoder would like to use.
+    preferred_table_size: usize,
+    /// The largest dynamic table size the decoder allows, i.e. its
+    /// SETTINGS_HEADER_TABLE_SIZE in HTTP/2.
+    max_allowed_table_size: usize,
+    /// The smallest and the last table size set since the previous header
+    /// block, which the next block has to announce.
+    size_update: Option<(usize, usize)>,
 }
 
 impl<'a> Encoder<'a> {
@@ -178,20 +226,115 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            huffman: HuffmanEncoder::new(),
+            huffman_policy: HuffmanPolicy::WhenShorter,
+            policies: SENSITIVE_HEADERS.iter()
+                                       .map(|name| (name.to_vec(), IndexingPolicy::NeverIndex))
+                                       .collect(),
+            preferred_table_size: DEFAULT_TABLE_SIZE,
+            max_allowed_table_size: DEFAULT_TABLE_SIZE,
+            size_update: None,
+        }
+    }
+
+    /// Returns the maximum size of the dynamic table currently in use.
+    pub fn max_table_size(&self) -> usize {
+        self.header_table.dynamic_table.get_max_table_size()
+    }
+
+    /// Sets the dynamic table size the encoder would like to use. The table
+    /// never grows beyond what the decoder allows, see
+    /// `set_max_allowed_table_size`.
+    pub fn set_max_table_size(&mut self, size: usize) {
+        self.preferred_table_size = size;
+        self.resize_table();
+    }
+
+    /// Sets the largest dynamic table size the decoder allows, e.g. when the
+    /// peer's SETTINGS_HEADER_TABLE_SIZE changes.
+    ///
+    /// If that changes the size of the table, entries are evicted as needed
+    /// and the next header block starts with a Dynamic Table Size Update
+    /// (HPACK spec section 6.3).
+    pub fn set_max_allowed_table_size(&mut self, size: usize) {
+        self.max_allowed_table_size = size;
+        self.resize_table();
+    }
+
+    fn resize_table(&mut self) {
+        let size = cmp::min(self.preferred_table_size, self.max_allowed_table_size);
+        if size == self.max_table_size() {
+            return;
+        }
+        self.header_table.dynamic_table.set_max_table_size(size);
+        // If the size went down and up again, the decoder has to see the
+        // smallest size too, so that it evicts the same entries (section 4.2).
+        self.size_update = Some(match self.size_update {
+            Some((smallest, _)) => (cmp::min(smallest, size), size),
+            None => (size, size),
+        });
+    }
+
+    /// Sets how header fields with the given name are represented. Names are matched exactly,
+    /// so they have to be lowercase like all HTTP/2 header names.
+    ///
+    /// All headers default to `IndexingPolicy::Index`, except for `SENSITIVE_HEADERS` which
+    /// default to `IndexingPolicy::NeverIndex`.
+    pub fn set_indexing_policy(&mut self, name: &[u8], policy: IndexingPolicy) {
+        if policy == IndexingPolicy::Index {
+            self.policies.remove(name);
+        } else {
+            self.policies.insert(name.to_vec(), policy);
         }
     }
 
+    /// Returns how header fields with the given name are represented.
+    pub fn indexing_policy(&self, name: &[u8]) -> IndexingPolicy {
+        self.policies.get(name).cloned().unwrap_or(IndexingPolicy::Index)
+    }
+
+    /// Sets when string literals are Huffman encoded. Defaults to
+    /// `HuffmanPolicy::WhenShorter`.
+    pub fn set_huffman_policy(&mut self, policy: HuffmanPolicy) {
+        self.huffman_policy = policy;
+    }
+
+    /// Returns the headers in the dynamic table, newest first.
+    pub fn dynamic_table(&self) -> Vec<(&[u8], &[u8])> {
+        self.header_table.dynamic_table.iter().collect()
+    }
+
+    /// Returns the size of the dynamic table as defined by the HPACK spec,
+    /// i.e. the length of every name and value plus 32 octets per entry.
+    pub fn dynamic_table_size(&self) -> usize {
+        self.header_table.dynamic_table.get_size()
+    }
+
+    /// Looks a header up in the header table the way `encode` does, returning
+    /// its index and whether the value matched as well.
+    #[doc(hidden)]
+    pub fn find_header(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
+        self.header_table.find_header(header)
+    }
+
+    /// Looks a header up with a linear scan of the header table, as lookups
+    /// were done before the tables were indexed. For benchmarks only.
+    #[doc(hidden)]
+    pub fn find_header_linear(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
+        self.header_table.find_header_linear(header)
+    }
+
     /// Encodes the given headers using the HPACK rules and returns a newly
     /// allocated `Vec` containing the bytes representing the encoded header
     /// set.
     ///
-    /// The encoder so far supports only a single, extremely simple encoding
-    /// strategy, whereby each header is represented as an indexed header if
-    /// already found in the header table and a literal otherwise. When a
-    /// header isn't found in the table, it is added if the header name wasn't
-    /// found either (i.e. there are never two header names with different
-    /// values in the produced header table). Strings are always encoded as
-    /// literals (Huffman encoding is not used).
+    /// The encoder so far supports only a single, simple encoding strategy,
+    /// the one of the examples in the HPACK spec (Appendix C): each header is
+    /// represented as an indexed header if already found in the header table
+    /// and as a literal otherwise, referencing the name in the table if it is
+    /// there. Unless the indexing policy of its name says otherwise, a literal
+    /// is added to the dynamic table. Strings are Huffman encoded according to
+    /// the `HuffmanPolicy`.
     pub fn encode<'b, I>(&mut self, headers: I) -> Vec<u8>
             where I: IntoIterator<Item=(&'b [u8], &'b [u8])> {
         let mut encoded: Vec<u8> = Vec::new();
@@ -206,13 +349,23 @@ impl<'a> Encoder<'a> {
     pub fn encode_into<'b, I, W>(&mut self, headers: I, writer: &mut W) -> io::Result<()>
             where I: IntoIterator<Item=(&'b [u8], &'b [u8])>,
                   W: io::Write {
+        if let Some((smallest, last)) = self.size_update.take() {
+            try!(encode_integer_into(smallest, 5, 0x20, writer));
+            if last != smallest {
+                try!(encode_integer_into(last, 5, 0x20, writer));
+            }
+        }
         for header in headers {
             try!(self.encode_header_into(header, writer));
         }
         Ok(())
     }
 
-    /// Encodes a single given header into the given `io::Write` instance.
+    /// Encodes a single given header into the given `io::Write` instance, following the
+    /// indexing policy set for its name.
+    ///
+    /// Dynamic Table Size Updates are only emitted by `encode_into`, since they have to come
+    /// first in a header block.
     ///
     /// Any errors are propagated, similarly to the `encode_into` method, and it is the callers
     /// responsiblity to make sure that the paired encoder sees them too.
@@ -221,24 +374,49 @@ impl<'a> Encoder<'a> {
             header: (&[u8], &[u8]),
             writer: &mut W)
             -> io::Result<()> {
-        match self.header_table.find_header(header) {
-            None => {
+        let policy = self.indexing_policy(header.0);
+        self.encode_header_with_policy_into(header, policy, writer)
+    }
+
+    /// Encodes a single given header into the given `io::Write` instance, using `policy`
+    /// instead of the one set for its name.
+    pub fn encode_header_with_policy_into<W: io::Write>(
+            &mut self,
+            header: (&[u8], &[u8]),
+            policy: IndexingPolicy,
+            writer: &mut W)
+            -> io::Result<()> {
+        match (self.header_table.find_header(header), policy) {
+            (None, IndexingPolicy::Index) => {
                 // The name of the header is in no tables: need to encode
                 // it with both a literal name and value.
-                try!(self.encode_literal(&header, true, writer));
+                try!(self.encode_literal(&header, policy, writer));
                 self.header_table.add_header(header.0.to_vec(), header.1.to_vec());
             },
-            Some((index, false)) => {
-                // The name of the header is at the given index, but the
-                // value does not match the current one: need to encode
-                // only the value as a literal.
-                try!(self.encode_indexed_name((index, header.1), false, writer));
+            (None, _) => {
+                try!(self.encode_literal(&header, policy, writer));
             },
-            Some((index, true)) => {
+            (Some((index, true)), IndexingPolicy::Index) |
+            (Some((index, true)), IndexingPolicy::NoIndex) => {
                 // The full header was found in one of the tables, so we
                 // just encode the index.
                 try!(self.encode_indexed(index, writer));
-            }
+            },
+            (Some((index, _)), IndexingPolicy::NeverIndex) => {
+                // Only the name is taken from the table, so the value is
+                // always sent as a never indexed literal.
+                try!(self.encode_indexed_name((index, header.1), policy, writer));
+            },
+            (Some((index, false)), IndexingPolicy::Index) => {
+                // The name of the header is at the given index, but the
+                // value does not match the current one: need to encode
+                // only the value as a literal.
+                try!(self.encode_indexed_name((index, header.1), policy, writer));
+                self.header_table.add_header(header.0.to_vec(), header.1.to_vec());
+            },
+            (Some((index, false)), _) => {
+                try!(self.encode_indexed_name((index, header.1), policy, writer));
+            },
         };
         Ok(())
     }
@@ -250,21 +428,17 @@ impl<'a> Encoder<'a> {
     /// # Parameters
     ///
     /// - `header` - the header to be encoded
-    /// - `should_index` - indicates whether the given header should be indexed, i.e.
-    ///                    inserted into the dynamic table
+    /// - `policy` - the literal representation to use, i.e. whether the header
+    ///              is inserted into the dynamic table
     /// - `buf` - The buffer into which the result is placed
     ///
     fn encode_literal<W: io::Write>(
             &mut self,
             header: &(&[u8], &[u8]),
-            should_index: bool,
+            policy: IndexingPolicy,
             buf: &mut W)
             -> io::Result<()> {
-        let mask = if should_index {
-            0x40
-        } else {
-            0x0
-        };
+        let (mask, _) = literal_prefix(policy);
 
         try!(buf.write_all(&[mask]));
         try!(self.encode_string_literal(&header.0, buf));
@@ -275,16 +449,26 @@ impl<'a> Encoder<'a> {
     /// Encodes a string literal and places the result in the given buffer
     /// `buf`.
     ///
-    /// The function does not consider Huffman encoding for now, but always
-    /// produces a string literal representations, according to the HPACK spec
-    /// section 5.2.
+    /// Whether the string is Huffman encoded or sent as is depends on the
+    /// `HuffmanPolicy`, see the HPACK spec section 5.2.
     fn encode_string_literal<W: io::Write>(
             &mut self,
             octet_str: &[u8],
             buf: &mut W)
             -> io::Result<()> {
-        try!(encode_integer_into(octet_str.len(), 7, 0, buf));
-        try!(buf.write_all(octet_str));
+        let huffman = match self.huffman_policy {
+            HuffmanPolicy::WhenShorter => self.huffman.encoded_len(octet_str) < octet_str.len(),
+            HuffmanPolicy::Always => true,
+            HuffmanPolicy::Never => false,
+        };
+        if huffman {
+            let encoded = self.huffman.encode(octet_str);
+            try!(encode_integer_into(encoded.len(), 7, 0x80, buf));
+            try!(buf.write_all(&encoded));
+        } else {
+            try!(encode_integer_into(octet_str.len(), 7, 0, buf));
+            try!(buf.write_all(octet_str));
+        }
         Ok(())
     }
 
@@ -293,17 +477,12 @@ impl<'a> Encoder<'a> {
     fn encode_indexed_name<W: io::Write>(
             &mut self,
             header: (usize, &[u8]),
-            should_index: bool,
+            policy: IndexingPolicy,
             buf: &mut W)
             -> io::Result<()> {
-        let (mask, prefix) = if should_index {
-            (0x40, 6)
-        } else {
-            (0x0, 4)
-        };
+        let (mask, prefix) = literal_prefix(policy);
 
         try!(encode_integer_into(header.0, prefix, mask, buf));
-        // So far, we rely on just one strategy for encoding string literals.
         try!(self.encode_string_literal(&header.1, buf));
         Ok(())
     }
@@ -319,3 +498,14 @@ impl<'a> Encoder<'a> {
         Ok(())
     }
 }
+
+/// Returns the leading bits and the prefix size of the name index for the
+/// literal representation matching `policy` (HPACK spec sections 6.2.1 to
+/// 6.2.3).
+fn literal_prefix(policy: IndexingPolicy) -> (u8, u8) {
+    match policy {
+        IndexingPolicy::Index => (0x40, 6),
+        IndexingPolicy::NoIndex => (0x0, 4),
+        IndexingPolicy::NeverIndex => (0x10, 4),
+    }
+}
@@ -167,6 +167,64 @@ impl HuffmanDecoder {
     }
 }
 
+/// A Huffman code encoder, using the same code table as the `HuffmanDecoder`.
+#[derive(Copy)]
+#[derive(Clone)]
+pub struct HuffmanEncoder {
+    table: &'static [(u32, u8)],
+}
+
+impl HuffmanEncoder {
+    /// Constructs a new HuffmanEncoder with the default Huffman code table, as
+    /// defined in the HPACK-draft-10, Appendix B.
+    pub fn new() -> HuffmanEncoder {
+        HuffmanEncoder {
+            table: HUFFMAN_CODE_TABLE,
+        }
+    }
+
+    /// Returns the number of octets the Huffman encoding of `buf` takes,
+    /// padding included.
+    pub fn encoded_len(&self, buf: &[u8]) -> usize {
+        let bits: usize = buf.iter().map(|&b| self.table[b as usize].1 as usize).sum();
+        (bits + 7) / 8
+    }
+
+    /// Encodes the buffer `buf` into a newly allocated `Vec`.
+    pub fn encode(&self, buf: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.encoded_len(buf));
+        self.encode_into(buf, &mut result);
+        result
+    }
+
+    /// Encodes the buffer `buf` and appends the result to `dst`.
+    ///
+    /// The last octet is padded with the most significant bits of the EOS
+    /// code, which are all set.
+    pub fn encode_into(&self, buf: &[u8], dst: &mut Vec<u8>) {
+        // Bits that did not fill an octet yet are kept in the least
+        // significant `pending` bits of `current`. Codes are at most 30 bits
+        // long, so they always fit next to the (at most 7) pending bits.
+        let mut current: u64 = 0;
+        let mut pending: u8 = 0;
+
+        for &b in buf {
+            let (code, code_len) = self.table[b as usize];
+            current = (current << code_len) | code as u64;
+            pending += code_len;
+            while pending >= 8 {
+                pending -= 8;
+                dst.push((current >> pending) as u8);
+            }
+        }
+
+        if pending > 0 {
+            let padding = 8 - pending;
+            dst.push(((current << padding) as u8) | ((1 << padding) - 1));
+        }
+    }
+}
+
 /// A helper struct that represents an iterator over individual bits of all
 /// bytes found in a wrapped Iterator over bytes.
 /// Bits are represented as `bool`s, where `true` corresponds to a set bit and
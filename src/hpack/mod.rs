@@ -26,17 +26,24 @@
 use std::fmt;
 use std::iter;
 use std::slice;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::collections::vec_deque;
 
 // Re-export the main HPACK API entry points.
-pub use self::decoder::Decoder;
-pub use self::encoder::Encoder;
+pub use self::decoder::{Decoder, DecoderLimits};
+pub use self::encoder::{Encoder, HuffmanPolicy, IndexingPolicy};
 
 pub mod encoder;
 pub mod decoder;
 pub mod huffman;
 
+#[cfg(test)]
+mod conformance;
+
+/// The initial maximum size of the dynamic table, which corresponds to the
+/// default of HTTP/2's SETTINGS_HEADER_TABLE_SIZE.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
 /// An `Iterator` through elements of the `DynamicTable`.
 ///
 /// The implementation of the iterator itself is very tightly coupled
@@ -87,18 +94,33 @@ impl<'a> Iterator for DynamicTableIter<'a> {
 /// only cares about the maximum size as set by the HPACK {en,de}coder and lets
 /// *it* worry about making certain that the changes are valid according to
 /// the (current) constraints of the protocol.
+///
+/// The table keeps an index from header names and values to the entries, so
+/// that the encoder can look headers up without scanning the table.
 struct DynamicTable {
     table: VecDeque<(Vec<u8>, Vec<u8>)>,
     size: usize,
     max_size: usize,
+    /// Index of the entries by name. Entries are identified by the number of
+    /// headers inserted before them, which unlike their position does not
+    /// change as new headers are added.
+    index: HashMap<Vec<u8>, NameIndex>,
+    /// The number of headers inserted so far.
+    inserted: u64,
+}
+
+/// The newest entries of the `DynamicTable` with a given name.
+struct NameIndex {
+    /// The newest entry with the name.
+    newest: u64,
+    /// The newest entry with the name and each of the values.
+    values: HashMap<Vec<u8>, u64>,
 }
 
 impl DynamicTable {
     /// Creates a new empty dynamic table with a default size.
     fn new() -> DynamicTable {
-        // The default maximum size corresponds to the default HTTP/2
-        // setting
-        DynamicTable::with_size(4096)
+        DynamicTable::with_size(DEFAULT_TABLE_SIZE)
     }
 
     /// Creates a new empty dynamic table with the given maximum size.
@@ -107,6 +129,8 @@ impl DynamicTable {
             table: VecDeque::new(),
             size: 0,
             max_size: max_size,
+            index: HashMap::new(),
+            inserted: 0,
         }
     }
 
@@ -159,7 +183,17 @@ impl DynamicTable {
         // how the table is stored).
         self.size += name.len() + value.len() + 32;
         // debug!("New dynamic table size {}", self.size);
-        // Now add it to the internal buffer
+        // Index the header before adding it to the internal buffer
+        let id = self.inserted;
+        self.inserted += 1;
+        {
+            let entry = self.index.entry(name.clone()).or_insert_with(|| NameIndex {
+                newest: id,
+                values: HashMap::new(),
+            });
+            entry.newest = id;
+            entry.values.insert(value.clone(), id);
+        }
         self.table.push_front((name, value));
         // ...and make sure we're not over the maximum size.
         self.consolidate_table();
@@ -171,18 +205,32 @@ impl DynamicTable {
     /// fashion.
     fn consolidate_table(&mut self) {
         while self.size > self.max_size {
-            {
-                let last_header = match self.table.back() {
-                    Some(x) => x,
-                    None => {
-                        // Can never happen as the size of the table must reach
-                        // 0 by the time we've exhausted all elements.
-                        panic!("Size of table != 0, but no headers left!");
+            // The oldest entry is the one inserted `len` headers ago.
+            let id = self.inserted - self.table.len() as u64;
+            let (name, value) = match self.table.pop_back() {
+                Some(x) => x,
+                None => {
+                    // Can never happen as the size of the table must reach
+                    // 0 by the time we've exhausted all elements.
+                    panic!("Size of table != 0, but no headers left!");
+                }
+            };
+            self.size -= name.len() + value.len() + 32;
+
+            // The index only refers to the evicted entry if no newer one
+            // with the same name (and value) exists.
+            let remove = match self.index.get_mut(&name) {
+                Some(entry) => {
+                    if entry.values.get(&value) == Some(&id) {
+                        entry.values.remove(&value);
                     }
-                };
-                self.size -= last_header.0.len() + last_header.1.len() + 32;
+                    entry.newest == id
+                },
+                None => false,
+            };
+            if remove {
+                self.index.remove(&name);
             }
-            self.table.pop_back();
         }
     }
 
@@ -208,6 +256,23 @@ impl DynamicTable {
     fn get(&self, index: usize) -> Option<&(Vec<u8>, Vec<u8>)> {
         self.table.get(index)
     }
+
+    /// Finds the newest entry matching both the name and the value of the
+    /// given header, or else the newest one matching only the name.
+    ///
+    /// Returns the 0-based position of the entry in the dynamic table and
+    /// whether the value matched.
+    fn find(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
+        let entry = match self.index.get(header.0) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        let newest = self.inserted - 1;
+        match entry.values.get(header.1) {
+            Some(&id) => Some(((newest - id) as usize, true)),
+            None => Some(((newest - entry.newest) as usize, false)),
+        }
+    }
 }
 
 impl fmt::Debug for DynamicTable {
@@ -269,6 +334,11 @@ fn static_table_mapper<'a>(h: &'a (&'a [u8], &'a [u8]))
 /// `2.3.3.` of the HPACK spec.
 struct HeaderTable<'a> {
     static_table: StaticTable<'a>,
+    /// The 1-based index of each header of the static table.
+    static_index: HashMap<(&'a [u8], &'a [u8]), usize>,
+    /// The 1-based index of the first header with each name in the static
+    /// table.
+    static_names: HashMap<&'a [u8], usize>,
     dynamic_table: DynamicTable,
 }
 
@@ -276,8 +346,17 @@ impl<'a> HeaderTable<'a> {
     /// Creates a new header table where the static part is initialized with
     /// the given static table.
     pub fn with_static_table(static_table: StaticTable<'a>) -> HeaderTable<'a> {
+        let mut static_index = HashMap::new();
+        let mut static_names = HashMap::new();
+        for (i, &header) in static_table.iter().enumerate() {
+            static_index.entry(header).or_insert(i + 1);
+            static_names.entry(header.0).or_insert(i + 1);
+        }
+
         HeaderTable {
             static_table: static_table,
+            static_index: static_index,
+            static_names: static_names,
             dynamic_table: DynamicTable::new(),
         }
     }
@@ -361,35 +440,38 @@ impl<'a> HeaderTable<'a> {
     /// of the header in the header tables (the 1-based index that HPACK uses)
     /// and a `bool` indicating whether the value of the header also matched.
     pub fn find_header(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
-        // Just does a simple scan of the entire table, searching for a header
-        // that matches both the name and the value of the given header.
-        // If no such header is found, then any one of the headers that had a
-        // matching name is returned, with the appropriate return flag set.
-        //
-        // The tables are so small that it is unlikely that the linear scan
-        // would be a major performance bottlneck. If it does prove to be,
-        // though, a more efficient lookup/header representation method could
-        // be devised.
+        // Full matches are preferred over matching names, and the static
+        // table over the dynamic one since its indices are smaller.
+        if let Some(&i) = self.static_index.get(&header) {
+            return Some((i, true));
+        }
+
+        let dynamic = self.dynamic_table.find(header)
+                                        .map(|(i, matched)| (self.static_table.len() + i + 1, matched));
+        if let Some((i, true)) = dynamic {
+            return Some((i, true));
+        }
+
+        match self.static_names.get(header.0) {
+            Some(&i) => Some((i, false)),
+            None => dynamic,
+        }
+    }
+
+    /// Finds the given header with a scan of the whole table, the way
+    /// `find_header` did before the tables were indexed. Only kept as the
+    /// baseline of `benches/hpack.rs`.
+    pub fn find_header_linear(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
         let mut matching_name: Option<usize> = None;
         for (i, h) in self.iter().enumerate() {
             if header.0 == h.0 {
                 if header.1 == h.1 {
-                    // Both name and value matched: returns it immediately
                     return Some((i + 1, true));
                 }
-                // If only the name was valid, we continue scanning, hoping to
-                // find one where both the name and value match. We remember
-                // this one, in case such a header isn't found after all.
                 matching_name = Some(i + 1);
             }
         }
-
-        // Finally, if there's no header with a matching name and value,
-        // return one that matched only the name, if that *was* found.
-        match matching_name {
-            Some(i) => Some((i, false)),
-            None => None,
-        }
+        matching_name.map(|i| (i, false))
     }
 }
 
@@ -458,3 +540,313 @@ static STATIC_TABLE: &'static [(&'static [u8], &'static [u8])] = &[
   (b"via", b""),
   (b"www-authenticate", b""),
 ];
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::{Encoder, IndexingPolicy};
+    use super::{HeaderTable, STATIC_TABLE};
+    use super::Decoder;
+    use super::huffman::{HuffmanDecoder, HuffmanEncoder};
+    use super::decoder::{DecoderError, DecoderLimit, DecoderLimits};
+
+    // Encodes the given header list, decodes it with a decoder that shares the encoder's
+    // history and checks that the decoder sees exactly what was encoded.
+    fn roundtrip(encoder: &mut Encoder, decoder: &mut Decoder, headers: &[(&[u8], &[u8])]) {
+        let encoded = encoder.encode(headers.iter().cloned());
+        let decoded = decoder.decode(&encoded).unwrap();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> =
+            headers.iter().map(|&(n, v)| (n.to_vec(), v.to_vec())).collect();
+
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_roundtrip_static() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        roundtrip(&mut encoder, &mut decoder, &[
+            (b":method", b"GET"),
+            (b":scheme", b"https"),
+            (b":path", b"/index.html"),
+            (b":status", b"200"),
+        ]);
+    }
+
+    #[test]
+    fn test_roundtrip_dynamic() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers: &[(&[u8], &[u8])] = &[
+            (b":method", b"POST"),
+            (b":path", b"/upload"),
+            (b"custom-key", b"custom-value"),
+            (b"content-type", b"application/json"),
+        ];
+
+        // The second and third blocks are expected to hit the dynamic table.
+        roundtrip(&mut encoder, &mut decoder, headers);
+        roundtrip(&mut encoder, &mut decoder, headers);
+        roundtrip(&mut encoder, &mut decoder, &[
+            (b"custom-key", b"other-value"),
+            (b"custom-key", b"custom-value"),
+        ]);
+    }
+
+    #[test]
+    fn test_roundtrip_eviction() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let value = vec![b'x'; 1000];
+
+        // Each entry is over 1KB so the default 4KB table has to evict as we go.
+        for i in 0..20 {
+            let name = format!("x-header-{}", i);
+            roundtrip(&mut encoder, &mut decoder, &[(name.as_bytes(), &value[..])]);
+        }
+        roundtrip(&mut encoder, &mut decoder, &[(b"x-header-19", &value[..])]);
+    }
+
+    #[test]
+    fn test_roundtrip_empty() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        roundtrip(&mut encoder, &mut decoder, &[]);
+        roundtrip(&mut encoder, &mut decoder, &[(b"x-empty", b"")]);
+    }
+
+    #[test]
+    fn test_decode_index_out_of_bounds() {
+        let mut decoder = Decoder::new();
+
+        assert_eq!(decoder.decode(&[0x80 | 62]), Err(DecoderError::HeaderIndexOutOfBounds));
+    }
+
+    #[test]
+    fn test_huffman_decode() {
+        let mut decoder = HuffmanDecoder::new();
+        // RFC 7541 C.4.1
+        let encoded = [0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+
+        assert_eq!(decoder.decode(&encoded).unwrap(), b"www.example.com".to_vec());
+    }
+
+    #[test]
+    fn test_huffman_encode() {
+        let encoder = HuffmanEncoder::new();
+        // RFC 7541 C.4.1 and C.6.1
+        assert_eq!(encoder.encode(b"www.example.com"),
+                   vec![0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff]);
+        assert_eq!(encoder.encode(b"302"), vec![0x64, 0x02]);
+        assert_eq!(encoder.encoded_len(b"www.example.com"), 12);
+        assert_eq!(encoder.encode(b""), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_huffman_roundtrip() {
+        let encoder = HuffmanEncoder::new();
+        let mut decoder = HuffmanDecoder::new();
+        let all: Vec<u8> = (0..256).map(|b| b as u8).collect();
+
+        // Every octet, and every length of padding.
+        for len in 0..all.len() {
+            assert_eq!(decoder.decode(&encoder.encode(&all[..len])).unwrap(), all[..len].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_encoder_huffman_only_if_shorter() {
+        let mut encoder = Encoder::new();
+
+        // Codes for control characters are longer than 8 bits.
+        let encoded = encoder.encode(vec![(&b"x-raw"[..], &b"\x00\x01"[..])]);
+        assert_eq!(encoded[encoded.len() - 3..].to_vec(), vec![2, 0x00, 0x01]);
+
+        // Lowercase letters take 5 or 6 bits.
+        let encoded = encoder.encode(vec![(&b"x-raw"[..], &b"aeiou"[..])]);
+        let mut expected = vec![0x80 | 4];
+        expected.extend(HuffmanEncoder::new().encode(b"aeiou"));
+        assert_eq!(encoded[encoded.len() - 5..].to_vec(), expected);
+    }
+
+    #[test]
+    fn test_sensitive_headers_never_indexed() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers: &[(&[u8], &[u8])] = &[(b"cookie", b"a=1"), (b"x-token", b"secret")];
+        encoder.set_indexing_policy(b"x-token", IndexingPolicy::NeverIndex);
+
+        for _ in 0..2 {
+            let encoded = encoder.encode(headers.iter().cloned());
+            // "cookie" is at index 32 of the static table, which takes two octets in a 4-bit
+            // prefix; the value is not added to the dynamic table.
+            assert_eq!(&encoded[..2], &[0x10 | 0x0f, 32 - 15]);
+            // The Huffman encoded value takes 3 octets, length included.
+            assert_eq!(encoded[5], 0x10);
+            assert_eq!(decoder.decode(&encoded).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_indexing_policy() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        encoder.set_indexing_policy(b"x-once", IndexingPolicy::NoIndex);
+        encoder.set_indexing_policy(b"authorization", IndexingPolicy::Index);
+        assert_eq!(encoder.indexing_policy(b"x-once"), IndexingPolicy::NoIndex);
+        assert_eq!(encoder.indexing_policy(b"authorization"), IndexingPolicy::Index);
+
+        roundtrip(&mut encoder, &mut decoder, &[(b"x-once", b"1")]);
+        assert_eq!(encoder.encode(vec![(&b"x-once"[..], &b"1"[..])])[0], 0x00);
+
+        // Once indexed like any other header, the name from the static table and the value are
+        // added to the dynamic table.
+        roundtrip(&mut encoder, &mut decoder, &[(b"authorization", b"basic")]);
+        assert_eq!(encoder.encode(vec![(&b"authorization"[..], &b"basic"[..])]), vec![0x80 | 62]);
+    }
+
+    #[test]
+    fn test_find_header() {
+        let mut table = HeaderTable::with_static_table(STATIC_TABLE);
+        // Small enough for entries to be evicted while duplicates of them are still in the table.
+        table.dynamic_table.set_max_table_size(300);
+        let names: &[&[u8]] = &[b"x-a", b"x-b", b":path", b"cookie", b"x-c"];
+        let values: &[&[u8]] = &[b"1", b"22", b"/", b"333"];
+
+        for i in 0..200 {
+            let name = names[i % names.len()];
+            let value = values[(i / 3) % values.len()];
+            table.add_header(name.to_vec(), value.to_vec());
+
+            // Compare against a scan for the first full match, or else the first matching name.
+            for &name in names {
+                for &value in values {
+                    let headers: Vec<(&[u8], &[u8])> = table.iter().collect();
+                    let scan = headers.iter().position(|&h| h == (name, value)).map(|i| (i + 1, true))
+                        .or(headers.iter().position(|h| h.0 == name).map(|i| (i + 1, false)));
+                    assert_eq!(table.find_header((name, value)), scan);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_size_update() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers: &[(&[u8], &[u8])] = &[(b"x-a", b"1"), (b"x-b", b"2")];
+        roundtrip(&mut encoder, &mut decoder, headers);
+
+        // Shrinking evicts everything, and the update comes first in the next block only.
+        encoder.set_max_allowed_table_size(40);
+        assert_eq!(encoder.max_table_size(), 40);
+        let encoded = encoder.encode(headers.iter().cloned());
+        assert_eq!(encoded[0], 0x20 | 31);
+        assert_eq!(encoded[1], 40 - 31);
+        assert_eq!(decoder.decode(&encoded).unwrap().len(), 2);
+        assert_eq!(encoder.encode(vec![(&b"x-b"[..], &b"2"[..])]), vec![0x80 | 62]);
+
+        // Going down and up again announces both the smallest and the final size.
+        encoder.set_max_allowed_table_size(0);
+        encoder.set_max_allowed_table_size(8192);
+        assert_eq!(encoder.max_table_size(), 4096);
+        let encoded = encoder.encode(headers.iter().cloned());
+        assert_eq!(&encoded[..4], &[0x20, 0x20 | 31, 0xe1, 0x1f]);
+        assert_eq!(decoder.decode(&encoded).unwrap().len(), 2);
+
+        // Nothing to announce when the size ends up unchanged.
+        encoder.set_max_table_size(4096);
+        roundtrip(&mut encoder, &mut decoder, headers);
+        assert_eq!(encoder.encode(headers.iter().cloned()), vec![0x80 | 63, 0x80 | 62]);
+    }
+
+    #[test]
+    fn test_decode_borrowed() {
+        let mut decoder = Decoder::new();
+        let huffman = HuffmanEncoder::new().encode(b"www.example.com");
+        let mut block = vec![0x82, 0x44, 6, b'/', b'i', b'n', b'd', b'e', b'x', 0x41, 0x80 | huffman.len() as u8];
+        block.extend_from_slice(&huffman);
+        let headers = decoder.decode_borrowed(&block).unwrap();
+
+        // Static table hits and raw literals are borrowed, Huffman encoded literals are not.
+        assert!(match headers[0] { (Cow::Borrowed(_), Cow::Borrowed(_)) => true, _ => false });
+        match headers[1] {
+            (Cow::Borrowed(name), Cow::Borrowed(value)) => {
+                assert_eq!(name, b":path");
+                assert_eq!(value.as_ptr(), block[3..].as_ptr());
+            },
+            _ => panic!("{:?}", headers[1]),
+        }
+        assert!(match headers[2] { (Cow::Borrowed(_), Cow::Owned(ref v)) => v == b"www.example.com", _ => false });
+
+        // Dynamic table hits are copied, since the table may change.
+        let block = [0xbf, 0xbe];
+        let headers = decoder.decode_borrowed(&block).unwrap();
+        assert!(headers.iter().all(|h| match *h { (Cow::Owned(_), Cow::Owned(_)) => true, _ => false }));
+        assert_eq!(&headers[0].1[..], b"/index");
+        assert_eq!(&headers[1].1[..], b"www.example.com");
+    }
+
+    #[test]
+    fn test_decoder_header_list_size_limit() {
+        // One large entry in the dynamic table, then a block that references it over and over.
+        let mut block = vec![0x40 | 0, 3];
+        block.extend_from_slice(b"x-a");
+        block.extend_from_slice(&[0x7f, 0xa1, 0x1e]);
+        block.extend_from_slice(&[b'a'; 4000]);
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&block).unwrap().len(), 1);
+
+        let bomb = vec![0x80 | 62; 1000];
+        assert_eq!(decoder.decode(&bomb),
+                   Err(DecoderError::LimitExceeded(DecoderLimit::HeaderListSize)));
+
+        // The table survives, so smaller blocks still decode.
+        assert_eq!(decoder.decode(&bomb[..10]).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_decoder_string_length_limit() {
+        let mut decoder = Decoder::new();
+        decoder.set_limits(DecoderLimits { max_string_length: 4, ..DecoderLimits::default() });
+
+        assert!(decoder.decode(&[0x00, 4, b'x', b'-', b'a', b'b', 4, b'1', b'2', b'3', b'4']).is_ok());
+        assert_eq!(decoder.decode(&[0x00, 4, b'x', b'-', b'a', b'b', 5, b'1', b'2', b'3', b'4', b'5']),
+                   Err(DecoderError::LimitExceeded(DecoderLimit::StringLength)));
+
+        // A Huffman encoded string is checked once decoded: "12345" takes 4 octets.
+        let huffman = HuffmanEncoder::new().encode(b"12345");
+        assert_eq!(huffman.len(), 4);
+        let mut block = vec![0x00, 1, b'x', 0x80 | 4];
+        block.extend_from_slice(&huffman);
+        assert_eq!(decoder.decode(&block),
+                   Err(DecoderError::LimitExceeded(DecoderLimit::StringLength)));
+    }
+
+    #[test]
+    fn test_decoder_header_count_limit() {
+        let mut decoder = Decoder::new();
+        decoder.set_limits(DecoderLimits { max_headers: 3, ..DecoderLimits::default() });
+
+        assert_eq!(decoder.decode(&[0x82, 0x84, 0x86]).unwrap().len(), 3);
+        assert_eq!(decoder.decode(&[0x82, 0x84, 0x86, 0x87]),
+                   Err(DecoderError::LimitExceeded(DecoderLimit::HeaderCount)));
+    }
+
+    #[test]
+    fn test_decoder_size_update() {
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode(&[0x20 | 31, 0xe1, 0x1f]).is_ok());
+
+        // Above the allowed size, or cut short.
+        assert_eq!(decoder.decode(&[0x20 | 31, 0xe2, 0x1f]), Err(DecoderError::InvalidMaxDynamicSize));
+        assert!(decoder.decode(&[0x20 | 31, 0xe1]).is_err());
+
+        decoder.set_max_table_size(100);
+        assert!(decoder.decode(&[0x20 | 31, 69]).is_ok());
+        assert_eq!(decoder.decode(&[0x20 | 31, 70]), Err(DecoderError::InvalidMaxDynamicSize));
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoding and encoding of the chunked transfer coding (RFC 7230 section 4.1).
+
+use std::cmp;
+use std::io;
+
+use futures::{Async, Poll};
+use httparse;
+use tokio_core::io::EasyBuf;
+
+use Headers;
+use super::response::encode_field;
+
+/// Most trailer fields accepted after the last chunk.
+const MAX_TRAILERS: usize = 16;
+
+/// Longest chunk size line, extensions included, that `Decoder` waits for.
+const MAX_SIZE_LINE: usize = 4096;
+
+/// Largest trailer section that `Decoder` waits for.
+const MAX_TRAILERS_SIZE: usize = 16384;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunked body: {}", msg))
+}
+
+/// Where a `Decoder` is in the chunked body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Size,
+    /// In the data of a chunk, with this many bytes of it left.
+    Data(usize),
+    /// At the CRLF that ends the data of a chunk.
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Decodes a chunked body as it arrives. Chunk extensions are skipped; the trailer section is
+/// kept for those who buffer the body and want its fields.
+#[derive(Debug)]
+pub struct Decoder {
+    state: State,
+    /// The trailer section as it came, ending with its empty line.
+    trailers: Vec<u8>,
+    /// The trailer fields, as the position of each name and value in `trailers`.
+    trailer_fields: Vec<((usize, usize), (usize, usize))>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { state: State::Size, trailers: Vec::new(), trailer_fields: Vec::new() }
+    }
+
+    /// The trailer section, once the body has been consumed, along with the position of each
+    /// trailer field name and value in it.
+    pub fn trailers(&self) -> (&[u8], &[((usize, usize), (usize, usize))]) {
+        (&self.trailers, &self.trailer_fields)
+    }
+
+    /// Takes what it can of the body from the start of `buf`. Yields the data as it comes, and
+    /// `None` once the body, trailers included, has been consumed. `NotReady` means `buf` needs
+    /// more bytes.
+    pub fn decode(&mut self, buf: &mut EasyBuf) -> Poll<Option<Vec<u8>>, io::Error> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let (size, line_len) = match try!(chunk_size(buf.as_slice())) {
+                        Some(size) => size,
+                        None if buf.len() > MAX_SIZE_LINE => return Err(invalid("chunk size line too long")),
+                        None => return Ok(Async::NotReady),
+                    };
+                    buf.drain_to(line_len);
+                    self.state = if size == 0 { State::Trailers } else { State::Data(size) };
+                },
+                State::Data(left) => {
+                    if buf.len() == 0 {
+                        return Ok(Async::NotReady);
+                    }
+                    let len = cmp::min(left, buf.len());
+                    let data = buf.drain_to(len).as_slice().to_vec();
+                    self.state = if len == left { State::DataEnd } else { State::Data(left - len) };
+                    return Ok(Async::Ready(Some(data)));
+                },
+                State::DataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(Async::NotReady);
+                    }
+                    if &buf.as_slice()[..2] != b"\r\n" {
+                        return Err(invalid("chunk data not followed by CRLF"));
+                    }
+                    buf.drain_to(2);
+                    self.state = State::Size;
+                },
+                State::Trailers => {
+                    let mut headers = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+                    let len = {
+                        let raw = buf.as_slice();
+                        let (len, fields) = match try!(httparse::parse_headers(raw, &mut headers)
+                                                           .map_err(|e| invalid(&format!("{:?}", e)))) {
+                            httparse::Status::Complete(trailers) => trailers,
+                            httparse::Status::Partial if buf.len() > MAX_TRAILERS_SIZE => return Err(invalid("trailers too large")),
+                            httparse::Status::Partial => return Ok(Async::NotReady),
+                        };
+                        let toslice = |a: &[u8]| {
+                            let start = a.as_ptr() as usize - raw.as_ptr() as usize;
+                            (start, start + a.len())
+                        };
+                        self.trailer_fields = fields.iter().map(|h| (toslice(h.name.as_bytes()), toslice(h.value))).collect();
+                        self.trailers = raw[..len].to_vec();
+                        len
+                    };
+                    buf.drain_to(len);
+                    self.state = State::Done;
+                },
+                State::Done => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// Writes `data` as a chunk. Nothing is written for empty data, which would end the body.
+pub fn encode_chunk(data: &[u8], buf: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Writes the last chunk, which ends the body, followed by the trailer section.
+pub fn encode_last_chunk(trailers: &Headers, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"0\r\n");
+    for &(ref name, ref value) in trailers {
+        encode_field(name, value, buf);
+    }
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Parses a chunk size line, i.e. the size in hex and any extensions, up to and including its
+/// CRLF.
+///
+/// Returns the chunk size and the length of the line, or `None` if the line is incomplete.
+fn chunk_size(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    if buf.first().map_or(false, |&b| !(b as char).is_digit(16)) {
+        return Err(invalid("missing chunk size"));
+    }
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let line = &buf[..end];
+    let digits = line.iter().take_while(|b| (**b as char).is_digit(16)).count();
+
+    let mut size: usize = 0;
+    for &b in &line[..digits] {
+        let digit = (b as char).to_digit(16).unwrap() as usize;
+        size = try!(size.checked_mul(16).and_then(|s| s.checked_add(digit))
+                        .ok_or_else(|| invalid("chunk size too large")));
+    }
+
+    // Anything after the size has to be extensions, which we do not understand and ignore.
+    match line[digits..].iter().find(|&&b| b != b' ' && b != b'\t') {
+        None | Some(&b';') => Ok(Some((size, end + 2))),
+        Some(_) => Err(invalid("malformed chunk size line")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::Async;
+    use tokio_core::io::EasyBuf;
+
+    use super::{encode_chunk, encode_last_chunk, Decoder};
+
+    /// Runs `raw` through a new `Decoder`. Returns the body, the decoder and what is left of
+    /// `raw`, or `None` if the body is not all there.
+    fn decode(raw: &[u8]) -> io::Result<Option<(Vec<u8>, Decoder, EasyBuf)>> {
+        let mut decoder = Decoder::new();
+        let mut buf = EasyBuf::from(raw.to_vec());
+        let mut body = Vec::new();
+        loop {
+            match try!(decoder.decode(&mut buf)) {
+                Async::Ready(Some(data)) => body.extend(data),
+                Async::Ready(None) => return Ok(Some((body, decoder, buf))),
+                Async::NotReady => return Ok(None),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let buf = b"4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\nGET";
+        let (body, decoder, rest) = decode(buf).unwrap().unwrap();
+        assert_eq!(body, b"Wikipedia in\r\n\r\nchunks.");
+        assert_eq!(decoder.trailers(), (&b"\r\n"[..], &[][..]));
+        assert_eq!(rest.as_slice(), b"GET");
+
+        // The body does not end until the last chunk and the trailer section are there.
+        for len in 0..buf.len() - 3 {
+            assert!(decode(&buf[..len]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_decode_trailers() {
+        let buf = b"3\r\nabc\r\n0 ; last\r\nExpires: never\r\nX-Sum: 42\r\n\r\n";
+        let (body, decoder, rest) = decode(buf).unwrap().unwrap();
+        assert_eq!(body, b"abc");
+        assert_eq!(rest.len(), 0);
+
+        let (raw, fields) = decoder.trailers();
+        assert_eq!(raw, b"Expires: never\r\nX-Sum: 42\r\n\r\n");
+        let trailers: Vec<(&[u8], &[u8])> = fields.iter()
+            .map(|&(n, v)| (&raw[n.0..n.1], &raw[v.0..v.1]))
+            .collect();
+        assert_eq!(trailers, vec![(&b"Expires"[..], &b"never"[..]), (&b"X-Sum"[..], &b"42"[..])]);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"x").is_err());
+        assert!(decode(b"\r\n").is_err());
+        assert!(decode(b"-1\r\n").is_err());
+        assert!(decode(b"3 x\r\nabc\r\n").is_err());
+        assert!(decode(b"3\r\nabcd\r\n").is_err());
+        assert!(decode(b"fffffffffffffffffffffff\r\n").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        encode_chunk(b"Wiki", &mut buf);
+        encode_chunk(b"", &mut buf);
+        encode_chunk(&[b'a'; 26], &mut buf);
+        encode_last_chunk(&vec![("X-Sum".to_string(), "42".to_string())], &mut buf);
+
+        let (body, decoder, rest) = decode(&buf).unwrap().unwrap();
+        assert_eq!(body, [&b"Wiki"[..], &[b'a'; 26][..]].concat());
+        assert_eq!(decoder.trailers().1.len(), 1);
+        assert_eq!(rest.len(), 0);
+        assert!(buf.starts_with(b"4\r\nWiki\r\n1a\r\n"));
+    }
+
+    #[test]
+    fn test_decoder() {
+        let raw = b"4\r\nWiki\r\n5;name=value\r\npedia\r\n0\r\nX-Sum: 42\r\n\r\nGET";
+
+        // However the body is split up on arrival, the same data comes out.
+        for split in 0..raw.len() - 3 {
+            let mut decoder = Decoder::new();
+            let mut buf = EasyBuf::from(raw[..split].to_vec());
+            let mut body = Vec::new();
+            loop {
+                match decoder.decode(&mut buf).unwrap() {
+                    Async::Ready(Some(data)) => body.extend(data),
+                    Async::Ready(None) => break,
+                    Async::NotReady => buf.get_mut().extend_from_slice(&raw[split..]),
+                }
+            }
+            assert_eq!(body, b"Wikipedia");
+            assert_eq!(buf.as_slice(), b"GET");
+        }
+    }
+
+    #[test]
+    fn test_decoder_invalid() {
+        let mut buf = EasyBuf::from(b"3\r\nabcd\r\n".to_vec());
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&mut buf).unwrap(), Async::Ready(Some(b"abc".to_vec())));
+        assert!(decoder.decode(&mut buf).is_err());
+
+        let mut buf = EasyBuf::from(vec![b'1'; 5000]);
+        assert!(Decoder::new().decode(&mut buf).is_err());
+    }
+}
@@ -13,74 +13,266 @@
 // limitations under the License.
 
 use std::io;
+use std::collections::VecDeque;
 use tokio_core::net::TcpStream;
 use std::net::SocketAddr;
 
-use tokio_proto::pipeline::ServerProto;
-use tokio_core::io::{Io, Codec, Framed, EasyBuf};
-
-use tokio_tls::TlsAcceptorExt;
-use native_tls::{Pkcs12, TlsAcceptor, TlsStream};
+use tokio_proto::streaming::pipeline::ServerProto;
+use tokio_core::io::{Codec, EasyBuf};
 
+use Error;
+use HttpVersion;
 use Router;
 use Logger;
 use LoggerLevel;
+use Method;
 
-pub use self::request::Request;
+pub use self::body::Body;
+pub use self::chunk::Chunk;
+pub use self::request::{BufferBody, HeaderList, HeaderListIter, Request};
+use self::request::{BodyLength, PartialRequest, Rejection};
 pub use self::response::Response;
+use self::response::{has_connection_option, BodyFraming, Exchange};
+pub use self::transport::HttpTransport;
 
-mod date;
+pub mod date;
+mod body;
+mod chunk;
+mod chunked;
 mod request;
 mod response;
+mod transport;
 pub mod buffer;
 
+/// Most headers a request may have unless configured otherwise.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// Largest request head, i.e. the request line and headers, unless configured otherwise.
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 16384;
+
+/// Largest request body that `HttpCodec::decode` buffers unless configured otherwise.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
 /// Proto and Codec can have STATE so you can add features to these two and then pass them to
 /// TcpServer.
+///
+/// Requests are served through tokio-proto's streaming pipeline: the service is called with a
+/// `Message` as soon as the request head is in, and the body follows as a stream of `Chunk`s.
+/// `Request::from` turns the message into a `Request` whose `take_body` returns that stream.
+/// Until `buffer_body` has collected the body, the request's `payload` is `None` and reading it
+/// gives nothing.
+///
+/// Services may answer with `Message::WithBody` to stream the response body as well. Unless the
+/// response gives a Content-Length, the body is sent chunked, followed by the response's
+/// trailers. A response with its body in a buffer gets its Content-Length filled in. Responses
+/// to HEAD requests are sent without their body.
+///
+/// Connections are persistent unless the client sends `Connection: close`, or is an HTTP/1.0
+/// client that does not ask for `Connection: keep-alive`. The response's status line carries the
+/// version of the request. Services can close the connection after a response by giving it a
+/// `Connection: close` header.
 #[derive(Default)]
 pub struct HttpProto {
     pub logger: Option<Logger>,
     pub router: Option<Router>,
+    /// Most headers a request may have before it is answered with 431 Request Header Fields Too
+    /// Large. Defaults to `http::DEFAULT_MAX_HEADERS`.
+    pub max_headers: Option<usize>,
+    /// Largest request head, in bytes, before the request is answered with 431. Defaults to
+    /// `http::DEFAULT_MAX_HEAD_SIZE`.
+    pub max_head_size: Option<usize>,
+    /// Most requests served on a connection. The response to the last one closes it. No limit
+    /// if `None`.
+    pub max_requests: Option<usize>,
 }
 
 // codec here so as to create a Codec that can handle a remote_addr field.
 impl HttpProto {
     fn codec(&self, remote_addr: SocketAddr, router: Option<Router>, logger: Option<Logger>) -> HttpCodec {
-        HttpCodec{ request: None, remote_addr: Some(remote_addr), router: router, logger: logger }
+        let mut codec = HttpCodec::new(Some(remote_addr), router, logger);
+        codec.set_max_headers(self.max_headers.unwrap_or(DEFAULT_MAX_HEADERS));
+        codec.set_max_head_size(self.max_head_size.unwrap_or(DEFAULT_MAX_HEAD_SIZE));
+        codec.set_max_requests(self.max_requests);
+        codec
     }
 }
 
 impl ServerProto<TcpStream> for HttpProto {
     type Request = Request;
+    type RequestBody = Chunk;
     type Response = Response;
-    type Transport = Framed<TcpStream, HttpCodec>;
-    type BindTransport = io::Result<Framed<TcpStream, HttpCodec>>;
+    type ResponseBody = Chunk;
+    type Error = Error;
+    type Transport = HttpTransport<TcpStream>;
+    type BindTransport = io::Result<HttpTransport<TcpStream>>;
 
-    fn bind_transport(&self, io: TcpStream) -> io::Result<Framed<TcpStream, HttpCodec>> {
+    fn bind_transport(&self, io: TcpStream) -> io::Result<HttpTransport<TcpStream>> {
         let addr = io.peer_addr()?;
-        Ok(io.framed(self.codec(addr, self.router.clone(), self.logger.clone())))
+        let codec = self.codec(addr, self.router.clone(), self.logger.clone());
+        Ok(HttpTransport::new(io, codec))
     }
 }
 
 // remote_addr is passed to the decode function to be added to the Request struct that eventually
 // gets passed to the Service call method in the server application.
 pub struct HttpCodec {
-    request: Option<Request>,
+    /// Requests decoded whose responses have not been encoded yet, for the access log, and
+    /// what their responses have to know about them.
+    exchanges: VecDeque<(Request, Exchange)>,
+    scheme: String,
+    max_headers: usize,
+    max_head_size: usize,
+    max_body_size: usize,
+    max_requests: Option<usize>,
+    /// Request that `decode` is buffering the body of.
+    partial: Option<PartialRequest>,
+    /// Number of requests decoded.
+    requests: usize,
+    /// Set once a request or response has ended the connection.
+    closing: bool,
     remote_addr: Option<SocketAddr>,
     router: Option<Router>,
     logger: Option<Logger>,
 }
 
+impl HttpCodec {
+    pub fn new(remote_addr: Option<SocketAddr>, router: Option<Router>, logger: Option<Logger>) -> HttpCodec {
+        HttpCodec{
+            exchanges: VecDeque::new(),
+            scheme: "http".to_string(),
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_requests: None,
+            partial: None,
+            requests: 0,
+            closing: false,
+            remote_addr: remote_addr,
+            router: router,
+            logger: logger,
+        }
+    }
+
+    /// Sets the scheme of decoded requests, e.g. `https` when the connection is secured with TLS.
+    pub fn set_scheme(&mut self, scheme: &str) {
+        self.scheme = scheme.to_string();
+    }
+
+    /// Sets how many headers a request may have before `decode` rejects it.
+    pub fn set_max_headers(&mut self, max_headers: usize) {
+        self.max_headers = max_headers;
+    }
+
+    /// Sets how large, in bytes, a request head may get before `decode` rejects it.
+    pub fn set_max_head_size(&mut self, max_head_size: usize) {
+        self.max_head_size = max_head_size;
+    }
+
+    /// Sets how large, in bytes, a request body may get before `decode` rejects the request
+    /// with 413 (Payload Too Large). Bodies streamed after `decode_head` are not limited.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Sets how many requests are served on the connection, or `None` for no limit.
+    pub fn set_max_requests(&mut self, max_requests: Option<usize>) {
+        self.max_requests = max_requests;
+    }
+
+    /// Whether the connection ends after the responses to the requests decoded so far. Nothing
+    /// more should be read once it does.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Decodes the head of the next request, leaving its body in `buf` to be streamed. Returns
+    /// how the body is framed along with the request.
+    pub fn decode_head(&mut self, buf: &mut EasyBuf) -> io::Result<Option<(Request, BodyLength)>> {
+        let req = try!(request::decode_head(buf, &self.scheme, self.max_headers, self.max_head_size,
+                                            self.remote_addr, self.router.clone(), self.logger.clone()));
+        if let Some((ref req, _)) = req {
+            self.start_exchange(req);
+        }
+        Ok(req)
+    }
+
+    /// Works out whether the connection stays open after the response to `req`: HTTP/1.1 is
+    /// persistent unless asked not to be, HTTP/1.0 only if asked to be, and neither once the
+    /// connection has served `max_requests`.
+    fn start_exchange(&mut self, req: &Request) {
+        self.requests += 1;
+        let connection = req.header("connection");
+        let keep_alive = match req.http_version() {
+            HttpVersion::Http10 => has_connection_option(connection, "keep-alive"),
+            _ => !has_connection_option(connection, "close"),
+        } && self.max_requests.map_or(true, |max| self.requests < max);
+        if !keep_alive {
+            self.closing = true;
+        }
+        let exchange = Exchange { version: req.http_version(), keep_alive: keep_alive, head: req.method() == Method::Head };
+        self.exchanges.push_back((req.clone(), exchange));
+    }
+
+    /// Writes the head of a response whose body is streamed after it. Returns how the body has
+    /// to be framed.
+    pub fn encode_head(&mut self, msg: &Response, buf: &mut Vec<u8>) -> BodyFraming {
+        let exchange = self.exchanges.pop_front();
+        let (framing, keep_alive) = response::encode_streamed(msg, exchange.as_ref().map_or(Exchange::default(), |e| e.1), buf);
+        self.finish_exchange(exchange, msg, keep_alive);
+        framing
+    }
+
+    fn finish_exchange(&mut self, exchange: Option<(Request, Exchange)>, msg: &Response, keep_alive: bool) {
+        if !keep_alive {
+            self.closing = true;
+        }
+        if let (&Some(ref logger), Some((ref request, _))) = (&self.logger, exchange) {
+            access_log(logger, self.remote_addr, request, msg);
+        }
+    }
+
+    /// Logs a request that `decode` rejected and returns the response to answer it with, or
+    /// `None` if the error is not a rejection and the connection should just be dropped. The
+    /// response closes the connection, as nothing after the rejected request can be trusted to
+    /// be where a request starts.
+    pub fn reject(&mut self, e: &io::Error) -> Option<Response> {
+        let rejection = match Rejection::from_error(e) {
+            Some(rejection) => rejection,
+            None => return None,
+        };
+        if let Some(ref logger) = self.logger {
+            let remote_addr = self.remote_addr.map_or("-".to_string(), |addr| format!("{}", addr));
+            logger.write(LoggerLevel::Warn, format!("{} - rejected request: {}", remote_addr, rejection));
+        }
+        Some(Response::new()
+             .with_status(rejection.status)
+             .with_header("Content-Length", "0")
+             .with_header("Connection", "close"))
+    }
+
+    /// Writes the response returned by `reject`. Unlike `encode`, this leaves the access log
+    /// alone, since there is no request to log it against. The connection is closing from then
+    /// on, so the rejected request is never decoded again.
+    pub fn encode_rejection(&mut self, res: &Response, buf: &mut Vec<u8>) {
+        self.closing = true;
+        response::encode(res, Exchange::default(), buf);
+    }
+}
+
 impl Codec for HttpCodec {
     type In = Request;
     type Out = Response;
 
     /// HttpCodec::decode can be modified to fit whatever is needed.
+    ///
+    /// Requests come with their whole body, which is decoded as it arrives.
     fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Request>> {
-        match request::decode(buf, self.remote_addr, self.router.clone(), self.logger.clone()) {
+        match request::decode(buf, &mut self.partial, &self.scheme, self.max_headers, self.max_head_size, self.max_body_size,
+                              self.remote_addr, self.router.clone(), self.logger.clone()) {
             Ok(req) => {
                 match req {
                     Some(req) => {
-                        self.request = Some(req.clone());
+                        self.start_exchange(&req);
                         Ok(Some(req))
                     }
                     None => Ok(None)
@@ -91,30 +283,33 @@ impl Codec for HttpCodec {
     }
 
     fn encode(&mut self, msg: Response, buf: &mut Vec<u8>) -> io::Result<()> {
-        response::encode(&msg, buf);
-        if self.logger.is_some() {
-            let logger = self.logger.clone().unwrap();
-            let request = self.request.clone().unwrap();
-            let referrer = "-"; //Check header
-            let mut remote_addr = "-".to_string();
-            match self.remote_addr {
-                Some(val) => remote_addr = format!("{}", val),
-                None => {},
-            }
-            logger.write(
-                LoggerLevel::Info,
-                format!("{} - \"{}\" {} {} \"{}\" \"{}\"",
-                remote_addr,
-                request.request_line(),
-                msg.code,
-                msg.content_length(),
-                referrer,
-                request.user_agent().unwrap_or("-")));
-        }
+        let exchange = self.exchanges.pop_front();
+        let keep_alive = response::encode(&msg, exchange.as_ref().map_or(Exchange::default(), |e| e.1), buf);
+        self.finish_exchange(exchange, &msg, keep_alive);
         Ok(())
     }
 }
 
+/// Writes the access log line for a request/response pair. Shared by the HTTP/1.x codec and the
+/// HTTP/2 connection so both produce the same log format.
+pub fn access_log(logger: &Logger, remote_addr: Option<SocketAddr>, request: &Request, response: &Response) {
+    let referrer = "-"; //Check header
+    let mut remote_addr_str = "-".to_string();
+    match remote_addr {
+        Some(val) => remote_addr_str = format!("{}", val),
+        None => {},
+    }
+    logger.write(
+        LoggerLevel::Info,
+        format!("{} - \"{}\" {} {} \"{}\" \"{}\"",
+        remote_addr_str,
+        request.request_line(),
+        response.code,
+        response.content_length(),
+        referrer,
+        request.user_agent().unwrap_or("-")));
+}
+
 // Original shown here for example reference...
 // pub struct Http;
 //
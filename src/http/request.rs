@@ -15,6 +15,8 @@
 #![allow(dead_code)]
 
 use std::{io, slice, str, fmt};
+use std::error::Error as StdError;
+use std::iter::FromIterator;
 use std::fs::File;
 use std::io::{Error, Read, BufReader};
 use std::net::SocketAddr;
@@ -22,20 +24,28 @@ use std::collections::HashMap;
 use std::collections::hash_map::Entry::*;
 use std::ops::DerefMut;
 use std::cmp;
+use std::time::Duration;
 use std::str::FromStr;
 
+use futures::{Async, Future, Poll, Stream};
 use tokio_core::io::{EasyBuf, EasyBufMut};
+use tokio_proto::streaming::Message;
 use unicase::UniCase;
 use httparse;
 use url::form_urlencoded;
 
 // use multipart::server::{HttpRequest, Multipart, Entries, SaveResult};
 use server::{HttpRequest, Multipart, Entries, SaveResult};
+use super::body::{Body, TokioBody};
 use super::buffer::Buffer;
+use super::chunked;
 use Method;
 use Handler;
+use HttpVersion;
 use Router;
 use Logger;
+use StatusCode;
+use Error as HttpError;
 
 /// Just a reader - Created to enforce the Read trait and to leave the under lying EasyBuf alone.
 #[derive(Clone)]
@@ -94,7 +104,10 @@ pub struct Request {
     uri: String,
     username: String,
     version: u8,
+    http_version: HttpVersion,
     remote_addr: Option<SocketAddr>,
+    /// Round-trip time of the connection, if it was measured.
+    rtt: Option<Duration>,
     headers: Vec<(Slice, Slice)>,
     data: ReqReader,
     /// Handler associated with the specific request. If none then the application (server)
@@ -102,16 +115,119 @@ pub struct Request {
     handler: Option<Handler>,
     /// Optional Logger associated with a given request
     pub logger: Option<Logger>,
+    body: StreamedBody,
 }
 
 type Slice = (usize, usize);
 
+/// The body of a request whose head was handed out before the body arrived. A stream can only be
+/// read once, so clones of the request go without it.
+struct StreamedBody(Option<Body>);
+
+impl Clone for StreamedBody {
+    fn clone(&self) -> StreamedBody {
+        StreamedBody(None)
+    }
+}
+
 #[derive(Debug)]
 pub struct RequestHeaders<'req> {
     pub headers: slice::Iter<'req, (Slice, Slice)>,
     req: &'req Request,
 }
 
+/// A header list laid out the way a `Request` keeps its headers: the names and values in a
+/// single buffer, and each field as a pair of slices into it.
+///
+/// Decoded HTTP/2 header lists are collected into one, so that building the `Request` takes the
+/// buffer over instead of copying each header again. Field names are checked as they are added,
+/// and a `Request` is not built from a list with a name HTTP/2 does not allow.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderList {
+    data: Vec<u8>,
+    fields: Vec<(Slice, Slice)>,
+    /// Set once a field name is not a lowercase token or pseudo-header (RFC 7540 section 8.1.2).
+    malformed: bool,
+}
+
+impl HeaderList {
+    pub fn new() -> HeaderList {
+        HeaderList::default()
+    }
+
+    /// Appends a field, copying its name and value into the list's buffer.
+    pub fn push(&mut self, name: &[u8], value: &[u8]) {
+        if !is_field_name(name) {
+            self.malformed = true;
+        }
+        let start = self.data.len();
+        self.data.extend_from_slice(name);
+        self.data.extend_from_slice(value);
+        let name = (start, start + name.len());
+        self.fields.push((name, (name.1, self.data.len())));
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Whether a field name was added that HTTP/2 does not allow.
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
+
+    pub fn iter(&self) -> HeaderListIter {
+        HeaderListIter {
+            fields: self.fields.iter(),
+            data: &self.data,
+        }
+    }
+}
+
+impl<N: AsRef<[u8]>, V: AsRef<[u8]>> Extend<(N, V)> for HeaderList {
+    fn extend<I: IntoIterator<Item=(N, V)>>(&mut self, iter: I) {
+        for (name, value) in iter {
+            self.push(name.as_ref(), value.as_ref());
+        }
+    }
+}
+
+impl<N: AsRef<[u8]>, V: AsRef<[u8]>> FromIterator<(N, V)> for HeaderList {
+    fn from_iter<I: IntoIterator<Item=(N, V)>>(iter: I) -> HeaderList {
+        let mut list = HeaderList::new();
+        list.extend(iter);
+        list
+    }
+}
+
+/// Whether `name` is a token without uppercase letters, or such a token after a colon for a
+/// pseudo-header.
+fn is_field_name(name: &[u8]) -> bool {
+    let token = if name.first() == Some(&b':') { &name[1..] } else { name };
+    !token.is_empty() && token.iter().all(|&b| {
+        (b >= b'a' && b <= b'z') || (b >= b'0' && b <= b'9') || b"!#$%&'*+-.^_`|~".contains(&b)
+    })
+}
+
+/// Iterator through the `(name, value)` fields of a `HeaderList`.
+pub struct HeaderListIter<'a> {
+    fields: slice::Iter<'a, (Slice, Slice)>,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for HeaderListIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        self.fields.next().map(|&(name, value)| (&self.data[name.0..name.1], &self.data[value.0..value.1]))
+    }
+}
+
+/// Reads the body, the same bytes `payload` gives, whichever protocol the request came over.
 impl Read for Request {
     fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<usize, io::Error> {
         let len = try!(self.data.read(buf));
@@ -153,7 +269,7 @@ impl Request {
     }
 
     pub fn method(&self) -> Method {
-        let method = str::from_utf8(self.slice(&self.method)).unwrap();
+        let method = str::from_utf8(self.slice(&self.method)).unwrap_or("");
         Method::from_str(method).unwrap_or(Method::Get)
     }
 
@@ -162,9 +278,12 @@ impl Request {
     }
 
     pub fn path(&self) -> &str {
-        str::from_utf8(self.slice(&self.path)).unwrap()
+        str::from_utf8(self.slice(&self.path)).unwrap_or("")
     }
 
+    /// The body, once it is buffered. It always is under `Http2Proto`, `HttpsProto` and
+    /// `HttpCodec::decode`. Under `HttpProto` the body is streamed: it comes through `take_body`,
+    /// or is here once the request `buffer_body` resolves to is.
     pub fn payload(&self) -> Option<&[u8]> {
         if self.payload.0 == 0 && self.payload.1 == 0 {
             None
@@ -173,6 +292,55 @@ impl Request {
         }
     }
 
+    /// Takes the body as a stream of chunks. A streamed body (see `HttpProto`) comes as it is
+    /// read off the connection, and can only be taken once. A buffered body comes as a single
+    /// chunk copied from the payload.
+    pub fn take_body(&mut self) -> Body {
+        if let Some(body) = self.body.0.take() {
+            return body;
+        }
+        match self.payload() {
+            Some(payload) => Body::from(payload.to_vec()),
+            None => Body::empty(),
+        }
+    }
+
+    /// Waits for a streamed body (see `HttpProto`) to come in whole, so that `payload` and `Read`
+    /// give it, e.g. before the request is passed to a `Handler`. A body that is already
+    /// buffered is left as it is. Fails with `Error::TooLarge` once the body grows beyond
+    /// `max_size` bytes.
+    pub fn buffer_body(mut self, max_size: usize) -> BufferBody {
+        let body = self.body.0.take();
+        BufferBody {
+            request: Some(self),
+            body: body,
+            buf: Vec::new(),
+            max_size: max_size,
+        }
+    }
+
+    /// Puts a buffered body at the end of the request's data. The trailer section of a chunked
+    /// body goes between the head and the body, and its fields, positioned in `trailers`, are
+    /// added to the headers.
+    fn set_body(&mut self, body: &[u8], trailers: &[u8], fields: &[((usize, usize), (usize, usize))]) {
+        let trailers_start = self.data.inner.len();
+        let body_start = trailers_start + trailers.len();
+        {
+            let mut data = self.data.inner.get_mut();
+            data.extend_from_slice(trailers);
+            data.extend_from_slice(body);
+        }
+        self.data.reset();
+        self.data.consume(body_start);
+        self.content_length = body.len();
+        if !body.is_empty() {
+            self.payload = (body_start, body_start + body.len());
+        }
+        self.headers.extend(fields.iter().map(|&(n, v)| {
+            ((trailers_start + n.0, trailers_start + n.1), (trailers_start + v.0, trailers_start + v.1))
+        }));
+    }
+
     pub fn query(&self) -> Option<HashMap<String, Vec<String>>> {
         if self.query.0 == 0 && self.query.1 == 0 {
             None
@@ -208,6 +376,16 @@ impl Request {
         self.remote_addr
     }
 
+    /// Round-trip time last measured on the connection the request arrived on. Only HTTP/2
+    /// connections with keepalive PINGs measure it.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn set_rtt(&mut self, rtt: Option<Duration>) {
+        self.rtt = rtt;
+    }
+
     pub fn request_line(&self) -> &str {
         &self.request_line
     }
@@ -228,6 +406,10 @@ impl Request {
         self.version
     }
 
+    pub fn http_version(&self) -> HttpVersion {
+        self.http_version
+    }
+
     pub fn header(&self, key: &str) -> Option<&str> {
         match self.headers().find(|&(k, v)| UniCase(k) == UniCase(key)) {
             Some((key, value)) => Some(str::from_utf8(value).unwrap_or("")),
@@ -242,6 +424,136 @@ impl Request {
         }
     }
 
+    /// Builds a `Request` from a decoded HTTP/2 header list and the stream's body.
+    ///
+    /// The body is appended to the header list's buffer, so the resulting `Request` slices into
+    /// its data the same way as one decoded from HTTP/1.x. Pseudo-headers (`:method`, `:path`,
+    /// `:scheme` and `:authority`) are not kept in the regular header list but are exposed
+    /// through the usual accessors.
+    pub fn from_header_list(header_list: HeaderList,
+                            body: Vec<u8>,
+                            http_version: HttpVersion,
+                            remote_addr: Option<SocketAddr>,
+                            router: Option<Router>,
+                            logger: Option<Logger>)
+                            -> io::Result<Request> {
+        if header_list.is_malformed() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header field name"));
+        }
+        let HeaderList { mut data, fields, .. } = header_list;
+        let mut method: Option<Slice> = None;
+        let mut uri: Option<Slice> = None;
+        let mut scheme = String::from("http");
+        let mut host = String::new();
+        let mut headers: Vec<(Slice, Slice)> = Vec::new();
+
+        for (name_slice, value_slice) in fields {
+            let name = &data[name_slice.0..name_slice.1];
+            let value = &data[value_slice.0..value_slice.1];
+
+            match name {
+                b":method" => method = Some(value_slice),
+                b":path" => uri = Some(value_slice),
+                b":scheme" => scheme = String::from_utf8_lossy(value).into_owned(),
+                b":authority" => host = String::from_utf8_lossy(value).into_owned(),
+                _ if name.first() == Some(&b':') => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown pseudo-header"));
+                },
+                _ => {
+                    // Field names are always lowercase in HTTP/2.
+                    if name == b"host" && host.is_empty() {
+                        host = String::from_utf8_lossy(value).into_owned();
+                    }
+                    headers.push((name_slice, value_slice));
+                },
+            }
+        }
+
+        let (method, uri) = match (method, uri) {
+            (Some(method), Some(uri)) => (method, uri),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "missing :method or :path")),
+        };
+
+        let method_str = try!(str::from_utf8(&data[method.0..method.1]).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })).to_string();
+        let uri_str = try!(str::from_utf8(&data[uri.0..uri.1]).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })).to_string();
+
+        let query: Slice;
+        let path: Slice;
+
+        if let Some(index) = uri_str.find('?') {
+            path = (uri.0, uri.0 + index);
+            query = (path.1 + 1, uri.1);
+        } else {
+            path = (uri.0, uri.1);
+            query = (0, 0);
+        }
+
+        let find = |key: &str| {
+            headers.iter()
+                   .find(|&&(k, _)| &data[k.0..k.1] == key.as_bytes())
+                   .map(|&(_, v)| String::from_utf8_lossy(&data[v.0..v.1]).into_owned())
+        };
+
+        let content_type = match &method_str[..] {
+            "POST" | "PUT" => find("content-type").unwrap_or("application/octet-stream".to_string()),
+            _ => find("accept").unwrap_or("text/plain".to_string()),
+        };
+
+        let mut content_type_metadata = String::new();
+
+        match content_type.find(';') {
+            Some(index) => {
+                content_type_metadata = content_type[index+1..].trim().to_string();
+            },
+            None => {},
+        }
+
+        let mut handler: Option<Handler> = None;
+        if router.is_some() {
+            let m = Method::from_str(&method_str).unwrap_or(Method::Get);
+            let p = str::from_utf8(&data[path.0..path.1]).unwrap_or("");
+            handler = router.unwrap().find_handler_with_method_and_path(m, p);
+        }
+
+        let content_length = body.len();
+        let body_start = data.len();
+        data.extend_from_slice(&body);
+        let payload: Slice = if content_length > 0 {(body_start, data.len())} else {(0, 0)};
+
+        let mut reader = ReqReader::new(EasyBuf::from(data));
+        // Reading an HTTP/2 request yields the body only.
+        reader.consume(body_start);
+
+        Ok(Request {
+            content_length: content_length,
+            content_type: content_type,
+            content_type_metadata: content_type_metadata,
+            host: host.clone(),
+            method: method,
+            password: "".to_string(),
+            path: path,
+            payload: payload,
+            query: query,
+            remote_addr: remote_addr,
+            rtt: None,
+            request_line: format!("{} {} HTTP/2.0", method_str, uri_str),
+            uri: format!("{}://{}{}", scheme, host, uri_str),
+            scheme: scheme,
+            username: "".to_string(),
+            version: 0,
+            http_version: http_version,
+            headers: headers,
+            data: reader,
+            handler: handler,
+            logger: logger,
+            body: StreamedBody(None),
+        })
+    }
+
     // Extracts the data from the buffer at the given offset for the given length
     fn slice(&self, slice: &Slice) -> &[u8] {
         &self.data.as_slice()[slice.0..slice.1]
@@ -250,6 +562,37 @@ impl Request {
 }
 
 
+// Returns the last transfer coding applied to the request body, if it has any. That is the one
+// the body has to be decoded with first, and which tells where the body ends.
+fn transfer_coding<'h, 'b>(req: &httparse::Request<'h, 'b>) -> Option<&'b str> {
+    req.headers.iter()
+       .filter(|h| UniCase(h.name) == UniCase("transfer-encoding"))
+       .last()
+       .map(|h| str::from_utf8(h.value).unwrap_or("").split(',').last().unwrap_or("").trim())
+}
+
+// Returns the request's Content-Length, if it has one. A value that is not a number, or repeated
+// values that differ, leave it unclear where the body ends, so they are an error (RFC 7230
+// section 3.3.3).
+fn parse_content_length(req: &httparse::Request) -> Result<Option<usize>, HttpError> {
+    let mut length = None;
+    for h in req.headers.iter().filter(|h| UniCase(h.name) == UniCase("content-length")) {
+        // Some clients join repeated fields into a list.
+        for value in try!(str::from_utf8(h.value).map_err(|_| HttpError::Header)).split(',') {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b >= b'0' && b <= b'9') {
+                return Err(HttpError::Header);
+            }
+            let value = try!(value.parse::<usize>().map_err(|_| HttpError::Header));
+            if length.map_or(false, |length| length != value) {
+                return Err(HttpError::Header);
+            }
+            length = Some(value);
+        }
+    }
+    Ok(length)
+}
+
 // Extract header value using key. If not found or can't be converted to &str then None else the &str value.
 fn header<'a>(req: &'a mut httparse::Request, key: &str) -> Option<&'a str> {
     let value: &str;
@@ -268,6 +611,49 @@ fn header<'a>(req: &'a mut httparse::Request, key: &str) -> Option<&'a str> {
     None
 }
 
+/// Attaches the body of a streamed request to it, so services served by `HttpProto` can go
+/// from the `Message` they are called with to a `Request`.
+/// Future returned by `Request::buffer_body`.
+pub struct BufferBody {
+    request: Option<Request>,
+    body: Option<Body>,
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl Future for BufferBody {
+    type Item = Request;
+    type Error = HttpError;
+
+    fn poll(&mut self) -> Poll<Request, HttpError> {
+        if let Some(ref mut body) = self.body {
+            while let Some(chunk) = try_ready!(body.poll()) {
+                if self.buf.len() + chunk.len() > self.max_size {
+                    return Err(HttpError::TooLarge);
+                }
+                self.buf.extend_from_slice(&chunk);
+            }
+        }
+        let mut request = self.request.take().expect("BufferBody polled after it resolved");
+        if self.body.take().is_some() {
+            request.set_body(&self.buf, &[], &[]);
+        }
+        Ok(Async::Ready(request))
+    }
+}
+
+impl From<Message<Request, TokioBody>> for Request {
+    fn from(msg: Message<Request, TokioBody>) -> Request {
+        match msg {
+            Message::WithoutBody(req) => req,
+            Message::WithBody(mut req, body) => {
+                req.body.0 = Some(Body::from(body));
+                req
+            },
+        }
+    }
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<HTTP Request {} {}>", self.method(), self.path())
@@ -290,28 +676,242 @@ fn combine_duplicates<I: Iterator<Item=(String, String)>>(collection: I) -> Hash
     deduplicated
 }
 
+/// A request turned away by `decode` before it got to the service. It travels inside the
+/// `io::Error` that `decode` returns so the transport can answer with `status`.
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub error: HttpError,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, error: HttpError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Rejection { status: status, error: error })
+    }
+
+    /// Rejects a request that failed to parse, with the status that goes with the error.
+    fn malformed<E: Into<HttpError>>(error: E) -> io::Error {
+        let error = error.into();
+        let status = match error {
+            HttpError::Version => StatusCode::HttpVersionNotSupported,
+            HttpError::TooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            _ => StatusCode::BadRequest,
+        };
+        Rejection::new(status, error)
+    }
+
+    /// Returns the rejection carried by an error from `decode`, if there is one.
+    pub fn from_error(e: &io::Error) -> Option<&Rejection> {
+        e.get_ref().and_then(|e| e.downcast_ref::<Rejection>())
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status.to_u16(), self.error)
+    }
+}
+
+impl StdError for Rejection {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        Some(&self.error)
+    }
+}
+
+/// Works out how many headers the head at the start of `buf` can hold, so the header array only
+/// grows as large as the request needs. Every header takes a line of its own, which makes the
+/// number of lines an upper bound.
+///
+/// Heads over `max_head_size` bytes or with more than `max_headers` headers are rejected with
+/// 431 Request Header Fields Too Large, or 414 URI Too Long if the request line alone is too
+/// long, without waiting for the rest of the head.
+fn header_capacity(buf: &[u8], max_headers: usize, max_head_size: usize) -> io::Result<usize> {
+    let mut lines: usize = 0;
+    let mut head_len = None;
+    for (i, _) in buf.iter().enumerate().filter(|&(_, &b)| b == b'\n') {
+        lines += 1;
+        let rest = &buf[i + 1..];
+        if rest.starts_with(b"\n") || rest.starts_with(b"\r\n") {
+            head_len = Some(i + 1 + if rest[0] == b'\n' { 1 } else { 2 });
+            break;
+        }
+    }
+
+    if head_len.unwrap_or(buf.len()) > max_head_size {
+        let status = if lines == 0 { StatusCode::UriTooLong } else { StatusCode::RequestHeaderFieldsTooLarge };
+        return Err(Rejection::new(status, HttpError::TooLarge));
+    }
+    // The first line is the request line. While the head is incomplete, a partial header line
+    // may follow the complete ones.
+    if lines.saturating_sub(1) > max_headers {
+        return Err(Rejection::new(StatusCode::RequestHeaderFieldsTooLarge, HttpError::TooLarge));
+    }
+    Ok(cmp::max(lines, 1))
+}
+
+/// Whether the request line at the start of `buf` is a method, a target and an `HTTP/x.y`
+/// version.
+fn has_http_version(buf: &[u8]) -> bool {
+    let line = buf.split(|&b| b == b'\n').next().unwrap_or(b"");
+    let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+    let parts: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+    if parts.len() != 3 {
+        return false;
+    }
+    let version = parts[2];
+    version.len() == 8 && version.starts_with(b"HTTP/") && version[6] == b'.' &&
+        (version[5] as char).is_digit(10) && (version[7] as char).is_digit(10)
+}
+
+/// How the body that follows a head decoded by `decode_head` is framed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    /// The body is as long as its Content-Length.
+    Length(usize),
+    /// The body uses the chunked transfer coding.
+    Chunked,
+}
+
+/// Decodes the body that follows a head as it arrives, framed as its `BodyLength` says.
+#[derive(Debug)]
+pub enum BodyDecoder {
+    /// This many bytes are left.
+    Length(usize),
+    Chunked(chunked::Decoder),
+}
+
+impl BodyDecoder {
+    /// Returns `None` if there is no body.
+    pub fn new(length: BodyLength) -> Option<BodyDecoder> {
+        match length {
+            BodyLength::Empty => None,
+            BodyLength::Length(len) => Some(BodyDecoder::Length(len)),
+            BodyLength::Chunked => Some(BodyDecoder::Chunked(chunked::Decoder::new())),
+        }
+    }
+
+    /// Takes what it can of the body from the start of `buf`. Yields the data as it comes, and
+    /// `None` at the end of the body. `NotReady` means `buf` needs more bytes.
+    pub fn decode(&mut self, buf: &mut EasyBuf) -> Poll<Option<Vec<u8>>, io::Error> {
+        match *self {
+            BodyDecoder::Length(0) => Ok(Async::Ready(None)),
+            BodyDecoder::Length(_) if buf.len() == 0 => Ok(Async::NotReady),
+            BodyDecoder::Length(ref mut left) => {
+                let len = cmp::min(*left, buf.len());
+                *left -= len;
+                Ok(Async::Ready(Some(buf.drain_to(len).as_slice().to_vec())))
+            },
+            BodyDecoder::Chunked(ref mut decoder) => decoder.decode(buf),
+        }
+    }
+}
+
+/// A request handed out by `decode` once its body is in. Until then, it is kept here along with
+/// the body decoded so far, so that each read only looks at the bytes that are new.
+pub struct PartialRequest {
+    request: Request,
+    decoder: BodyDecoder,
+    body: Vec<u8>,
+}
+
 /// Decode is a stand alone function since it creates the Request struct.
 /// EasyBuf is from Tokio-core and is used for handling slices without having to create additional buffers.
 /// Because of the slices, the methods used the begin and end parts of the Slice to determine where
 /// to extract from the lower level EasyBuf.
+///
+/// The request is returned with its whole body, which goes after the head in the request's data.
+/// While the body is coming in, the request waits in `partial` between calls. A body larger than
+/// `max_body_size` gets the request rejected with 413 (Payload Too Large). The trailer fields of
+/// a chunked body are added to the headers.
 pub fn decode(buf: &mut EasyBuf,
+              partial: &mut Option<PartialRequest>,
+              scheme: &str,
+              max_headers: usize,
+              max_head_size: usize,
+              max_body_size: usize,
               remote_addr: Option<SocketAddr>,
               router: Option<Router>,
               logger: Option<Logger>)
               -> io::Result<Option<Request>> {
-    let (content_length, content_type, content_type_metadata, handler, host, method, path, payload, query, request_line, scheme, uri, version, headers, amt) = {
-        let mut headers = [httparse::EMPTY_HEADER; 16];
+    if partial.is_none() {
+        let (request, length) = match try!(decode_head(buf, scheme, max_headers, max_head_size, remote_addr, router, logger)) {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        if let BodyLength::Length(len) = length {
+            if len > max_body_size {
+                return Err(Rejection::new(StatusCode::PayloadTooLarge, HttpError::TooLarge));
+            }
+        }
+        match BodyDecoder::new(length) {
+            Some(decoder) => *partial = Some(PartialRequest { request: request, decoder: decoder, body: Vec::new() }),
+            None => return Ok(Some(request)),
+        }
+    }
+
+    {
+        let partial = partial.as_mut().unwrap();
+        loop {
+            match try!(partial.decoder.decode(buf).map_err(Rejection::malformed)) {
+                Async::Ready(Some(data)) => {
+                    if partial.body.len() + data.len() > max_body_size {
+                        return Err(Rejection::new(StatusCode::PayloadTooLarge, HttpError::TooLarge));
+                    }
+                    partial.body.extend_from_slice(&data);
+                },
+                Async::Ready(None) => break,
+                Async::NotReady => return Ok(None),
+            }
+        }
+    }
+
+    let PartialRequest { mut request, decoder, body } = partial.take().unwrap();
+    let (trailers, fields) = match decoder {
+        BodyDecoder::Chunked(ref decoder) => decoder.trailers(),
+        BodyDecoder::Length(_) => (&[][..], &[][..]),
+    };
+    request.set_body(&body, trailers, fields);
+    Ok(Some(request))
+}
+
+/// Like `decode`, but returns as soon as the head is there and leaves the body in `buf`, to be
+/// streamed as it arrives. The returned `Request` has no payload.
+pub fn decode_head(buf: &mut EasyBuf,
+                   scheme: &str,
+                   max_headers: usize,
+                   max_head_size: usize,
+                   remote_addr: Option<SocketAddr>,
+                   router: Option<Router>,
+                   logger: Option<Logger>)
+                   -> io::Result<Option<(Request, BodyLength)>> {
+    let capacity = try!(header_capacity(buf.as_slice(), max_headers, max_head_size));
+    let (content_length, content_type, content_type_metadata, handler, host, method, path, query, request_line, scheme, uri, version, headers, body_length, amt) = {
+        let mut headers = vec![httparse::EMPTY_HEADER; capacity];
         let mut r = httparse::Request::new(&mut headers);
-        let status = try!(r.parse(buf.as_slice()).map_err(|e| {
-            let msg = format!("failed to parse http request: {:?}", e);
-            io::Error::new(io::ErrorKind::Other, msg)
+        let status = try!(r.parse(buf.as_slice()).map_err(|e| match e {
+            // httparse takes whatever follows the target for the version, so only a real
+            // version that is not 1.0 or 1.1 is unsupported. Anything else is a bad request line.
+            httparse::Error::Version if !has_http_version(buf.as_slice()) => {
+                Rejection::new(StatusCode::BadRequest, HttpError::Version)
+            },
+            e => Rejection::malformed(e),
         }));
 
-        let mut amt = match status {
+        let amt = match status {
             httparse::Status::Complete(amt) => amt,
             httparse::Status::Partial => return Ok(None),
         };
 
+        // httparse lets bytes above 0x7f through in the request target without checking that
+        // they make up UTF-8.
+        try!(str::from_utf8(r.path.unwrap().as_bytes()).map_err(Rejection::malformed));
+        let method_type = try!(Method::from_str(r.method.unwrap()).map_err(Rejection::malformed));
+
         // println!("{:?}", String::from_utf8_lossy(buf.as_slice()));
 
         let toslice = |a: &[u8]| {
@@ -320,7 +920,7 @@ pub fn decode(buf: &mut EasyBuf,
             (start, start + a.len())
         };
 
-        let scheme = String::from("http");  // TODO: Hardcoded for now!
+        let scheme = scheme.to_string();
         let host = header(&mut r, "host").unwrap_or("").to_string();
         let content_type: String;
 
@@ -339,17 +939,31 @@ pub fn decode(buf: &mut EasyBuf,
             None => {},
         }
 
-        let content_length: usize = header(&mut r, "content-length").unwrap_or("0").parse::<usize>().unwrap_or(0);
+        let mut content_length = try!(parse_content_length(&r).map_err(Rejection::malformed)).unwrap_or(0);
 
-        // Adjust `amt` to also include payload
-        amt += content_length;
+        // The length of a chunked body is only known once it is in. With both Transfer-Encoding
+        // and Content-Length, a proxy in front of us could disagree on where the request ends,
+        // so such requests are rejected.
+        let body_length = match transfer_coding(&r) {
+            None if content_length > 0 => BodyLength::Length(content_length),
+            None => BodyLength::Empty,
+            Some(_) if r.headers.iter().any(|h| UniCase(h.name) == UniCase("content-length")) => {
+                return Err(Rejection::malformed(HttpError::Header));
+            },
+            Some(coding) if UniCase(coding) != UniCase("chunked") => {
+                return Err(Rejection::malformed(HttpError::Header));
+            },
+            Some(_) => {
+                content_length = 0;
+                BodyLength::Chunked
+            },
+        };
 
         let method = toslice(r.method.unwrap().as_bytes());
         let uri = toslice(r.path.unwrap().as_bytes());
         let uri_str = r.path.unwrap();
         let query: Slice;
         let path: Slice;
-        let payload: Slice = if content_length > 0 {((amt as u64 - content_length as u64) as usize, amt)} else {(0,0)};
 
         // NB: Parse out username and password for basic auth later
 
@@ -365,9 +979,8 @@ pub fn decode(buf: &mut EasyBuf,
 
         let mut handler: Option<Handler> = None;
         if router.is_some() {
-            let m = Method::from_str(r.method.unwrap()).unwrap();
             let p = str::from_utf8(&buf.as_slice()[path.0..path.1]).unwrap_or("");
-            handler = router.unwrap().find_handler_with_method_and_path(m, p);
+            handler = router.unwrap().find_handler_with_method_and_path(method_type, p);
         }
 
         let request_line = format!("{} {} HTTP/1.{}", r.method.unwrap(), r.path.unwrap_or(""), r.version.unwrap());
@@ -380,7 +993,6 @@ pub fn decode(buf: &mut EasyBuf,
          host,
          method,
          path,
-         payload,
          query,
          request_line,
          scheme,
@@ -390,10 +1002,15 @@ pub fn decode(buf: &mut EasyBuf,
           .iter()
           .map(|h| (toslice(h.name.as_bytes()), toslice(h.value)))
           .collect(),
+         body_length,
          amt
         )
     };
 
+    // `Read` only gives the body, which is not there yet.
+    let mut data = ReqReader::new(buf.drain_to(amt));
+    data.consume(amt);
+
     let res = Request {
         content_length: content_length,
         content_type: content_type,
@@ -402,32 +1019,38 @@ pub fn decode(buf: &mut EasyBuf,
         method: method,
         password: "".to_string(),
         path: path,
-        payload: payload,
+        payload: (0, 0),
         query: query,
         remote_addr: remote_addr,
+        rtt: None,
         request_line: request_line,
         scheme: scheme,
         uri: uri,
         username: "".to_string(),
         version: version,
+        http_version: if version == 0 { HttpVersion::Http10 } else { HttpVersion::Http11 },
         headers: headers,
-        data: ReqReader::new(buf.drain_to(amt)),
+        data: data,
         handler: handler,
         logger: logger,
+        body: StreamedBody(None),
     };
 
-    Ok(Some(res))
+    Ok(Some((res, body_length)))
 }
 
 impl<'req> Iterator for RequestHeaders<'req> {
     type Item = (&'req str, &'req [u8]);
 
     fn next(&mut self) -> Option<(&'req str, &'req [u8])> {
-        self.headers.next().map(|&(ref a, ref b)| {
-            let a = self.req.slice(a);
-            let b = self.req.slice(b);
-            (str::from_utf8(a).unwrap(), b)
-        })
+        // Names are checked when the request is decoded, so one that is not UTF-8 is skipped
+        // rather than trusted.
+        while let Some(&(ref a, ref b)) = self.headers.next() {
+            if let Ok(name) = str::from_utf8(self.req.slice(a)) {
+                return Some((name, self.req.slice(b)));
+            }
+        }
+        None
     }
 }
 
@@ -451,3 +1074,254 @@ impl HttpRequest for Request {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tokio_core::io::EasyBuf;
+
+    use http::{DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE};
+    use status::StatusCode;
+    use futures::{Future, Stream};
+    use tokio_proto::streaming::Message;
+
+    use http::Chunk;
+    use http::body::TokioBody;
+    use super::{decode, decode_head, BodyLength, HeaderList, Rejection, Request};
+    use Error;
+    use HttpVersion;
+
+    #[test]
+    fn test_decode_chunked() {
+        let raw = b"POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: abc\r\n\r\n\
+                    GET / HTTP/1.1\r\n\r\n";
+        let split = raw.len() - 18;
+
+        // Nothing is decoded until the whole body is there, however it arrives.
+        let mut partial = None;
+        let mut buf = EasyBuf::new();
+        for &b in &raw[..split - 1] {
+            buf.get_mut().push(b);
+            assert!(decode(&mut buf, &mut partial, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().is_none());
+        }
+
+        buf.get_mut().extend_from_slice(&raw[split - 1..]);
+        let mut req = decode(&mut buf, &mut partial, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap();
+        assert!(partial.is_none());
+        assert_eq!(req.payload(), Some(&b"hello world"[..]));
+        assert_eq!(req.content_length(), 11);
+        assert_eq!(req.header("x-checksum"), Some("abc"));
+
+        // The next request starts right after the body.
+        assert_eq!(buf.as_slice(), &raw[split..]);
+        assert_eq!(decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap().path(), "/");
+
+        let mut data = Vec::new();
+        req.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world");
+    }
+
+    #[test]
+    fn test_decode_ambiguous_length() {
+        for raw in &[&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n"[..],
+                     &b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"[..],
+                     &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n"[..]] {
+            let mut buf = EasyBuf::from(raw.to_vec());
+            assert!(decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_content_length() {
+        let body = b"hello";
+        for length in &["5x", "+5", "-5", "", "5 5", "99999999999999999999999"] {
+            let mut raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length).into_bytes();
+            raw.extend_from_slice(body);
+            let mut buf = EasyBuf::from(raw);
+            assert!(decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).is_err());
+        }
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 0\r\n\r\nhello";
+        let mut buf = EasyBuf::from(raw.to_vec());
+        assert!(decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).is_err());
+
+        // Repeated values are fine as long as they agree.
+        for raw in &[&b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello"[..],
+                     &b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\nhello"[..]] {
+            let mut buf = EasyBuf::from(raw.to_vec());
+            let req = decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap();
+            assert_eq!(req.payload(), Some(&b"hello"[..]));
+        }
+    }
+
+    #[test]
+    fn test_decode_partial_body() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let head_len = raw.len() - 5;
+
+        // The head is only decoded once. Each read after it takes what it can of the body.
+        let mut partial = None;
+        let mut buf = EasyBuf::new();
+        for (i, &b) in raw[..raw.len() - 1].iter().enumerate() {
+            buf.get_mut().push(b);
+            assert!(decode(&mut buf, &mut partial, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().is_none());
+            assert_eq!(partial.is_some(), i + 1 >= head_len);
+            if partial.is_some() {
+                assert_eq!(buf.len(), 0);
+            }
+        }
+
+        buf.get_mut().push(b'o');
+        let req = decode(&mut buf, &mut partial, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap();
+        assert_eq!(req.payload(), Some(&b"hello"[..]));
+        assert_eq!(req.content_length(), 5);
+    }
+
+    #[test]
+    fn test_decode_body_too_large() {
+        let too_large = StatusCode::PayloadTooLarge.to_u16();
+        let decode_limited = |raw: &[u8]| {
+            let mut buf = EasyBuf::from(raw.to_vec());
+            decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, 10, None, None, None)
+        };
+
+        let req = decode_limited(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789").unwrap().unwrap();
+        assert_eq!(req.payload(), Some(&b"0123456789"[..]));
+        let req = decode_limited(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n01234\r\n5\r\n56789\r\n0\r\n\r\n").unwrap().unwrap();
+        assert_eq!(req.payload(), Some(&b"0123456789"[..]));
+
+        // A Content-Length over the limit is turned away before any of the body is read.
+        let e = decode_limited(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n").unwrap_err();
+        assert_eq!(Rejection::from_error(&e).map(|r| r.status.to_u16()), Some(too_large));
+
+        // A chunked body is turned away as soon as it grows past the limit, whether or not it
+        // would ever end.
+        let e = decode_limited(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n01234\r\n6\r\n56789a").unwrap_err();
+        assert_eq!(Rejection::from_error(&e).map(|r| r.status.to_u16()), Some(too_large));
+    }
+
+    #[test]
+    fn test_decode_head() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        let mut buf = EasyBuf::from(raw.to_vec());
+        let (req, length) = decode_head(&mut buf, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, None, None, None).unwrap().unwrap();
+        assert_eq!(length, BodyLength::Length(5));
+        assert_eq!(req.content_length(), 5);
+        assert_eq!(req.payload(), None);
+        assert_eq!(buf.as_slice(), b"hel");
+
+        let mut req = Request::from(Message::WithBody(req, TokioBody::from(Chunk::from(b"hello".to_vec()))));
+        let chunks = req.take_body().collect().wait().unwrap();
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), 5);
+        assert!(req.take_body().collect().wait().unwrap().is_empty());
+
+        let mut buf = EasyBuf::from(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec());
+        let (_, length) = decode_head(&mut buf, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, None, None, None).unwrap().unwrap();
+        assert_eq!(length, BodyLength::Chunked);
+    }
+
+    #[test]
+    fn test_buffer_body() {
+        let streamed = || {
+            let mut buf = EasyBuf::from(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nX-Id: 7\r\n\r\n".to_vec());
+            let (req, _) = decode_head(&mut buf, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, None, None, None).unwrap().unwrap();
+            Request::from(Message::WithBody(req, TokioBody::from(Chunk::from(b"hello".to_vec()))))
+        };
+
+        // Until the streamed body is buffered, there is nothing to read.
+        let mut req = streamed();
+        let mut data = Vec::new();
+        req.read_to_end(&mut data).unwrap();
+        assert!(data.is_empty() && req.payload().is_none());
+
+        let mut req = streamed().buffer_body(5).wait().unwrap();
+        assert_eq!(req.payload(), Some(&b"hello"[..]));
+        assert_eq!(req.header("x-id"), Some("7"));
+        req.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+
+        match streamed().buffer_body(4).wait() {
+            Err(Error::TooLarge) => {},
+            _ => panic!("expected the body to be too large"),
+        }
+
+        // A buffered body is left alone, and reads the same over HTTP/2.
+        let mut buf = EasyBuf::from(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".to_vec());
+        let req = decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap();
+        assert_eq!(req.buffer_body(0).wait().unwrap().payload(), Some(&b"hello"[..]));
+
+        let list: HeaderList = vec![(&b":method"[..], &b"POST"[..]), (&b":path"[..], &b"/"[..])].into_iter().collect();
+        let mut req = Request::from_header_list(list, b"hello".to_vec(), HttpVersion::H2c, None, None, None).unwrap();
+        data.clear();
+        req.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    fn rejected(raw: &[u8], max_headers: usize, max_head_size: usize) -> Option<u16> {
+        let mut buf = EasyBuf::from(raw.to_vec());
+        match decode(&mut buf, &mut None, "http", max_headers, max_head_size, DEFAULT_MAX_BODY_SIZE, None, None, None) {
+            Err(e) => Rejection::from_error(&e).map(|r| r.status.to_u16()),
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_decode_many_headers() {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..40 {
+            raw.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+
+        let mut buf = EasyBuf::from(raw.clone());
+        let req = decode(&mut buf, &mut None, "http", DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE, DEFAULT_MAX_BODY_SIZE, None, None, None).unwrap().unwrap();
+        assert_eq!(req.header("x-header-39"), Some("39"));
+        assert_eq!(rejected(&raw, 40, raw.len()), None);
+
+        let too_large = StatusCode::RequestHeaderFieldsTooLarge.to_u16();
+        assert_eq!(rejected(&raw, 39, DEFAULT_MAX_HEAD_SIZE), Some(too_large));
+        assert_eq!(rejected(&raw, 40, raw.len() - 1), Some(too_large));
+        // Neither limit waits for the end of the head.
+        assert_eq!(rejected(&raw[..raw.len() / 2], 10, DEFAULT_MAX_HEAD_SIZE), Some(too_large));
+        assert_eq!(rejected(&raw[..raw.len() / 2], 40, 100), Some(too_large));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let cases: &[(&[u8], StatusCode)] = &[
+            (b"GE,T / HTTP/1.1\r\n\r\n", StatusCode::BadRequest),
+            (b"GET /a b HTTP/1.1\r\n\r\n", StatusCode::BadRequest),
+            (b"GET /\xff HTTP/1.1\r\n\r\n", StatusCode::BadRequest),
+            (b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n", StatusCode::BadRequest),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", StatusCode::BadRequest),
+            (b"POST / HTTP/1.1\r\nContent-Length: 5x\r\n\r\nhello", StatusCode::BadRequest),
+            (b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 0\r\n\r\nhello", StatusCode::BadRequest),
+            (b"GET / HTTP/2.0\r\n\r\n", StatusCode::HttpVersionNotSupported),
+        ];
+        for &(raw, status) in cases {
+            assert_eq!(rejected(raw, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEAD_SIZE), Some(status.to_u16()));
+        }
+
+        let mut raw = b"GET /".to_vec();
+        raw.extend(vec![b'a'; 200]);
+        assert_eq!(rejected(&raw, DEFAULT_MAX_HEADERS, 100), Some(StatusCode::UriTooLong.to_u16()));
+    }
+
+    #[test]
+    fn test_from_header_list_field_names() {
+        let list = |name: &[u8]| {
+            let list: HeaderList = vec![(&b":method"[..], &b"GET"[..]), (&b":path"[..], &b"/"[..]),
+                                        (name, &b"x"[..])].into_iter().collect();
+            list
+        };
+        let req = Request::from_header_list(list(b"x-custom_1"), Vec::new(), HttpVersion::H2c, None, None, None).unwrap();
+        assert_eq!(req.header("x-custom_1"), Some("x"));
+
+        for name in &[&b"\xff\xfe"[..], b"User-Agent", b"x header", b"", b":"] {
+            assert!(list(name).is_malformed());
+            assert!(Request::from_header_list(list(name), Vec::new(), HttpVersion::H2c, None, None, None).is_err());
+        }
+    }
+}
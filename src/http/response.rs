@@ -22,6 +22,7 @@ use unicase::UniCase;
 use http::date;
 use Body;
 use Headers;
+use HttpVersion;
 use StatusCode;
 
 #[derive(Clone, Debug)]
@@ -31,6 +32,11 @@ pub struct Response {
     pub status_message: StatusMessage,
     pub code: u16,
     pub message: String,
+    /// Paths of resources to push along with the response. Only used by HTTP/2 connections.
+    pub pushes: Vec<String>,
+    /// Trailer fields sent after the body. Only used when the body is streamed with the chunked
+    /// transfer coding.
+    pub trailers: Headers,
 }
 
 #[derive(Clone, Debug)]
@@ -49,6 +55,8 @@ impl Response {
             status_message: StatusMessage::Custom(status.to_u16(), status.canonical_reason().unwrap_or("").to_string()),
             code: status.to_u16(),
             message: status.canonical_reason().unwrap_or("").to_string(),
+            pushes: Vec::new(),
+            trailers: Headers::new(),
         };
 
         res
@@ -66,6 +74,22 @@ impl Response {
         self
     }
 
+    /// Promises the resource at `path` to the client. On an HTTP/2 connection the server sends a
+    /// PUSH_PROMISE for a GET of the path and serves it through the router like any other
+    /// request. Ignored for HTTP/1.x and when the client disabled push.
+    #[inline]
+    pub fn with_push(mut self, path: &str) -> Self {
+        self.pushes.push(path.to_string());
+        self
+    }
+
+    /// Adds a trailer field, sent after a streamed body. See `trailers`.
+    #[inline]
+    pub fn with_trailer(mut self, name: &str, val: &str) -> Self {
+        self.trailers.push((name.to_string(), val.to_string()));
+        self
+    }
+
     #[inline]
     pub fn with_status(mut self, code: StatusCode) -> Self {
         self.code = code.to_u16();
@@ -96,26 +120,140 @@ impl Response {
     }
 }
 
+/// How the body of a streamed response is delimited on the wire.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFraming {
+    /// The response gives its own Content-Length, so the body is written as it is.
+    Length,
+    /// The body is sent with the chunked transfer coding.
+    Chunked,
+    /// The body ends when the connection is closed. HTTP/1.0 clients do not know the chunked
+    /// transfer coding.
+    Close,
+    /// The response has no body, as it answers a HEAD request or its status allows none.
+    /// Anything streamed for it is dropped.
+    Empty,
+}
+
+/// What encoding a response depends on in the request it answers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exchange {
+    /// Version of the request, echoed in the status line.
+    pub version: HttpVersion,
+    /// Whether the connection stays open after the response, as far as the request and the
+    /// server are concerned.
+    pub keep_alive: bool,
+    /// Whether the request is a HEAD request, whose response is sent without its body.
+    pub head: bool,
+}
+
+impl Default for Exchange {
+    fn default() -> Exchange {
+        Exchange { version: HttpVersion::Http11, keep_alive: true, head: false }
+    }
+}
+
+/// Whether a response with this status code can have a body (RFC 7230 section 3.3.3).
+fn has_body(code: u16) -> bool {
+    !(code >= 100 && code < 200 || code == 204 || code == 304)
+}
+
+/// Whether a Connection header value lists `option`, e.g. `close` or `keep-alive`.
+pub fn has_connection_option(value: Option<&str>, option: &str) -> bool {
+    value.map_or(false, |value| value.split(',').any(|token| UniCase(token.trim()) == UniCase(option)))
+}
+
 // NOTE: May want to modify this to a different header write option...
 
-pub fn encode(res: &Response, buf: &mut Vec<u8>) {
-    let length = res.body.len();
+/// Writes a response with its body, or without it if it answers a HEAD request. Content-Length
+/// is filled in from the body unless the response sets it itself.
+///
+/// Returns whether the connection stays open after the response, which it does not if either
+/// `exchange` or the response says otherwise.
+pub fn encode(res: &Response, exchange: Exchange, buf: &mut Vec<u8>) -> bool {
+    let length = res.body.len().to_string();
+    let content_length = if res.header("content-length").is_none() && has_body(res.code) {
+        Some(("Content-Length", &length[..]))
+    } else {
+        None
+    };
+    let keep_alive = exchange.keep_alive && !has_connection_option(res.header("connection"), "close");
+    encode_head(res, exchange.version, keep_alive, content_length, buf);
+    if !exchange.head {
+        buf.extend_from_slice(&res.body[..]); //.as_bytes());
+    }
+    keep_alive
+}
+
+/// Writes the head of a response whose body is streamed after it. Returns how the body has to
+/// be framed: as it is if the response gives its length, otherwise chunked, or up to the end of
+/// the connection for HTTP/1.0, and not at all if the response can have no body. Also returns
+/// whether the connection stays open, as `encode` does.
+pub fn encode_streamed(res: &Response, exchange: Exchange, buf: &mut Vec<u8>) -> (BodyFraming, bool) {
+    let framing = if exchange.head || !has_body(res.code) {
+        BodyFraming::Empty
+    } else if res.header("content-length").is_some() {
+        BodyFraming::Length
+    } else if exchange.version == HttpVersion::Http10 {
+        BodyFraming::Close
+    } else {
+        BodyFraming::Chunked
+    };
+    let keep_alive = exchange.keep_alive && framing != BodyFraming::Close &&
+                     !has_connection_option(res.header("connection"), "close");
+    let transfer_encoding = if framing == BodyFraming::Chunked { Some(("Transfer-Encoding", "chunked")) } else { None };
+    encode_head(res, exchange.version, keep_alive, transfer_encoding, buf);
+    (framing, keep_alive)
+}
+
+/// Writes the status line and the headers, along with `extra` if given. A Connection header is
+/// added if the response has none and the version's default does not match `keep_alive`. If the
+/// connection is closing, the response cannot keep it alive: keep-alive is taken out of its
+/// Connection header, and close is sent.
+fn encode_head(res: &Response, version: HttpVersion, keep_alive: bool, extra: Option<(&str, &str)>, buf: &mut Vec<u8>) {
     let now = date::now();
+    let version = if version == HttpVersion::Http10 { "HTTP/1.0" } else { "HTTP/1.1" };
 
     write!(FastWrite(buf), "\
-        HTTP/1.1 {}\r\n\
+        {} {}\r\n\
         Date: {}\r\n\
-    ", res.status_message, now).unwrap();
+    ", version, res.status_message, now).unwrap();
 
+    let mut close_sent = false;
     for &(ref k, ref v) in &res.headers {
-        buf.extend_from_slice(k.as_bytes());
-        buf.extend_from_slice(b": ");
-        buf.extend_from_slice(v.as_bytes());
-        buf.extend_from_slice(b"\r\n");
+        if !keep_alive && UniCase(&k[..]) == UniCase("connection") {
+            let options: Vec<&str> = v.split(',')
+                                      .map(|option| option.trim())
+                                      .filter(|option| !option.is_empty() && UniCase(*option) != UniCase("keep-alive"))
+                                      .collect();
+            if !options.is_empty() {
+                close_sent = close_sent || options.iter().any(|option| UniCase(*option) == UniCase("close"));
+                encode_field(k, &options.join(", "), buf);
+            }
+            continue;
+        }
+        encode_field(k, v, buf);
+    }
+    if let Some((k, v)) = extra {
+        encode_field(k, v, buf);
+    }
+    if !keep_alive {
+        if !close_sent {
+            encode_field("Connection", "close", buf);
+        }
+    } else if res.header("connection").is_none() && version == "HTTP/1.0" {
+        encode_field("Connection", "keep-alive", buf);
     }
 
     buf.extend_from_slice(b"\r\n");
-    buf.extend_from_slice(&res.body[..]); //.as_bytes());
+}
+
+/// Writes a header or trailer field.
+pub fn encode_field(name: &str, value: &str, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(b": ");
+    buf.extend_from_slice(value.as_bytes());
+    buf.extend_from_slice(b"\r\n");
 }
 
 // TODO: impl fmt::Write for Vec<u8>
@@ -144,3 +282,111 @@ impl fmt::Display for StatusMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use HttpVersion;
+    use StatusCode;
+    use super::{encode, encode_streamed, BodyFraming, Exchange, Response};
+
+    fn head(buf: &[u8]) -> &str {
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        str::from_utf8(&buf[..end + 2]).unwrap()
+    }
+
+    #[test]
+    fn test_encode_content_length() {
+        let mut buf = Vec::new();
+        encode(&Response::new().with_body(b"hello".to_vec()), Exchange::default(), &mut buf);
+        assert!(head(&buf).contains("\r\nContent-Length: 5\r\n"));
+        assert!(buf.ends_with(b"\r\n\r\nhello"));
+
+        // A length set by the handler is left alone.
+        let mut buf = Vec::new();
+        encode(&Response::new().with_header("content-length", "5").with_body(b"hello".to_vec()), Exchange::default(), &mut buf);
+        assert_eq!(head(&buf).matches("ontent-").count(), 1);
+
+        let mut buf = Vec::new();
+        encode(&Response::new().with_status(StatusCode::NoContent), Exchange::default(), &mut buf);
+        assert!(!head(&buf).contains("Content-Length"));
+    }
+
+    #[test]
+    fn test_encode_streamed() {
+        let mut buf = Vec::new();
+        assert_eq!(encode_streamed(&Response::new(), Exchange::default(), &mut buf), (BodyFraming::Chunked, true));
+        assert!(head(&buf).contains("\r\nTransfer-Encoding: chunked\r\n"));
+
+        let mut buf = Vec::new();
+        let exchange = Exchange { version: HttpVersion::Http10, ..Exchange::default() };
+        assert_eq!(encode_streamed(&Response::new(), exchange, &mut buf), (BodyFraming::Close, false));
+        assert!(head(&buf).starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head(&buf).contains("\r\nConnection: close\r\n"));
+
+        let mut buf = Vec::new();
+        let res = Response::new().with_header("Content-Length", "10");
+        assert_eq!(encode_streamed(&res, Exchange::default(), &mut buf), (BodyFraming::Length, true));
+        assert!(!head(&buf).contains("Transfer-Encoding"));
+
+        let mut buf = Vec::new();
+        let res = Response::new().with_status(StatusCode::NotModified);
+        assert_eq!(encode_streamed(&res, Exchange::default(), &mut buf), (BodyFraming::Empty, true));
+        assert!(!head(&buf).contains("Transfer-Encoding"));
+    }
+
+    #[test]
+    fn test_encode_head_request() {
+        let exchange = Exchange { head: true, ..Exchange::default() };
+
+        let mut buf = Vec::new();
+        assert_eq!(encode_streamed(&Response::new(), exchange, &mut buf), (BodyFraming::Empty, true));
+        assert!(!head(&buf).contains("Transfer-Encoding"));
+
+        // The length is that of the body a GET would have got, but the body itself is left out.
+        let mut buf = Vec::new();
+        encode(&Response::new().with_body(b"hello".to_vec()), exchange, &mut buf);
+        assert!(head(&buf).contains("\r\nContent-Length: 5\r\n"));
+        assert!(buf.ends_with(b"\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_encode_persistence() {
+        let encoded = |res: &Response, version, keep_alive| {
+            let mut buf = Vec::new();
+            let keep_alive = encode(res, Exchange { version: version, keep_alive: keep_alive, head: false }, &mut buf);
+            (head(&buf).to_string(), keep_alive)
+        };
+
+        let (head, keep_alive) = encoded(&Response::new(), HttpVersion::Http11, true);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n") && !head.contains("Connection"));
+        assert!(keep_alive);
+
+        let (head, keep_alive) = encoded(&Response::new(), HttpVersion::Http11, false);
+        assert!(head.contains("\r\nConnection: close\r\n") && !keep_alive);
+
+        let (head, keep_alive) = encoded(&Response::new(), HttpVersion::Http10, true);
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n") && head.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(keep_alive);
+
+        let (head, keep_alive) = encoded(&Response::new(), HttpVersion::Http10, false);
+        assert!(head.contains("\r\nConnection: close\r\n") && !keep_alive);
+
+        // The response can close the connection too.
+        let (head, keep_alive) = encoded(&Response::new().with_header("Connection", "Close"), HttpVersion::Http11, true);
+        assert_eq!(head.matches("Connection").count(), 1);
+        assert!(!keep_alive);
+
+        // But it cannot keep open a connection that is closing.
+        for version in &[HttpVersion::Http10, HttpVersion::Http11] {
+            let (head, keep_alive) = encoded(&Response::new().with_header("Connection", "keep-alive"), *version, false);
+            assert!(head.contains("\r\nConnection: close\r\n") && !head.contains("keep-alive"));
+            assert_eq!(head.matches("Connection").count(), 1);
+            assert!(!keep_alive);
+        }
+        let (head, _) = encoded(&Response::new().with_header("Connection", "Keep-Alive, Upgrade"), HttpVersion::Http11, false);
+        assert!(head.contains("\r\nConnection: Upgrade\r\n") && head.contains("\r\nConnection: close\r\n"));
+        assert!(!head.contains("Keep-Alive"));
+    }
+}
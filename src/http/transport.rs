@@ -0,0 +1,499 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pipelined HTTP/1.x transport for tokio-proto's streaming pipeline. Request heads are decoded
+//! with an `HttpCodec` and handed out as soon as they are complete; the bodies follow as chunks
+//! while they arrive, so they never have to fit in memory. A request the codec turns away is
+//! answered (after the responses to the requests before it) instead of tearing the connection
+//! down without a word.
+//!
+//! Once the codec finds the connection closing, or a request has been rejected, nothing more is
+//! read, but the stream only ends when every response in flight has been written in full: the
+//! dispatcher stops waiting on response bodies as soon as the stream ends.
+
+use std::io::{self, Read, Write};
+
+use futures::{task, Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use tokio_core::io::{Codec, EasyBuf, Io};
+use tokio_proto::streaming::pipeline::{Frame, Transport};
+
+use super::{Chunk, HttpCodec, Request, Response};
+use super::chunked;
+use super::request::BodyDecoder;
+use super::response::BodyFraming;
+use Error;
+use Headers;
+
+const READ_CHUNK_SIZE: usize = 8192;
+
+pub struct HttpTransport<T> {
+    io: T,
+    codec: HttpCodec,
+    rd: EasyBuf,
+    wr: Vec<u8>,
+    eof: bool,
+    /// Body of the last request handed out, if it is still coming in.
+    body: Option<BodyDecoder>,
+    /// Number of requests handed out whose responses have not been written in full yet.
+    in_flight: usize,
+    /// Framing and trailers of the response body being streamed, if any.
+    response_body: Option<(BodyFraming, Headers)>,
+    /// Answer to a rejected request. It goes out once nothing is in flight, and nothing is read
+    /// after it.
+    rejection: Option<Response>,
+}
+
+impl<T: Io> HttpTransport<T> {
+    pub fn new(io: T, codec: HttpCodec) -> HttpTransport<T> {
+        HttpTransport {
+            io: io,
+            codec: codec,
+            rd: EasyBuf::new(),
+            wr: Vec::new(),
+            eof: false,
+            body: None,
+            in_flight: 0,
+            response_body: None,
+            rejection: None,
+        }
+    }
+
+    /// Reads whatever is available from the socket into the read buffer.
+    fn fill(&mut self) -> Poll<usize, io::Error> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        match self.io.read(&mut chunk) {
+            Ok(n) => {
+                self.rd.get_mut().extend_from_slice(&chunk[..n]);
+                Ok(Async::Ready(n))
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decodes what it can of the body being streamed. Yields the next chunk, or `None` at the
+    /// end of the body; `NotReady` if more has to be read first.
+    fn poll_body(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        let data = try_ready!(self.body.as_mut().unwrap().decode(&mut self.rd));
+        if data.is_none() {
+            self.body = None;
+        }
+        Ok(Async::Ready(data.map(Chunk::from)))
+    }
+
+    /// Notes that a response has been written in full. If it was the last one the stream was
+    /// held open for, the dispatcher has to poll the transport again to find out it ended.
+    fn response_done(&mut self) {
+        self.in_flight -= 1;
+        if self.in_flight == 0 && (self.rejection.is_some() || self.codec.is_closing()) {
+            task::park().unpark();
+        }
+    }
+}
+
+impl<T: Io> Stream for HttpTransport<T> {
+    type Item = Frame<Request, Chunk, Error>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Frame<Request, Chunk, Error>>, io::Error> {
+        loop {
+            if self.body.is_some() {
+                if let Async::Ready(chunk) = try!(self.poll_body()) {
+                    return Ok(Async::Ready(Some(Frame::Body { chunk: chunk })));
+                }
+                if self.eof {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a request body"));
+                }
+                if try_ready!(self.fill()) == 0 {
+                    self.eof = true;
+                }
+                continue;
+            }
+
+            if self.rejection.is_some() || self.codec.is_closing() {
+                if self.in_flight > 0 {
+                    return Ok(Async::NotReady);
+                }
+                return Ok(Async::Ready(None));
+            }
+
+            match self.codec.decode_head(&mut self.rd) {
+                Ok(Some((req, length))) => {
+                    self.in_flight += 1;
+                    self.body = BodyDecoder::new(length);
+                    return Ok(Async::Ready(Some(Frame::Message { message: req, body: self.body.is_some() })));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    match self.codec.reject(&e) {
+                        Some(res) => {
+                            self.rejection = Some(res);
+                            continue;
+                        },
+                        None => return Err(e),
+                    }
+                },
+            }
+
+            if self.eof {
+                return Ok(Async::Ready(None));
+            }
+            if try_ready!(self.fill()) == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<T: Io> Sink for HttpTransport<T> {
+    type SinkItem = Frame<Response, Chunk, Error>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, frame: Frame<Response, Chunk, Error>) -> StartSend<Frame<Response, Chunk, Error>, io::Error> {
+        match frame {
+            Frame::Message { message, body: true } => {
+                let framing = self.codec.encode_head(&message, &mut self.wr);
+                self.response_body = Some((framing, message.trailers));
+            },
+            Frame::Message { message, body: false } => {
+                try!(self.codec.encode(message, &mut self.wr));
+                self.response_done();
+            },
+            Frame::Body { chunk: Some(chunk) } => {
+                match self.response_body {
+                    Some((BodyFraming::Chunked, _)) => chunked::encode_chunk(&chunk, &mut self.wr),
+                    Some((BodyFraming::Empty, _)) => {},
+                    _ => self.wr.extend_from_slice(&chunk),
+                }
+            },
+            Frame::Body { chunk: None } => {
+                if let Some((BodyFraming::Chunked, trailers)) = self.response_body.take() {
+                    chunked::encode_last_chunk(&trailers, &mut self.wr);
+                }
+                self.response_done();
+            },
+            Frame::Error { error } => return Err(io::Error::new(io::ErrorKind::Other, error)),
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if self.in_flight == 0 {
+            if let Some(res) = self.rejection.take() {
+                self.codec.encode_rejection(&res, &mut self.wr);
+            }
+        }
+        while !self.wr.is_empty() {
+            match self.io.write(&self.wr) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write response")),
+                Ok(n) => { self.wr.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T: Io + 'static> Transport for HttpTransport<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    use futures::{self, Async, Future, Sink, Stream};
+    use slog;
+    use tokio_core::io::Io;
+    use tokio_proto::streaming::pipeline::Frame;
+
+    use http::{Chunk, HttpCodec, Response};
+    use Logger;
+    use super::HttpTransport;
+
+    struct Mock {
+        rd: Vec<u8>,
+        wr: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = (&self.rd[..]).read(buf).unwrap();
+            self.rd.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.wr.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    /// Keeps the lines logged through it.
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for Capture {
+        type Error = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKeyValueList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(format!("{}", record.msg()));
+            Ok(())
+        }
+    }
+
+    /// Answers the request in flight with `res`. Ending the stream wakes the task up, so this
+    /// has to run inside one.
+    fn respond(transport: HttpTransport<Mock>, res: Response) -> HttpTransport<Mock> {
+        let mut transport = transport;
+        futures::lazy(move || {
+            transport.start_send(Frame::Message { message: res, body: false }).unwrap();
+            transport.poll_complete().unwrap();
+            Ok::<_, ()>(transport)
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn test_rejection_follows_earlier_responses() {
+        let mut rd = b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        rd.extend_from_slice(b"GET /second HTTP/1.1\r\n");
+        for i in 0..10 {
+            rd.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes());
+        }
+        rd.extend_from_slice(b"\r\n");
+        let mut codec = HttpCodec::new(None, None, None);
+        codec.set_max_headers(8);
+        let mut transport = HttpTransport::new(Mock { rd: rd, wr: Vec::new() }, codec);
+
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { message, body: false })) => assert_eq!(message.path(), "/first"),
+            _ => panic!("expected the first request"),
+        }
+        // Nothing is read past the rejected request, and nothing goes out before the first
+        // request is answered.
+        assert!(transport.poll().unwrap().is_not_ready());
+        transport.poll_complete().unwrap();
+        assert!(transport.io.wr.is_empty());
+
+        let res = Response::new().with_header("Content-Length", "2").with_body(b"ok".to_vec());
+        let mut transport = respond(transport, res);
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected the stream to end at the rejected request"),
+        }
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        let second = wr.find("HTTP/1.1 431 Request Header Fields Too Large\r\n").unwrap();
+        assert!(wr.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(wr[..second].ends_with("ok"));
+        assert!(wr[second..].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_rejection_is_answered_once() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger { logger: slog::Logger::root(Capture(lines.clone()), None) };
+        let rd = b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n".to_vec();
+        let mut transport = HttpTransport::new(Mock { rd: rd, wr: Vec::new() }, HttpCodec::new(None, None, Some(logger)));
+
+        // The dispatcher may poll again after the rejection is written; the stream stays ended.
+        for _ in 0..3 {
+            match transport.poll().unwrap() {
+                Async::Ready(None) => {},
+                _ => panic!("expected the stream to end at the rejected request"),
+            }
+            transport.poll_complete().unwrap();
+        }
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(wr.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(wr.matches("HTTP/1.1 ").count(), 1);
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("rejected request"));
+    }
+
+    /// Polls `transport` for the next request and the whole of its body, checking that the body
+    /// comes in more than one chunk.
+    fn streamed_body(transport: &mut HttpTransport<Mock>, path: &str) -> Vec<u8> {
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { message, body: true })) => assert_eq!(message.path(), path),
+            _ => panic!("expected a request with a body"),
+        }
+        let mut body = Vec::new();
+        let mut chunks = 0;
+        loop {
+            match transport.poll().unwrap() {
+                Async::Ready(Some(Frame::Body { chunk: Some(chunk) })) => body.extend_from_slice(&chunk),
+                Async::Ready(Some(Frame::Body { chunk: None })) => break,
+                _ => panic!("expected the rest of the body"),
+            }
+            chunks += 1;
+        }
+        assert!(chunks > 1);
+        body
+    }
+
+    #[test]
+    fn test_streamed_bodies() {
+        let upload = vec![b'x'; 20000];
+        let mut rd = format!("PUT /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", upload.len()).into_bytes();
+        rd.extend_from_slice(&upload);
+        rd.extend_from_slice(b"POST /chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        for _ in 0..3 {
+            rd.extend_from_slice(b"2000\r\n");
+            rd.extend(vec![b'y'; 0x2000]);
+            rd.extend_from_slice(b"\r\n");
+        }
+        rd.extend_from_slice(b"0\r\n\r\nGET /last HTTP/1.1\r\n\r\n");
+        let mut transport = HttpTransport::new(Mock { rd: rd, wr: Vec::new() }, HttpCodec::new(None, None, None));
+
+        assert_eq!(streamed_body(&mut transport, "/upload"), upload);
+        assert_eq!(streamed_body(&mut transport, "/chunked"), vec![b'y'; 3 * 0x2000]);
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { message, body: false })) => assert_eq!(message.path(), "/last"),
+            _ => panic!("expected the last request"),
+        }
+    }
+
+    /// Answers the one request in `rd` with a streamed body and returns what was written.
+    fn stream_response(rd: &[u8], res: Response) -> (HttpTransport<Mock>, String) {
+        let mut transport = HttpTransport::new(Mock { rd: rd.to_vec(), wr: Vec::new() }, HttpCodec::new(None, None, None));
+        // Closing the connection wakes the task up, so this has to run inside one.
+        let transport = futures::lazy(move || {
+            transport.poll().unwrap();
+            transport.start_send(Frame::Message { message: res, body: true }).unwrap();
+            for chunk in &[&b"Wiki"[..], &b""[..], &b"pedia"[..]] {
+                transport.start_send(Frame::Body { chunk: Some(Chunk::from(chunk.to_vec())) }).unwrap();
+            }
+            transport.start_send(Frame::Body { chunk: None }).unwrap();
+            transport.poll_complete().unwrap();
+            Ok::<_, ()>(transport)
+        }).wait().unwrap();
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        (transport, wr)
+    }
+
+    #[test]
+    fn test_streamed_response() {
+        let res = Response::new().with_trailer("X-Sum", "42");
+        let (mut transport, wr) = stream_response(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n", res);
+        assert!(wr.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(wr.ends_with("\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Sum: 42\r\n\r\n"));
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { .. })) => {},
+            _ => panic!("expected the next request"),
+        }
+
+        // HTTP/1.0 clients get the body up to the end of the connection instead.
+        let res = Response::new().with_trailer("X-Sum", "42");
+        let (mut transport, wr) = stream_response(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n", res);
+        assert!(wr.contains("\r\nConnection: close\r\n"));
+        assert!(wr.ends_with("\r\n\r\nWikipedia"));
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected nothing more to be read"),
+        }
+
+        // The response to a HEAD request gets neither a body nor any framing for one.
+        let res = Response::new().with_trailer("X-Sum", "42");
+        let (_, wr) = stream_response(b"HEAD / HTTP/1.1\r\n\r\n", res);
+        assert!(wr.starts_with("HTTP/1.1 200 OK\r\n") && wr.ends_with("\r\n\r\n"));
+        assert!(!wr.contains("Transfer-Encoding") && !wr.contains("Wiki") && !wr.contains("X-Sum"));
+    }
+
+    /// Reads the one request `rd` holds before the connection closes, and returns what was
+    /// written in answer to it.
+    fn last_response(rd: &[u8], codec: HttpCodec, res: Response) -> String {
+        let mut transport = HttpTransport::new(Mock { rd: rd.to_vec(), wr: Vec::new() }, codec);
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { body: false, .. })) => {},
+            _ => panic!("expected a request"),
+        }
+        assert!(transport.poll().unwrap().is_not_ready());
+        let mut transport = respond(transport, res);
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected nothing more to be read"),
+        }
+        String::from_utf8(transport.io.wr.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_persistence() {
+        let next = b"GET /next HTTP/1.1\r\n\r\n";
+        let ok = || Response::new().with_body(b"ok".to_vec());
+
+        let mut rd = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n".to_vec();
+        rd.extend_from_slice(next);
+        let wr = last_response(&rd, HttpCodec::new(None, None, None), ok());
+        assert!(wr.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(wr.contains("\r\nConnection: close\r\n"));
+
+        let mut rd = b"GET / HTTP/1.0\r\n\r\n".to_vec();
+        rd.extend_from_slice(next);
+        let wr = last_response(&rd, HttpCodec::new(None, None, None), ok());
+        assert!(wr.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(wr.contains("\r\nConnection: close\r\n"));
+
+        // The service can end the connection too.
+        let mut transport = HttpTransport::new(Mock { rd: next.to_vec(), wr: Vec::new() }, HttpCodec::new(None, None, None));
+        transport.poll().unwrap();
+        let mut transport = respond(transport, ok().with_header("Connection", "close"));
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected nothing more to be read"),
+        }
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let mut rd = Vec::new();
+        for _ in 0..3 {
+            rd.extend_from_slice(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        }
+        let mut codec = HttpCodec::new(None, None, None);
+        codec.set_max_requests(Some(2));
+        let mut transport = HttpTransport::new(Mock { rd: rd, wr: Vec::new() }, codec);
+
+        transport.poll().unwrap();
+        transport.start_send(Frame::Message { message: Response::new(), body: false }).unwrap();
+        transport.poll_complete().unwrap();
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(wr.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(wr.contains("\r\nConnection: keep-alive\r\n"));
+
+        // The second request is the last one the connection serves.
+        transport.io.wr.clear();
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { .. })) => {},
+            _ => panic!("expected the second request"),
+        }
+        let mut transport = respond(transport, Response::new());
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(wr.contains("\r\nConnection: close\r\n"));
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected the third request to be left unread"),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! tokio `Codec` that splits a byte stream into HTTP/2 frames.
+//!
+//! The codec only deals with framing: it does not know about the connection preface, streams or
+//! settings other than the maximum frame size.
+
+use std::io;
+
+use tokio_core::io::{Codec, EasyBuf};
+
+use http2::frame::*;
+use http2::owned::OwnedFrame;
+use http2::settings::DEFAULT_MAX_FRAME_SIZE;
+
+use http2::Error;
+use http2::FRAME_HEADER_BYTES;
+
+/// Decodes `OwnedFrame`s from and encodes them into a byte stream.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameCodec {
+    max_frame_size: u32,
+}
+
+impl FrameCodec {
+    pub fn new() -> FrameCodec {
+        FrameCodec {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Largest frame payload accepted, i.e. the SETTINGS_MAX_FRAME_SIZE we advertised.
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    pub fn set_max_frame_size(&mut self, size: u32) {
+        self.max_frame_size = size;
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> FrameCodec {
+        FrameCodec::new()
+    }
+}
+
+impl Codec for FrameCodec {
+    type In = OwnedFrame;
+    type Out = OwnedFrame;
+
+    /// Returns the next frame once it was received completely. Frames larger than the maximum
+    /// frame size and malformed frames fail with `InvalidData`; the caller should treat them as
+    /// a connection error.
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<OwnedFrame>> {
+        let header = match FrameHeader::parse(buf.as_slice()) {
+            Ok(header) => header,
+            Err(Error::Short) => return Ok(None),
+            Err(e) => return Err(invalid_data(e)),
+        };
+
+        if header.length > self.max_frame_size {
+            return Err(invalid_data(Error::InvalidPayloadLength));
+        }
+
+        let frame_len = FRAME_HEADER_BYTES + header.length as usize;
+        if buf.len() < frame_len {
+            return Ok(None);
+        }
+
+        let bytes = buf.drain_to(frame_len);
+        let frame = try!(Frame::parse(header, &bytes.as_slice()[FRAME_HEADER_BYTES..]).map_err(invalid_data));
+        Ok(Some(OwnedFrame::from(frame)))
+    }
+
+    fn encode(&mut self, frame: OwnedFrame, buf: &mut Vec<u8>) -> io::Result<()> {
+        frame.encode(buf);
+        Ok(())
+    }
+}
+
+fn invalid_data(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP/2 frame: {:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use http2::flag::*;
+    use http2::owned::OwnedFrame;
+    use http2::StreamIdentifier;
+    use super::FrameCodec;
+
+    #[test]
+    fn test_decode() {
+        let first = OwnedFrame::data(StreamIdentifier(1), b"hello".to_vec()).padded(2);
+        let second = OwnedFrame::ping(7).ack();
+
+        let mut codec = FrameCodec::new();
+        let mut encoded = Vec::new();
+        codec.encode(first.clone(), &mut encoded).unwrap();
+        codec.encode(second.clone(), &mut encoded).unwrap();
+
+        // Feed the bytes one at a time to check that partial frames are left alone.
+        let mut buf = EasyBuf::new();
+        let mut frames = Vec::new();
+        for byte in encoded {
+            buf.get_mut().push(byte);
+            if let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![first, second]);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut codec = FrameCodec::new();
+        codec.set_max_frame_size(4);
+
+        let mut encoded = Vec::new();
+        OwnedFrame::data(StreamIdentifier(1), b"hello".to_vec()).encode(&mut encoded);
+        let mut buf = EasyBuf::from(encoded);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_malformed_frame() {
+        // A PING frame must carry 8 bytes.
+        let mut buf = EasyBuf::from(vec![0, 0, 4, 0x6, 0, 0, 0, 0, 0, 1, 2, 3, 4]);
+        assert_eq!(FrameCodec::new().decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let flag = Flag::end_stream();
+        let mut buf = EasyBuf::from(vec![0, 0, 0, 0x0, flag.bits(), 0, 0, 0, 1]);
+        assert_eq!(FrameCodec::new().decode(&mut buf).unwrap(),
+                   Some(OwnedFrame::data(StreamIdentifier(1), Vec::new()).end_stream()));
+    }
+}
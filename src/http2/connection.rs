@@ -0,0 +1,2080 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/2 server connection state machine.
+//!
+//! `Connection` does no I/O of its own: bytes read from the peer are handed to `recv`, complete
+//! requests are taken out with `poll_request` and every frame the connection wants to send is
+//! collected in an output buffer that the transport drains. This keeps the protocol logic
+//! testable without a socket.
+//!
+//! Request bodies are buffered until the request is complete. Each is limited to
+//! `max_body_size`, and once the bodies still being received hold more than `max_buffered_body`
+//! bytes, the connection window is only given back as those requests complete or their streams
+//! go away. Peers that send more concurrent bodies than that at once stall until one finishes.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use unicase::UniCase;
+
+use http2::kind::*;
+use http2::flag::*;
+use http2::frame::*;
+use http2::payload::*;
+use http2::stream::{Stream, StreamState};
+use http2::flow::{Window, RecvWindow, DEFAULT_WINDOW_SIZE};
+use http2::settings::Settings;
+use http2::keepalive::{Keepalive, KeepaliveAction};
+use http2::priority::PriorityTree;
+use http2::owned::OwnedFrame;
+use http2::header_block::{HeaderBlock, HeaderBlockAssembler, DEFAULT_MAX_HEADER_LIST_SIZE, header_list_size};
+
+use http2::Error;
+use http2::HttpError;
+use http2::SizeIncrement;
+use http2::StreamIdentifier;
+use http2::FRAME_HEADER_BYTES;
+
+use hpack::{Encoder, Decoder, DecoderLimits, IndexingPolicy};
+use http;
+use http::date;
+use http::HeaderList;
+use HttpVersion;
+use Method;
+use Request;
+use Response;
+use Router;
+use Logger;
+use LoggerLevel;
+use StatusCode;
+
+/// The client connection preface (RFC 7540 section 3.5).
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Default limit for SETTINGS_MAX_CONCURRENT_STREAMS.
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Largest stream identifier (RFC 7540 section 5.1.1).
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+
+/// Default limit for the body of a single request.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default limit for the request bodies a connection buffers before it stops giving back window.
+pub const DEFAULT_MAX_BUFFERED_BODY: usize = 16 * 1024 * 1024;
+
+/// Headers that are specific to an HTTP/1.x connection and must not be sent over HTTP/2
+/// (RFC 7540 section 8.1.2.2).
+const CONNECTION_HEADERS: &'static [&'static str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// Waiting for the client connection preface.
+    Preface,
+    /// The preface was received; the next frame must be the client's SETTINGS.
+    Settings,
+    Open,
+    /// GOAWAY was received. No new streams are accepted but those in flight are completed;
+    /// the connection closes once they are.
+    Draining,
+    /// GOAWAY was sent, or draining finished. Nothing more is read from the peer.
+    Closed,
+}
+
+/// A protocol violation and how far its consequences reach (RFC 7540 section 5.4).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Violation {
+    /// The connection cannot be used any further: GOAWAY is sent and the connection closed.
+    Connection(HttpError),
+    /// Only the stream is affected: it is reset with RST_STREAM.
+    Stream(StreamIdentifier, HttpError),
+}
+
+impl From<HttpError> for Violation {
+    fn from(error: HttpError) -> Violation {
+        Violation::Connection(error)
+    }
+}
+
+pub struct Connection {
+    state: ConnectionState,
+    streams: HashMap<u32, Stream>,
+    /// Decides the order in which streams send DATA.
+    priority: PriorityTree,
+    /// Joins HEADERS and CONTINUATION frames; also holds our SETTINGS_MAX_HEADER_LIST_SIZE.
+    header_blocks: HeaderBlockAssembler,
+    /// Complete requests waiting to be handed to the service.
+    ready: VecDeque<(StreamIdentifier, Request)>,
+    encoder: Encoder<'static>,
+    decoder: Decoder<'static>,
+    /// Highest stream identifier the peer has opened.
+    last_stream_id: StreamIdentifier,
+    /// Highest stream identifier reserved for a push.
+    last_push_id: StreamIdentifier,
+    /// Our settings, as announced in the server connection preface.
+    local_settings: Settings,
+    /// The peer's settings: the initial send window of new streams, the largest frame we may
+    /// send and whether we may push.
+    remote_settings: Settings,
+    /// Connection-level window for DATA we send.
+    send_window: Window,
+    /// Connection-level window for DATA we receive.
+    recv_window: RecvWindow,
+    /// Bytes of the request bodies still being received.
+    buffered_body: usize,
+    /// DATA received whose connection window was not given back yet.
+    unreleased: usize,
+    max_body_size: usize,
+    max_buffered_body: usize,
+    /// Encoded frames waiting to be written.
+    out: Vec<u8>,
+    keepalive: Option<Keepalive>,
+    /// Round-trip time measured by the last acknowledged PING.
+    rtt: Option<Duration>,
+    http_version: HttpVersion,
+    remote_addr: Option<SocketAddr>,
+    router: Option<Router>,
+    logger: Option<Logger>,
+}
+
+impl Connection {
+    /// Creates a server connection with the default settings, apart from a limit of
+    /// `DEFAULT_MAX_HEADER_LIST_SIZE` on request header lists and of
+    /// `DEFAULT_MAX_CONCURRENT_STREAMS` on open streams.
+    pub fn new(http_version: HttpVersion,
+               remote_addr: Option<SocketAddr>,
+               router: Option<Router>,
+               logger: Option<Logger>)
+               -> Connection {
+        let mut settings = Settings::default();
+        settings.max_header_list_size = Some(DEFAULT_MAX_HEADER_LIST_SIZE);
+        settings.max_concurrent_streams = Some(DEFAULT_MAX_CONCURRENT_STREAMS);
+        Connection::with_settings(settings, http_version, remote_addr, router, logger)
+    }
+
+    /// Creates a server connection announcing the given settings. The server's SETTINGS frame is
+    /// queued right away since it is part of the server connection preface.
+    ///
+    /// Requests whose header list exceeds `max_header_list_size` are answered with 431 (Request
+    /// Header Fields Too Large), and streams beyond `max_concurrent_streams` are refused. Header
+    /// blocks that decompress to more than four times the limit are treated as a COMPRESSION_ERROR
+    /// without being decoded in full.
+    pub fn with_settings(settings: Settings,
+                         http_version: HttpVersion,
+                         remote_addr: Option<SocketAddr>,
+                         router: Option<Router>,
+                         logger: Option<Logger>)
+                         -> Connection {
+        let max_header_list_size = settings.max_header_list_size.unwrap_or(u32::max_value());
+
+        let mut conn = Connection {
+            state: ConnectionState::Preface,
+            streams: HashMap::new(),
+            priority: PriorityTree::new(),
+            header_blocks: HeaderBlockAssembler::new(max_header_list_size),
+            ready: VecDeque::new(),
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+            last_stream_id: StreamIdentifier(0),
+            last_push_id: StreamIdentifier(0),
+            local_settings: settings,
+            remote_settings: Settings::default(),
+            send_window: Window::new(DEFAULT_WINDOW_SIZE),
+            recv_window: RecvWindow::new(DEFAULT_WINDOW_SIZE),
+            buffered_body: 0,
+            unreleased: 0,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_buffered_body: DEFAULT_MAX_BUFFERED_BODY,
+            out: Vec::new(),
+            keepalive: None,
+            rtt: None,
+            http_version: http_version,
+            remote_addr: remote_addr,
+            router: router,
+            logger: logger,
+        };
+
+        // Some slack above the limit, so that moderately large requests still get their 431.
+        let max_decoded_size = (max_header_list_size as usize).saturating_mul(4);
+        conn.decoder.set_limits(DecoderLimits {
+            max_header_list_size: max_decoded_size,
+            max_string_length: max_decoded_size,
+            max_headers: max_decoded_size / 32,
+        });
+        conn.write_frame(OwnedFrame::settings(&settings.changes()));
+        conn
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == ConnectionState::Closed
+    }
+
+    /// Sets how response header fields with the given name are compressed. See
+    /// `hpack::Encoder::set_indexing_policy` for the defaults.
+    pub fn set_indexing_policy(&mut self, name: &str, policy: IndexingPolicy) {
+        self.encoder.set_indexing_policy(name.to_lowercase().as_bytes(), policy);
+    }
+
+    /// Sets the largest request body, beyond which the request is answered with 413 (Payload
+    /// Too Large), and how much of the request bodies still being received the connection
+    /// buffers before it stops giving back window.
+    pub fn set_body_limits(&mut self, max_body_size: usize, max_buffered_body: usize) {
+        self.max_body_size = max_body_size;
+        self.max_buffered_body = max_buffered_body;
+    }
+
+    /// Sends a PING after `interval` without incoming frames and closes the connection if it is
+    /// not acknowledged within `timeout`. Call `poll_keepalive` to drive it.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.keepalive = Some(Keepalive::new(interval, timeout, Instant::now()));
+    }
+
+    /// Sends a keepalive PING or closes the connection with GOAWAY, depending on what is due at
+    /// `now`. Returns when to call it again, or `None` if keepalive is off or the connection is
+    /// closed.
+    pub fn poll_keepalive(&mut self, now: Instant) -> Option<Instant> {
+        loop {
+            if self.state == ConnectionState::Closed {
+                return None;
+            }
+            let action = match self.keepalive {
+                Some(ref mut keepalive) => keepalive.poll(now),
+                None => return None,
+            };
+            match action {
+                KeepaliveAction::Wait(deadline) => return Some(deadline),
+                KeepaliveAction::Ping(data) => self.write_frame(OwnedFrame::ping(data)),
+                KeepaliveAction::Expired => {
+                    self.log(LoggerLevel::Info, "PING timeout, closing connection");
+                    self.go_away(HttpError::NoError);
+                },
+            }
+        }
+    }
+
+    /// Round-trip time measured by the last acknowledged keepalive PING.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Whether there are streams that still expect a response.
+    pub fn has_open_streams(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    /// Processes as many complete frames as `buf` holds and removes them from it. Partial frames
+    /// are left in the buffer until more data arrives.
+    pub fn recv(&mut self, buf: &mut Vec<u8>) {
+        let mut pos = 0;
+        let now = Instant::now();
+
+        loop {
+            if self.state == ConnectionState::Closed {
+                // Anything after GOAWAY is discarded.
+                pos = buf.len();
+                break;
+            }
+
+            let rest = &buf[pos..];
+
+            if self.state == ConnectionState::Preface {
+                if rest.len() < PREFACE.len() {
+                    if !PREFACE.starts_with(rest) {
+                        self.go_away(HttpError::ProtocolError);
+                    }
+                    break;
+                }
+                if &rest[..PREFACE.len()] != PREFACE {
+                    self.go_away(HttpError::ProtocolError);
+                    break;
+                }
+                pos += PREFACE.len();
+                self.state = ConnectionState::Settings;
+                continue;
+            }
+
+            let header = match FrameHeader::parse(rest) {
+                Ok(header) => header,
+                Err(Error::Short) => break,
+                Err(_) => {
+                    self.go_away(HttpError::ProtocolError);
+                    break;
+                },
+            };
+
+            if header.length > self.local_settings.max_frame_size {
+                self.go_away(HttpError::FrameSizeError);
+                break;
+            }
+
+            let frame_len = FRAME_HEADER_BYTES + header.length as usize;
+            if rest.len() < frame_len {
+                break;
+            }
+
+            if let Some(ref mut keepalive) = self.keepalive {
+                keepalive.recv(now);
+            }
+
+            let result = match Frame::parse(header, &rest[FRAME_HEADER_BYTES..frame_len]) {
+                Ok(frame) => self.recv_frame(frame),
+                // A PRIORITY frame of the wrong size only affects its stream (section 6.3).
+                Err(Error::InvalidPayloadLength) if header.kind == Kind::Priority && header.id.0 != 0 => {
+                    Err(Violation::Stream(header.id, HttpError::FrameSizeError))
+                },
+                Err(Error::PartialSettingLength) |
+                Err(Error::InvalidPayloadLength) => Err(HttpError::FrameSizeError.into()),
+                Err(_) => Err(HttpError::ProtocolError.into()),
+            };
+
+            match result {
+                Ok(()) => {},
+                Err(Violation::Stream(id, error)) => self.reset_stream(id, error),
+                Err(Violation::Connection(error)) => {
+                    self.go_away(error);
+                    break;
+                },
+            }
+
+            pos += frame_len;
+        }
+
+        buf.drain(..pos);
+    }
+
+    /// Takes over a connection that was upgraded from HTTP/1.1 (RFC 7540 section 3.2).
+    ///
+    /// `settings` is the decoded HTTP2-Settings payload of the upgrade request; the 101 response
+    /// acknowledges it, so no SETTINGS ACK is sent. The upgrade request itself is served as
+    /// stream 1, which the client has already half-closed. The client still sends the connection
+    /// preface after the 101 response.
+    pub fn upgrade(&mut self, settings: &[u8], request: Request) -> Result<(), HttpError> {
+        if settings.len() % SETTING_BYTES != 0 {
+            return Err(HttpError::ProtocolError);
+        }
+        try!(self.apply_settings(settings));
+
+        let id = StreamIdentifier(1);
+        let mut stream = Stream::new(id, self.remote_settings.initial_window_size, self.local_settings.initial_window_size);
+        stream.state = StreamState::HalfClosedRemote;
+        stream.request = Some(request.clone());
+        self.streams.insert(id.0, stream);
+        try!(self.priority.insert(id, None));
+        self.last_stream_id = id;
+        self.ready.push_back((id, request));
+        Ok(())
+    }
+
+    /// Whether `poll_request` has a request to hand out.
+    pub fn has_pending_requests(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Takes the next complete request, along with the stream it arrived on.
+    pub fn poll_request(&mut self) -> Option<(StreamIdentifier, Request)> {
+        let rtt = self.rtt;
+        self.ready.pop_front().map(|(id, mut request)| {
+            request.set_rtt(rtt);
+            (id, request)
+        })
+    }
+
+    /// Sends `res` on the given stream as a HEADERS frame followed by DATA frames. The body is
+    /// queued on the stream and sent as far as the flow control windows allow; the rest goes out
+    /// as the peer sends WINDOW_UPDATE.
+    ///
+    /// Resources promised by the response or by the matching route are pushed first: the
+    /// PUSH_PROMISE frames go out before the response so the client does not request them
+    /// itself, and the synthetic requests are queued for `poll_request`.
+    ///
+    /// The response to a HEAD request ends with its HEADERS frame. Its headers, Content-Length
+    /// included, are those a GET would get, but the body is dropped.
+    ///
+    /// Responses for streams that no longer exist (e.g. reset by the peer) are dropped.
+    pub fn send_response(&mut self, id: StreamIdentifier, res: Response) {
+        let request = match self.streams.get(&id.0) {
+            Some(stream) => stream.request.clone(),
+            None => return,
+        };
+
+        if let Some(ref request) = request {
+            let mut pushes = res.pushes.clone();
+            if let Some(ref router) = self.router {
+                pushes.extend(router.find_pushes(request.method(), request.path()));
+            }
+            for path in pushes {
+                self.push(id, request, &path);
+            }
+        }
+
+        let mut block: Vec<u8> = Vec::new();
+        {
+            let status = res.code.to_string();
+            let now = date::now().to_string();
+            let mut headers: Vec<(Vec<u8>, Vec<u8>)> = vec![
+                (b":status".to_vec(), status.into_bytes()),
+                (b"date".to_vec(), now.into_bytes()),
+            ];
+
+            for &(ref name, ref value) in &res.headers {
+                if CONNECTION_HEADERS.iter().any(|h| UniCase(&h[..]) == UniCase(&name[..])) {
+                    continue;
+                }
+                // Header field names must be lowercase in HTTP/2.
+                headers.push((name.to_lowercase().into_bytes(), value.as_bytes().to_vec()));
+            }
+
+            self.encoder.encode_into(headers.iter().map(|h| (&h.0[..], &h.1[..])), &mut block).unwrap();
+        }
+
+        let head = request.as_ref().map_or(false, |request| request.method() == Method::Head);
+        let end_stream = head || res.body.is_empty();
+        self.write_header_block(id, None, &block, end_stream);
+
+        if let (Some(ref logger), Some(ref request)) = (self.logger.clone(), request) {
+            http::access_log(logger, self.remote_addr, request, &res);
+        }
+
+        let closed = match self.streams.get_mut(&id.0) {
+            Some(stream) => {
+                stream.send_headers();
+                if end_stream {
+                    stream.send_end_stream();
+                } else {
+                    stream.send_buf = res.body;
+                    stream.send_eos = true;
+                }
+                stream.is_closed()
+            },
+            None => false,
+        };
+        if closed {
+            self.remove_stream(id);
+        }
+
+        self.flush_data();
+    }
+
+    /// Returns the bytes waiting to be written to the peer.
+    pub fn pending_output(&self) -> &[u8] {
+        &self.out
+    }
+
+    /// Marks `amt` bytes of the pending output as written.
+    pub fn consume_output(&mut self, amt: usize) {
+        self.out.drain(..amt);
+    }
+
+    /// Sends GOAWAY with the given error and closes the connection. The frame carries the
+    /// highest stream the peer opened, so it knows which of its requests may have been
+    /// processed and which can be retried on a new connection.
+    pub fn go_away(&mut self, error: HttpError) {
+        if self.state == ConnectionState::Closed {
+            return;
+        }
+        let last = self.last_stream_id;
+        self.write_frame(OwnedFrame::go_away(last, error.into(), Vec::new()));
+        self.state = ConnectionState::Closed;
+    }
+
+    fn recv_frame(&mut self, frame: Frame) -> Result<(), Violation> {
+        if self.state == ConnectionState::Settings && frame.header.kind != Kind::Settings {
+            return Err(HttpError::ProtocolError.into());
+        }
+        // Nothing may come between a header block's frames.
+        try!(self.header_blocks.check(&frame.header));
+
+        match frame.payload {
+            Payload::Settings(settings) => self.recv_settings(frame.header, settings),
+            Payload::Headers { .. } |
+            Payload::Continuation(_) => {
+                match try!(self.header_blocks.recv(frame)) {
+                    Some(block) => self.recv_headers(block),
+                    None => Ok(()),
+                }
+            },
+            Payload::Data { data } => self.recv_data(frame.header, data),
+            Payload::Reset(_) => self.recv_reset(frame.header),
+            Payload::GoAway { last, .. } => self.recv_go_away(frame.header, last),
+            // Clients cannot push.
+            Payload::PushPromise { .. } => Err(HttpError::ProtocolError.into()),
+            Payload::WindowUpdate(increment) => self.recv_window_update(frame.header, increment),
+            Payload::Priority(priority) => {
+                let id = frame.header.id;
+                if id.0 == 0 {
+                    return Err(HttpError::ProtocolError.into());
+                }
+                // PRIORITY may arrive in any stream state. Closed streams are no longer in the
+                // tree and get a fresh node, which the tree drops again once it holds too many
+                // for streams that are not open. An idle stream cannot be reset, so an error
+                // there takes the connection down. A new order does not let any more data out,
+                // so nothing is flushed until the windows or the queued data change.
+                let idle = self.is_idle(id);
+                try!(self.priority.reprioritize(id, priority).map_err(|e| {
+                    if idle { Violation::Connection(e) } else { Violation::Stream(id, e) }
+                }));
+                Ok(())
+            },
+            Payload::Ping(data) => self.recv_ping(frame.header, data),
+            Payload::Unregistered(_) => Ok(()),
+        }
+    }
+
+    fn recv_ping(&mut self, header: FrameHeader, data: u64) -> Result<(), Violation> {
+        if header.id.0 != 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        if !header.flag.contains(Flag::ack()) {
+            self.write_frame(OwnedFrame::ping(data).ack());
+            return Ok(());
+        }
+
+        let rtt = match self.keepalive {
+            Some(ref mut keepalive) => keepalive.ack(data, Instant::now()),
+            None => None,
+        };
+        if let Some(rtt) = rtt {
+            self.rtt = Some(rtt);
+            let millis = rtt.as_secs() * 1000 + (rtt.subsec_nanos() / 1000000) as u64;
+            self.log(LoggerLevel::Debug, &format!("PING round-trip time {} ms", millis));
+        }
+        Ok(())
+    }
+
+    fn recv_settings(&mut self, header: FrameHeader, settings: &[u8]) -> Result<(), Violation> {
+        if header.id.0 != 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        if header.flag.contains(Flag::ack()) {
+            if header.length != 0 {
+                return Err(HttpError::FrameSizeError.into());
+            }
+            return Ok(());
+        }
+
+        try!(self.apply_settings(settings));
+
+        self.write_frame(OwnedFrame::settings_ack());
+
+        if self.state == ConnectionState::Settings {
+            self.state = ConnectionState::Open;
+        }
+
+        // A larger initial window may unblock queued data.
+        self.flush_data();
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, settings: &[u8]) -> Result<(), HttpError> {
+        let initial_window_size = self.remote_settings.initial_window_size;
+        try!(self.remote_settings.apply_all(settings));
+
+        // A new initial window size applies to every open stream, not just new ones.
+        let delta = self.remote_settings.initial_window_size as i64 - initial_window_size as i64;
+        if delta != 0 {
+            for stream in self.streams.values_mut() {
+                try!(stream.send_window.adjust(delta));
+            }
+        }
+
+        // The size update goes out with the next header block.
+        self.encoder.set_max_allowed_table_size(self.remote_settings.header_table_size as usize);
+        Ok(())
+    }
+
+    fn recv_headers(&mut self, block: HeaderBlock) -> Result<(), Violation> {
+        let id = block.header.id;
+        if id.0 == 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        // The block has to be decoded even if the stream is refused, to keep the HPACK context
+        // in sync with the peer.
+        let headers = try!(self.decoder.decode_borrowed(&block.block).map_err(|_| HttpError::CompressionError));
+        let end_stream = block.end_stream();
+        let priority = block.priority;
+        let too_large = header_list_size(&headers) > self.header_blocks.max_size() as usize;
+
+        if self.streams.contains_key(&id.0) {
+            // Trailers: the regular fields are merged into the request's headers.
+            let stream = self.streams.get_mut(&id.0).unwrap();
+            try!(stream.recv_headers(end_stream).map_err(|e| Violation::Stream(id, e)));
+            if !too_large {
+                stream.headers.extend(headers.into_iter().filter(|h| h.0.first() != Some(&b':')));
+            }
+            if let Some(priority) = priority {
+                try!(self.priority.reprioritize(id, priority).map_err(|e| Violation::Stream(id, e)));
+            }
+        } else {
+            if id.0 % 2 == 0 || id.0 <= self.last_stream_id.0 {
+                return Err(HttpError::ProtocolError.into());
+            }
+            self.last_stream_id = id;
+            if self.state != ConnectionState::Open {
+                // Draining: the request was not processed and can be retried elsewhere.
+                return Err(Violation::Stream(id, HttpError::RefusedStream));
+            }
+            if let Some(max) = self.local_settings.max_concurrent_streams {
+                let open = self.streams.keys().filter(|&&id| id % 2 == 1).count();
+                if open >= max as usize {
+                    return Err(Violation::Stream(id, HttpError::RefusedStream));
+                }
+            }
+
+            let mut stream = Stream::new(id, self.remote_settings.initial_window_size, self.local_settings.initial_window_size);
+            try!(stream.recv_headers(end_stream));
+            if !too_large {
+                stream.headers = headers.into_iter().collect();
+            }
+            try!(self.priority.insert(id, priority).map_err(|e| Violation::Stream(id, e)));
+            self.streams.insert(id.0, stream);
+        }
+
+        if too_large {
+            self.reject(id, StatusCode::RequestHeaderFieldsTooLarge);
+            return Ok(());
+        }
+
+        if end_stream {
+            try!(self.dispatch(id));
+        }
+        Ok(())
+    }
+
+    /// Answers a request that exceeds one of our limits (431 for a header list larger than our
+    /// SETTINGS_MAX_HEADER_LIST_SIZE, 413 for a body larger than `max_body_size`) instead of
+    /// handing it to the service.
+    fn reject(&mut self, id: StreamIdentifier, status: StatusCode) {
+        let remote_closed = self.streams.get(&id.0).map(|s| s.is_remote_closed()).unwrap_or(true);
+        let res = Response::new().with_header("Content-Length", "0")
+                                 .with_status(status);
+        self.send_response(id, res);
+
+        if !remote_closed {
+            // The rest of the request is of no use, so ask the client to stop sending it.
+            self.reset_stream(id, HttpError::NoError);
+        }
+    }
+
+    fn recv_data(&mut self, header: FrameHeader, data: &[u8]) -> Result<(), Violation> {
+        let id = header.id;
+        if id.0 == 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        // The whole payload counts against the windows, padding included.
+        let len = header.length;
+        try!(self.recv_window.recv(len));
+
+        let end_stream = header.flag.contains(Flag::end_stream());
+        let idle = self.is_idle(id);
+        let max_body_size = self.max_body_size;
+        let mut buffered = 0;
+        let stream_increment = match self.streams.get_mut(&id.0) {
+            Some(stream) => {
+                stream.recv_data(end_stream)
+                      .and_then(|_| stream.recv_window.recv(len))
+                      .map(|_| {
+                          if stream.body.len() + data.len() > max_body_size {
+                              return None;
+                          }
+                          stream.body.extend_from_slice(data);
+                          buffered = data.len();
+
+                          // A stream window only holds back a single body, so it is given back
+                          // as soon as the data is buffered; what the connection buffers is
+                          // bounded by the connection window. No update is needed once the
+                          // peer is done.
+                          if stream.is_remote_closed() { None } else { stream.recv_window.release(len) }
+                      })
+            },
+            None if idle => return Err(HttpError::ProtocolError.into()),
+            None => Err(HttpError::StreamClosed),
+        };
+
+        // Data that is discarded because of a stream error still counts against the connection
+        // window, and so is released all the same.
+        self.buffered_body += buffered;
+        self.unreleased += len as usize;
+        self.release_recv_window();
+        if let Some(increment) = try!(stream_increment.map_err(|e| Violation::Stream(id, e))) {
+            self.write_frame(OwnedFrame::window_update(id, increment));
+        }
+
+        if buffered < data.len() {
+            self.reject(id, StatusCode::PayloadTooLarge);
+            return Ok(());
+        }
+        if end_stream {
+            try!(self.dispatch(id));
+        }
+        Ok(())
+    }
+
+    /// Gives back the connection window for the DATA received, except for what the bodies still
+    /// being received hold beyond `max_buffered_body`. That part is given back as those bodies
+    /// are handed to the service or dropped.
+    fn release_recv_window(&mut self) {
+        let held = cmp::min(self.unreleased, self.buffered_body.saturating_sub(self.max_buffered_body));
+        let amt = self.unreleased - held;
+        self.unreleased = held;
+        if let Some(increment) = self.recv_window.release(amt as u32) {
+            self.write_frame(OwnedFrame::window_update(StreamIdentifier(0), increment));
+        }
+    }
+
+    fn recv_window_update(&mut self, header: FrameHeader, increment: SizeIncrement) -> Result<(), Violation> {
+        let id = header.id;
+        if id.0 == 0 {
+            if increment.0 == 0 {
+                return Err(HttpError::ProtocolError.into());
+            }
+            try!(self.send_window.increase(increment.0));
+        } else {
+            if increment.0 == 0 {
+                return Err(Violation::Stream(id, HttpError::ProtocolError));
+            }
+            let idle = self.is_idle(id);
+            match self.streams.get_mut(&id.0) {
+                Some(stream) => {
+                    try!(stream.send_window.increase(increment.0).map_err(|e| Violation::Stream(id, e)));
+                },
+                // WINDOW_UPDATE on an idle stream.
+                None if idle => return Err(HttpError::ProtocolError.into()),
+                // The stream is already closed; the update can arrive late.
+                None => {},
+            }
+        }
+
+        self.flush_data();
+        Ok(())
+    }
+
+    /// Sends queued response data as far as the connection and stream windows allow, split into
+    /// frames no larger than the peer's SETTINGS_MAX_FRAME_SIZE. Streams take turns frame by
+    /// frame in the order the priority tree gives.
+    fn flush_data(&mut self) {
+        loop {
+            let next = {
+                let streams = &self.streams;
+                let available = self.send_window.available();
+                self.priority.next(|id| streams.get(&id.0).map(|s| s.can_send(available)).unwrap_or(false))
+            };
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+
+            let (chunk, eos, closed) = {
+                let stream = self.streams.get_mut(&id.0).unwrap();
+                let len = *[self.send_window.available() as usize,
+                            stream.send_window.available() as usize,
+                            self.remote_settings.max_frame_size as usize,
+                            stream.send_buf.len()].iter().min().unwrap();
+
+                self.send_window.send(len as u32);
+                stream.send_window.send(len as u32);
+                let chunk: Vec<u8> = stream.send_buf.drain(..len).collect();
+
+                let eos = stream.send_eos && stream.send_buf.is_empty();
+                if eos {
+                    stream.send_eos = false;
+                    stream.send_end_stream();
+                }
+                (chunk, eos, stream.is_closed())
+            };
+
+            self.priority.charge(id, chunk.len());
+            let frame = OwnedFrame::data(id, chunk);
+            self.write_frame(if eos { frame.end_stream() } else { frame });
+
+            if closed {
+                self.remove_stream(id);
+            }
+        }
+    }
+
+    /// Reserves a stream for the resource at `path`, promises it on the `associated` stream and
+    /// queues a GET request for it as if the client had sent one.
+    fn push(&mut self, associated: StreamIdentifier, request: &Request, path: &str) {
+        // Pushes are only promised on streams the client opened, and never after GOAWAY.
+        if !self.remote_settings.enable_push || self.state != ConnectionState::Open || associated.0 % 2 == 0 {
+            return;
+        }
+        // The client's SETTINGS_MAX_CONCURRENT_STREAMS limits the pushes in progress (section
+        // 8.2.2), and once the stream identifiers run out nothing more can be pushed.
+        if let Some(max) = self.remote_settings.max_concurrent_streams {
+            let pushed = self.streams.keys().filter(|&&id| id % 2 == 0).count();
+            if pushed >= max as usize {
+                return;
+            }
+        }
+        if self.last_push_id.0 + 2 > MAX_STREAM_ID {
+            return;
+        }
+
+        let headers: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b":method".to_vec(), b"GET".to_vec()),
+            (b":scheme".to_vec(), request.scheme().as_bytes().to_vec()),
+            (b":authority".to_vec(), request.host().as_bytes().to_vec()),
+            (b":path".to_vec(), path.as_bytes().to_vec()),
+        ];
+
+        let pushed = match Request::from_header_list(headers.iter().map(|h| (&h.0, &h.1)).collect(),
+                                                      Vec::new(),
+                                                      self.http_version,
+                                                      self.remote_addr,
+                                                      self.router.clone(),
+                                                      self.logger.clone()) {
+            Ok(pushed) => pushed,
+            Err(_) => return,
+        };
+
+        let mut block: Vec<u8> = Vec::new();
+        self.encoder.encode_into(headers.iter().map(|h| (&h.0[..], &h.1[..])), &mut block).unwrap();
+
+        let id = StreamIdentifier(self.last_push_id.0 + 2);
+        self.last_push_id = id;
+        self.write_header_block(associated, Some(id), &block, false);
+
+        let mut stream = Stream::new(id, self.remote_settings.initial_window_size, self.local_settings.initial_window_size);
+        stream.state = StreamState::ReservedLocal;
+        stream.request = Some(pushed.clone());
+        self.streams.insert(id.0, stream);
+        // A pushed stream depends on the stream it was promised on (section 5.3.5).
+        self.priority.insert(id, Some(Priority::new(false, associated, 15))).unwrap();
+        self.ready.push_back((id, pushed));
+    }
+
+    /// Whether the stream was never opened, neither by the peer nor by a push.
+    fn is_idle(&self, id: StreamIdentifier) -> bool {
+        if id.0 % 2 == 0 {
+            id.0 > self.last_push_id.0
+        } else {
+            id.0 > self.last_stream_id.0
+        }
+    }
+
+    /// Resets the stream after a stream error.
+    fn reset_stream(&mut self, id: StreamIdentifier, error: HttpError) {
+        self.write_frame(OwnedFrame::reset(id, error.into()));
+        if let Some(stream) = self.streams.get_mut(&id.0) {
+            stream.reset();
+        }
+        self.remove_stream(id);
+    }
+
+    /// Forgets a closed stream, along with its request if it was not handed out yet.
+    fn remove_stream(&mut self, id: StreamIdentifier) {
+        if let Some(stream) = self.streams.remove(&id.0) {
+            self.buffered_body -= stream.body.len();
+            self.release_recv_window();
+        }
+        self.priority.remove(id);
+        self.ready.retain(|&(ready_id, _)| ready_id != id);
+
+        if self.state == ConnectionState::Draining && self.streams.is_empty() {
+            self.state = ConnectionState::Closed;
+        }
+    }
+
+    fn recv_reset(&mut self, header: FrameHeader) -> Result<(), Violation> {
+        let id = header.id;
+        if id.0 == 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+        if self.is_idle(id) {
+            // RST_STREAM on an idle stream.
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        // A request that was reset before it was served must not reach the service.
+        if let Some(stream) = self.streams.get_mut(&id.0) {
+            stream.reset();
+        }
+        self.remove_stream(id);
+        Ok(())
+    }
+
+    /// The peer is going away: streams it opened are still answered, but nothing new starts.
+    fn recv_go_away(&mut self, header: FrameHeader, last: StreamIdentifier) -> Result<(), Violation> {
+        if header.id.0 != 0 {
+            return Err(HttpError::ProtocolError.into());
+        }
+
+        // `last` refers to the streams we initiated: pushes above it will never be processed.
+        let cancelled: Vec<u32> = self.streams.keys().cloned().filter(|&id| id % 2 == 0 && id > last.0).collect();
+        for id in cancelled {
+            self.remove_stream(StreamIdentifier(id));
+        }
+
+        self.state = if self.streams.is_empty() {
+            ConnectionState::Closed
+        } else {
+            ConnectionState::Draining
+        };
+        Ok(())
+    }
+
+    /// The request on the stream is complete: turns it into a `Request` for the service.
+    fn dispatch(&mut self, id: StreamIdentifier) -> Result<(), Violation> {
+        let (headers, body) = match self.streams.get_mut(&id.0) {
+            Some(stream) => (mem::replace(&mut stream.headers, HeaderList::new()),
+                             mem::replace(&mut stream.body, Vec::new())),
+            None => return Ok(()),
+        };
+        self.buffered_body -= body.len();
+        self.release_recv_window();
+
+        let request = try!(Request::from_header_list(headers,
+                                                     body,
+                                                     self.http_version,
+                                                     self.remote_addr,
+                                                     self.router.clone(),
+                                                     self.logger.clone())
+                               .map_err(|_| Violation::Stream(id, HttpError::ProtocolError)));
+
+        if let Some(stream) = self.streams.get_mut(&id.0) {
+            stream.request = Some(request.clone());
+        }
+        self.ready.push_back((id, request));
+        Ok(())
+    }
+
+    /// Writes a line to the connection's log, prefixed with the peer's address.
+    fn log(&self, level: LoggerLevel, line: &str) {
+        if let Some(ref logger) = self.logger {
+            let remote_addr = self.remote_addr.map(|addr| addr.to_string()).unwrap_or("-".to_string());
+            logger.write(level, format!("{} - {}", remote_addr, line));
+        }
+    }
+
+    /// Writes a header block as a HEADERS frame, or a PUSH_PROMISE frame if `promised` is given,
+    /// followed by CONTINUATION frames if the block does not fit in a single frame.
+    fn write_header_block(&mut self,
+                          id: StreamIdentifier,
+                          promised: Option<StreamIdentifier>,
+                          block: &[u8],
+                          end_stream: bool) {
+        let max = self.remote_settings.max_frame_size as usize;
+        // The promised stream identifier takes up part of the first frame.
+        let first_max = if promised.is_some() { max - 4 } else { max };
+        let (first, rest) = block.split_at(if block.len() < first_max { block.len() } else { first_max });
+        let mut chunks = rest.chunks(max).peekable();
+
+        let mut frame = match promised {
+            Some(promised) => OwnedFrame::push_promise(id, promised, first.to_vec()),
+            None => OwnedFrame::headers(id, first.to_vec()),
+        };
+        if end_stream {
+            frame = frame.end_stream();
+        }
+        if chunks.peek().is_none() {
+            frame = frame.end_headers();
+        }
+        self.write_frame(frame);
+
+        while let Some(chunk) = chunks.next() {
+            let frame = OwnedFrame::continuation(id, chunk.to_vec());
+            self.write_frame(if chunks.peek().is_none() { frame.end_headers() } else { frame });
+        }
+    }
+
+    fn write_frame(&mut self, frame: OwnedFrame) {
+        frame.encode(&mut self.out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::kind::*;
+    use http2::flag::*;
+    use http2::frame::*;
+    use http2::payload::*;
+
+    use http2::ErrorCode;
+    use http2::HttpError;
+    use http2::SizeIncrement;
+    use http2::StreamIdentifier;
+    use http2::FRAME_HEADER_BYTES;
+    use http2::flow::MAX_WINDOW_SIZE;
+    use http2::settings::Settings;
+    use http2::header_block::DEFAULT_MAX_HEADER_LIST_SIZE;
+
+    use hpack::{Encoder, Decoder};
+    use HttpVersion;
+    use Method;
+    use Request;
+    use Response;
+    use Route;
+    use RouterBuilder;
+    use super::{Connection, ConnectionState, MAX_STREAM_ID, PREFACE};
+
+    fn frame(buf: &mut Vec<u8>, payload: Payload, flag: Flag, id: u32) {
+        let frame = Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: payload.kind(),
+                flag: flag,
+                id: StreamIdentifier(id),
+            },
+            payload: payload,
+        };
+        let start = buf.len();
+        buf.resize(start + frame.encoded_len(), 0);
+        frame.encode(&mut buf[start..]);
+    }
+
+    // Splits the connection's output into (header, payload) pairs.
+    fn output(conn: &mut Connection) -> Vec<(FrameHeader, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let len = {
+            let mut buf = conn.pending_output();
+            while !buf.is_empty() {
+                let header = FrameHeader::parse(buf).unwrap();
+                let end = FRAME_HEADER_BYTES + header.length as usize;
+                frames.push((header, buf[FRAME_HEADER_BYTES..end].to_vec()));
+                buf = &buf[end..];
+            }
+            conn.pending_output().len()
+        };
+        conn.consume_output(len);
+        frames
+    }
+
+    fn handshake() -> Connection {
+        handshake_with(Connection::new(HttpVersion::H2c, None, None, None))
+    }
+
+    fn handshake_with(mut conn: Connection) -> Connection {
+        let mut buf = PREFACE.to_vec();
+        frame(&mut buf, Payload::Settings(&[]), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert!(buf.is_empty());
+        assert_eq!(conn.state(), ConnectionState::Open);
+        output(&mut conn);
+        conn
+    }
+
+    fn get(encoder: &mut Encoder, path: &str) -> Vec<u8> {
+        let headers: Vec<(&[u8], &[u8])> = vec![
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":authority", b"localhost"),
+            (b":path", path.as_bytes()),
+            (b"user-agent", b"test"),
+        ];
+        encoder.encode(headers)
+    }
+
+    fn assert_goaway(conn: &mut Connection, error: HttpError) {
+        let frames = output(conn);
+        let &(header, ref payload) = frames.last().unwrap();
+        assert_eq!(header.kind, Kind::GoAway);
+        assert_eq!(Payload::parse(header, payload).unwrap().kind(), Kind::GoAway);
+        assert_eq!(HttpError::from(ErrorCode::parse(&payload[4..])), error);
+        assert!(conn.is_closed());
+    }
+
+    fn assert_reset(conn: &mut Connection, id: u32, error: HttpError) {
+        let frames = output(conn);
+        let &(header, ref payload) = frames.last().unwrap();
+        assert_eq!(header.kind, Kind::Reset);
+        assert_eq!(header.id, StreamIdentifier(id));
+        assert_eq!(HttpError::from(ErrorCode::parse(payload)), error);
+        assert!(!conn.is_closed());
+    }
+
+    #[test]
+    fn test_preface_and_settings() {
+        let mut conn = Connection::new(HttpVersion::H2c, None, None, None);
+        let mut buf = PREFACE[..10].to_vec();
+        conn.recv(&mut buf);
+        assert_eq!(conn.state(), ConnectionState::Preface);
+
+        buf.extend_from_slice(&PREFACE[10..]);
+        frame(&mut buf, Payload::Settings(&[]), Flag::empty(), 0);
+        conn.recv(&mut buf);
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0.kind, Kind::Settings);
+        assert!(!frames[0].0.flag.contains(Flag::ack()));
+        let mut announced = Settings::default();
+        announced.apply_all(&frames[0].1).unwrap();
+        assert_eq!(announced.max_header_list_size, Some(DEFAULT_MAX_HEADER_LIST_SIZE));
+        assert_eq!(frames[1].0.kind, Kind::Settings);
+        assert!(frames[1].0.flag.contains(Flag::ack()));
+        assert_eq!(conn.state(), ConnectionState::Open);
+    }
+
+    #[test]
+    fn test_bad_preface() {
+        let mut conn = Connection::new(HttpVersion::H2c, None, None, None);
+        let mut buf = b"GET / HTTP/1.1\r\n".to_vec();
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_first_frame_must_be_settings() {
+        let mut conn = Connection::new(HttpVersion::H2c, None, None, None);
+        let mut buf = PREFACE.to_vec();
+        frame(&mut buf, Payload::Ping(0), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_invalid_field_name() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let headers: Vec<(&[u8], &[u8])> = vec![
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+            (b"\xff\xfe", b"test"),
+        ];
+        let block = encoder.encode(headers);
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+        assert_reset(&mut conn, 1, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_request_response() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/index.html?a=1");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+
+        let (id, req) = conn.poll_request().unwrap();
+        assert_eq!(id, StreamIdentifier(1));
+        assert_eq!(req.method(), Method::Get);
+        assert_eq!(req.path(), "/index.html");
+        assert_eq!(req.query().unwrap()["a"], vec!["1".to_string()]);
+        assert_eq!(req.host(), "localhost");
+        assert_eq!(req.user_agent(), Some("test"));
+        assert_eq!(req.http_version(), HttpVersion::H2c);
+        assert!(conn.poll_request().is_none());
+
+        let res = Response::new().with_header("Content-Length", "5")
+                                 .with_header("Connection", "keep-alive")
+                                 .with_body(b"hello".to_vec());
+        conn.send_response(id, res);
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert!(frames[0].0.flag.contains(Flag::end_headers()));
+        assert!(!frames[0].0.flag.contains(Flag::end_stream()));
+
+        let headers = Decoder::new().decode(&frames[0].1).unwrap();
+        assert_eq!(headers[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(headers.contains(&(b"content-length".to_vec(), b"5".to_vec())));
+        assert!(!headers.iter().any(|h| h.0 == b"connection"));
+
+        assert_eq!(frames[1].0.kind, Kind::Data);
+        assert!(frames[1].0.flag.contains(Flag::end_stream()));
+        assert_eq!(frames[1].1, b"hello".to_vec());
+        assert!(!conn.has_open_streams());
+    }
+
+    #[test]
+    fn test_head_response() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let headers: Vec<(&[u8], &[u8])> = vec![
+            (b":method", b"HEAD"),
+            (b":scheme", b"http"),
+            (b":path", b"/index.html"),
+        ];
+        let block = encoder.encode(headers);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+
+        let (id, req) = conn.poll_request().unwrap();
+        assert_eq!(req.method(), Method::Head);
+        let res = Response::new().with_header("Content-Length", "5").with_body(b"hello".to_vec());
+        conn.send_response(id, res);
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert!(frames[0].0.flag.contains(Flag::end_stream()));
+        let headers = Decoder::new().decode(&frames[0].1).unwrap();
+        assert!(headers.contains(&(b"content-length".to_vec(), b"5".to_vec())));
+        assert!(!conn.has_open_streams());
+    }
+
+    #[test]
+    fn test_request_body() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let headers: Vec<(&[u8], &[u8])> = vec![
+            (b":method", b"POST"),
+            (b":scheme", b"http"),
+            (b":path", b"/upload"),
+            (b"content-type", b"text/plain"),
+        ];
+        let block = encoder.encode(headers);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        frame(&mut buf, Payload::Data { data: b"hello " }, Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+
+        frame(&mut buf, Payload::Data { data: b"world" }, Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+
+        let (_, req) = conn.poll_request().unwrap();
+        assert_eq!(req.method(), Method::Post);
+        assert_eq!(req.content_type(), "text/plain");
+        assert_eq!(req.payload(), Some(&b"hello world"[..]));
+        assert_eq!(req.content_length(), 11);
+    }
+
+    #[test]
+    fn test_large_response_is_split() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        let (id, _) = conn.poll_request().unwrap();
+
+        conn.send_response(id, Response::new().with_body(vec![0; 40000]));
+
+        let frames = output(&mut conn);
+        let data: Vec<_> = frames.iter().filter(|f| f.0.kind == Kind::Data).collect();
+        assert_eq!(data.len(), 3);
+        assert!(data.iter().all(|f| f.0.length <= 16384));
+        assert!(data[2].0.flag.contains(Flag::end_stream()));
+    }
+
+    #[test]
+    fn test_even_stream_id() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 2);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_data_on_stream_zero() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Data { data: b"x" }, Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_data_after_end_stream() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        frame(&mut buf, Payload::Data { data: b"x" }, Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 1, HttpError::StreamClosed);
+        assert!(conn.poll_request().is_none());
+    }
+
+    #[test]
+    fn test_reset_drops_response() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        let (id, _) = conn.poll_request().unwrap();
+
+        frame(&mut buf, Payload::Reset(ErrorCode(0x8)), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        conn.send_response(id, Response::new());
+        assert!(output(&mut conn).is_empty());
+    }
+
+    #[test]
+    fn test_window_update_unblocks_response() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        let (id, _) = conn.poll_request().unwrap();
+
+        conn.send_response(id, Response::new().with_body(vec![0; 100000]));
+        let frames = output(&mut conn);
+        let sent: u32 = frames.iter().filter(|f| f.0.kind == Kind::Data).map(|f| f.0.length).sum();
+        assert_eq!(sent, 65535);
+        assert!(frames.iter().all(|f| !f.0.flag.contains(Flag::end_stream())));
+
+        // The stream window alone is not enough, the connection window is exhausted too.
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(40000)), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert!(output(&mut conn).is_empty());
+
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(40000)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        let frames = output(&mut conn);
+        let sent: u32 = frames.iter().map(|f| f.0.length).sum();
+        assert_eq!(sent, 100000 - 65535);
+        assert!(frames.last().unwrap().0.flag.contains(Flag::end_stream()));
+        assert!(!conn.has_open_streams());
+    }
+
+    #[test]
+    fn test_request_body_window_update() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        frame(&mut buf, Payload::Data { data: &[0; 16384] }, Flag::empty(), 1);
+        frame(&mut buf, Payload::Data { data: &[0; 16384] }, Flag::empty(), 1);
+        conn.recv(&mut buf);
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 2);
+        for &(header, ref payload) in &frames {
+            assert_eq!(header.kind, Kind::WindowUpdate);
+            assert_eq!(SizeIncrement::parse(payload), SizeIncrement(32768));
+        }
+        assert_eq!(frames[0].0.id, StreamIdentifier(0));
+        assert_eq!(frames[1].0.id, StreamIdentifier(1));
+    }
+
+    #[test]
+    fn test_buffered_body_holds_connection_window() {
+        let mut conn = handshake();
+        conn.set_body_limits(65536, 0);
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        frame(&mut buf, Payload::Data { data: &[0; 16384] }, Flag::empty(), 1);
+        frame(&mut buf, Payload::Data { data: &[0; 16384] }, Flag::empty(), 1);
+        conn.recv(&mut buf);
+
+        // Only the stream window is given back while the body is not handed out.
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::WindowUpdate);
+        assert_eq!(frames[0].0.id, StreamIdentifier(1));
+
+        frame(&mut buf, Payload::Data { data: &[] }, Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::WindowUpdate);
+        assert_eq!(frames[0].0.id, StreamIdentifier(0));
+        assert_eq!(SizeIncrement::parse(&frames[0].1), SizeIncrement(32768));
+        assert!(conn.poll_request().is_some());
+    }
+
+    #[test]
+    fn test_request_body_too_large() {
+        let mut conn = handshake();
+        conn.set_body_limits(10, 100);
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        frame(&mut buf, Payload::Data { data: b"hello" }, Flag::empty(), 1);
+        frame(&mut buf, Payload::Data { data: b"world!" }, Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+
+        let frames = output(&mut conn);
+        let mut decoder = Decoder::new();
+        let headers = decoder.decode(&frames[frames.len() - 2].1).unwrap();
+        assert_eq!(headers[0], (b":status".to_vec(), b"413".to_vec()));
+        let &(header, ref payload) = frames.last().unwrap();
+        assert_eq!(header.kind, Kind::Reset);
+        assert_eq!(HttpError::from(ErrorCode::parse(payload)), HttpError::NoError);
+    }
+
+    // Opens one stream per (id, priority) pair, then exhausts the connection window on a stream
+    // of its own so the responses queued next are only sent after a WINDOW_UPDATE.
+    fn blocked(streams: &[(u32, Option<Priority>)]) -> Connection {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let mut buf = Vec::new();
+        for &(id, priority) in streams.iter().chain(Some(&(99, None))) {
+            let block = get(&mut encoder, "/");
+            let mut flag = Flag::end_headers() | Flag::end_stream();
+            if priority.is_some() {
+                flag = flag | Flag::priority();
+            }
+            frame(&mut buf, Payload::Headers { priority: priority, block: &block }, flag, id);
+        }
+        conn.recv(&mut buf);
+        while conn.poll_request().is_some() {}
+        assert!(!conn.is_closed());
+
+        conn.send_response(StreamIdentifier(99), Response::new().with_body(vec![0; 65535]));
+        output(&mut conn);
+        conn
+    }
+
+    // Stream ids of the DATA frames carrying END_STREAM, in the order they were sent.
+    fn finished(conn: &mut Connection) -> Vec<u32> {
+        output(conn).iter()
+                    .filter(|f| f.0.kind == Kind::Data && f.0.flag.contains(Flag::end_stream()))
+                    .map(|f| f.0.id.0)
+                    .collect()
+    }
+
+    #[test]
+    fn test_priority_weight() {
+        let heavy = Priority::new(false, StreamIdentifier(0), 255);
+        let mut conn = blocked(&[(1, None), (3, Some(heavy))]);
+        conn.send_response(StreamIdentifier(1), Response::new().with_body(vec![0; 40000]));
+        conn.send_response(StreamIdentifier(3), Response::new().with_body(vec![0; 40000]));
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(80000)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_eq!(finished(&mut conn), vec![3, 1]);
+    }
+
+    #[test]
+    fn test_priority_dependency() {
+        let mut conn = blocked(&[(1, None), (3, None)]);
+
+        // Stream 3 now depends on stream 1 and only gets to send once stream 1 is done.
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Priority(Priority::new(true, StreamIdentifier(1), 15)), Flag::empty(), 3);
+        conn.recv(&mut buf);
+
+        conn.send_response(StreamIdentifier(3), Response::new().with_body(vec![0; 20000]));
+        conn.send_response(StreamIdentifier(1), Response::new().with_body(vec![0; 20000]));
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(40000)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+
+        let frames = output(&mut conn);
+        let data: Vec<u32> = frames.iter().filter(|f| f.0.kind == Kind::Data).map(|f| f.0.id.0).collect();
+        assert_eq!(data, vec![1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn test_priority_chain() {
+        let mut conn = blocked(&[(1, None)]);
+
+        // A long chain of idle streams, each depending on the one before it.
+        let mut buf = Vec::new();
+        for i in 0..10000 {
+            let priority = Priority::new(false, StreamIdentifier(2 * i + 101), 15);
+            frame(&mut buf, Payload::Priority(priority), Flag::empty(), 2 * i + 103);
+        }
+        conn.recv(&mut buf);
+        assert!(output(&mut conn).is_empty());
+
+        conn.send_response(StreamIdentifier(1), Response::new().with_body(vec![0; 100]));
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(100)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_eq!(finished(&mut conn), vec![1]);
+    }
+
+    #[test]
+    fn test_priority_self_dependency() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Priority(Priority::new(false, StreamIdentifier(1), 15)), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_priority_self_dependency_open_stream() {
+        let mut conn = blocked(&[(1, None)]);
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Priority(Priority::new(false, StreamIdentifier(1), 15)), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 1, HttpError::ProtocolError);
+    }
+
+    fn open(conn: &mut Connection, path: &str) -> StreamIdentifier {
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, path);
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        conn.poll_request().unwrap().0
+    }
+
+    #[test]
+    fn test_push() {
+        let mut conn = handshake();
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css").with_body(b"page".to_vec()));
+
+        let frames = output(&mut conn);
+        let kinds: Vec<Kind> = frames.iter().map(|f| f.0.kind).collect();
+        assert_eq!(kinds, vec![Kind::PushPromise, Kind::Headers, Kind::Data]);
+        assert_eq!(frames[0].0.id, id);
+        assert!(frames[0].0.flag.contains(Flag::end_headers()));
+        assert_eq!(StreamIdentifier::parse(&frames[0].1), StreamIdentifier(2));
+
+        let mut decoder = Decoder::new();
+        let promised = decoder.decode(&frames[0].1[4..]).unwrap();
+        assert!(promised.contains(&(b":method".to_vec(), b"GET".to_vec())));
+        assert!(promised.contains(&(b":path".to_vec(), b"/app.css".to_vec())));
+        assert!(promised.contains(&(b":authority".to_vec(), b"localhost".to_vec())));
+        decoder.decode(&frames[1].1).unwrap();
+
+        let (pushed, req) = conn.poll_request().unwrap();
+        assert_eq!(pushed, StreamIdentifier(2));
+        assert_eq!(req.method(), Method::Get);
+        assert_eq!(req.path(), "/app.css");
+
+        conn.send_response(pushed, Response::new().with_body(b"body {}".to_vec()));
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert_eq!(frames[0].0.id, pushed);
+        assert_eq!(frames[1].0.kind, Kind::Data);
+        assert!(frames[1].0.flag.contains(Flag::end_stream()));
+        assert!(!conn.has_open_streams());
+    }
+
+    fn index(_: Request, _: String) -> Response {
+        Response::new().with_body(b"page".to_vec())
+    }
+
+    #[test]
+    fn test_push_route() {
+        let router = RouterBuilder::new().add(Route::get("/").push("/app.js").using(index)).build();
+        let mut conn = Connection::new(HttpVersion::H2c, None, Some(router), None);
+        let mut buf = PREFACE.to_vec();
+        frame(&mut buf, Payload::Settings(&[]), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        output(&mut conn);
+
+        let id = open(&mut conn, "/");
+        conn.send_response(id, index(conn.streams[&id.0].request.clone().unwrap(), String::new()));
+        assert_eq!(output(&mut conn)[0].0.kind, Kind::PushPromise);
+
+        let (pushed, req) = conn.poll_request().unwrap();
+        assert_eq!(pushed, StreamIdentifier(2));
+        assert_eq!(req.path(), "/app.js");
+        // A pushed response cannot push in turn.
+        conn.send_response(pushed, Response::new().with_push("/other.js"));
+        assert!(conn.poll_request().is_none());
+    }
+
+    #[test]
+    fn test_push_disabled() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        let settings = Setting::encode_all(&[Setting::new(SettingIdentifier::EnablePush, 0)]);
+        frame(&mut buf, Payload::Settings(&settings), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        output(&mut conn);
+
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css"));
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert!(conn.poll_request().is_none());
+    }
+
+    #[test]
+    fn test_push_max_concurrent_streams() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        let settings = Setting::encode_all(&[Setting::new(SettingIdentifier::MaxConcurrentStreams, 1)]);
+        frame(&mut buf, Payload::Settings(&settings), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        output(&mut conn);
+
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css").with_push("/app.js"));
+        let kinds: Vec<Kind> = output(&mut conn).iter().map(|f| f.0.kind).collect();
+        assert_eq!(kinds, vec![Kind::PushPromise, Kind::Headers]);
+        let (pushed, req) = conn.poll_request().unwrap();
+        assert_eq!(req.path(), "/app.css");
+        assert!(conn.poll_request().is_none());
+
+        // Once the pushed response is done, there is room for another push.
+        conn.send_response(pushed, Response::new());
+        let block = get(&mut Encoder::new(), "/");
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers() | Flag::end_stream(), 3);
+        conn.recv(&mut buf);
+        let (id, _) = conn.poll_request().unwrap();
+        conn.send_response(id, Response::new().with_push("/app.js"));
+        assert_eq!(output(&mut conn).iter().filter(|f| f.0.kind == Kind::PushPromise).count(), 1);
+        assert_eq!(conn.poll_request().unwrap().1.path(), "/app.js");
+    }
+
+    #[test]
+    fn test_push_stream_ids_exhausted() {
+        let mut conn = handshake();
+        conn.last_push_id = StreamIdentifier(MAX_STREAM_ID - 1);
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css"));
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert!(conn.poll_request().is_none());
+    }
+
+    #[test]
+    fn test_push_reset() {
+        let mut conn = handshake();
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css"));
+        output(&mut conn);
+
+        // The client declines the push.
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Reset(ErrorCode(0x8)), Flag::empty(), 2);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+        assert!(!conn.is_closed());
+
+        // A reset on a stream that was never promised is a connection error.
+        frame(&mut buf, Payload::Reset(ErrorCode(0x8)), Flag::empty(), 4);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/split");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block[..5] }, Flag::end_stream(), 1);
+        frame(&mut buf, Payload::Continuation(&block[5..10]), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+
+        frame(&mut buf, Payload::Continuation(&block[10..]), Flag::end_headers(), 1);
+        conn.recv(&mut buf);
+        let (id, req) = conn.poll_request().unwrap();
+        assert_eq!(id, StreamIdentifier(1));
+        assert_eq!(req.path(), "/split");
+    }
+
+    #[test]
+    fn test_interleaved_continuation() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block[..5] }, Flag::end_stream(), 1);
+        frame(&mut buf, Payload::Ping(0), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_unexpected_continuation() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Continuation(b"x"), Flag::end_headers(), 1);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_endless_continuation() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &[0; 16384] }, Flag::empty(), 1);
+        frame(&mut buf, Payload::Continuation(&[0; 16384]), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::EnhanceYourCalm);
+    }
+
+    #[test]
+    fn test_header_list_decompression_bomb() {
+        let mut settings = Settings::default();
+        settings.max_header_list_size = Some(200);
+        let mut conn = handshake_with(Connection::with_settings(settings, HttpVersion::H2c, None, None, None));
+
+        // A single large dynamic table entry, referenced over and over.
+        let mut block = vec![0x40, 1, b'x', 127, 23];
+        block.extend_from_slice(&[b'a'; 150]);
+        block.extend_from_slice(&[0x80 | 62; 20]);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+        assert_goaway(&mut conn, HttpError::CompressionError);
+    }
+
+    #[test]
+    fn test_header_list_too_large() {
+        let mut settings = Settings::default();
+        settings.max_header_list_size = Some(200);
+        let mut conn = handshake_with(Connection::with_settings(settings, HttpVersion::H2c, None, None, None));
+        let mut encoder = Encoder::new();
+        let cookie = vec![b'a'; 150];
+        let headers: Vec<(&[u8], &[u8])> = vec![
+            (b":method", b"GET"),
+            (b":scheme", b"http"),
+            (b":path", b"/"),
+            (b"cookie", &cookie),
+        ];
+        let block = encoder.encode(headers);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        let headers = Decoder::new().decode(&frames[0].1).unwrap();
+        assert_eq!(headers[0], (b":status".to_vec(), b"431".to_vec()));
+        assert_eq!(frames[1].0.kind, Kind::Reset);
+        assert_eq!(HttpError::from(ErrorCode::parse(&frames[1].1)), HttpError::NoError);
+        assert!(!conn.is_closed());
+    }
+
+    #[test]
+    fn test_goaway_last_stream_id() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let mut buf = Vec::new();
+        for id in &[1, 5] {
+            let block = get(&mut encoder, "/");
+            frame(&mut buf, Payload::Headers { priority: None, block: &block },
+                  Flag::end_headers() | Flag::end_stream(), *id);
+        }
+        frame(&mut buf, Payload::Data { data: b"x" }, Flag::empty(), 0);
+        conn.recv(&mut buf);
+
+        let frames = output(&mut conn);
+        let &(header, ref payload) = frames.last().unwrap();
+        assert_eq!(header.kind, Kind::GoAway);
+        assert_eq!(StreamIdentifier::parse(payload), StreamIdentifier(5));
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let mut conn = handshake();
+        let mut encoder = Encoder::new();
+        let headers: Vec<(&[u8], &[u8])> = vec![(b":method", b"GET"), (b":scheme", b"http")];
+        let block = encoder.encode(headers);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 1, HttpError::ProtocolError);
+        assert!(conn.poll_request().is_none());
+    }
+
+    #[test]
+    fn test_stream_window_update_overflow() {
+        let mut conn = blocked(&[(1, None)]);
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(MAX_WINDOW_SIZE)), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 1, HttpError::FlowControlError);
+    }
+
+    #[test]
+    fn test_incoming_goaway_drains() {
+        let mut conn = blocked(&[(1, None)]);
+        conn.send_response(StreamIdentifier(1), Response::new().with_body(vec![0; 100]));
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::GoAway { last: StreamIdentifier(0), error: HttpError::NoError.into(), data: &[] },
+              Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_eq!(conn.state(), ConnectionState::Draining);
+
+        // New streams are refused while draining.
+        let mut encoder = Encoder::new();
+        let block = get(&mut encoder, "/");
+        frame(&mut buf, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 101);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 101, HttpError::RefusedStream);
+
+        // The response in flight still completes, after which the connection closes.
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(100)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].0.flag.contains(Flag::end_stream()));
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_incoming_goaway_cancels_pushes() {
+        let mut conn = handshake();
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new().with_push("/app.css").with_body(vec![0; 10]));
+        output(&mut conn);
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::GoAway { last: StreamIdentifier(0), error: HttpError::NoError.into(), data: &[] },
+              Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert!(conn.poll_request().is_none());
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_zero_window_update() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(0)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_window_update_overflow() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::WindowUpdate(SizeIncrement(MAX_WINDOW_SIZE)), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::FlowControlError);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut conn = Connection::new(HttpVersion::H2c, None, None, None);
+        let headers = vec![(b":method".to_vec(), b"GET".to_vec()),
+                           (b":path".to_vec(), b"/".to_vec())];
+        let request = Request::from_header_list(headers.into_iter().collect(), Vec::new(), HttpVersion::Http11, None, None, None).unwrap();
+        conn.upgrade(&[], request).unwrap();
+
+        let (id, request) = conn.poll_request().unwrap();
+        assert_eq!(id, StreamIdentifier(1));
+        assert_eq!(request.path(), "/");
+        // The HTTP2-Settings are acknowledged by the 101 response, not by a SETTINGS ACK.
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].0.flag.contains(Flag::ack()));
+
+        // The client still sends the preface; stream 1 is taken.
+        let mut buf = PREFACE.to_vec();
+        frame(&mut buf, Payload::Settings(&[]), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_eq!(conn.state(), ConnectionState::Open);
+        output(&mut conn);
+
+        conn.send_response(id, Response::new());
+        let frames = output(&mut conn);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert_eq!(frames[0].0.id, StreamIdentifier(1));
+        assert!(frames[0].0.flag.contains(Flag::end_stream()));
+        assert!(!conn.has_open_streams());
+
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Headers { priority: None, block: &[] }, Flag::end_headers(), 1);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_initial_window_size_setting() {
+        let mut conn = handshake();
+        let id = open(&mut conn, "/");
+
+        // As sent by a client: identifier 0x4, value 100, in network byte order. The new size
+        // also applies to the already open stream.
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Settings(&[0x0, 0x4, 0x0, 0x0, 0x0, 0x64]), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_eq!(output(&mut conn).len(), 1);
+
+        conn.send_response(id, Response::new().with_body(vec![0; 1000]));
+        let frames = output(&mut conn);
+        let sent: u32 = frames.iter().filter(|f| f.0.kind == Kind::Data).map(|f| f.0.length).sum();
+        assert_eq!(sent, 100);
+    }
+
+    #[test]
+    fn test_invalid_setting() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        let settings = Setting::encode_all(&[Setting::new(SettingIdentifier::MaxFrameSize, 100)]);
+        frame(&mut buf, Payload::Settings(&settings), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_max_concurrent_streams() {
+        let mut settings = Settings::default();
+        settings.max_concurrent_streams = Some(1);
+        let mut conn = handshake_with(Connection::with_settings(settings, HttpVersion::H2c, None, None, None));
+        let mut encoder = Encoder::new();
+
+        let mut buf = Vec::new();
+        let block = get(&mut encoder, "/");
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 1);
+        let block = get(&mut encoder, "/");
+        frame(&mut buf, Payload::Headers { priority: None, block: &block }, Flag::end_headers(), 3);
+        conn.recv(&mut buf);
+        assert_reset(&mut conn, 3, HttpError::RefusedStream);
+    }
+
+    #[test]
+    fn test_ping_ack() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Ping(0x0102030405060708), Flag::empty(), 0);
+        conn.recv(&mut buf);
+
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::Ping);
+        assert!(frames[0].0.flag.contains(Flag::ack()));
+        assert_eq!(frames[0].1, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // ACKs are not answered, and PING on a stream is a connection error.
+        frame(&mut buf, Payload::Ping(7), Flag::ack(), 0);
+        conn.recv(&mut buf);
+        assert!(output(&mut conn).is_empty());
+        frame(&mut buf, Payload::Ping(7), Flag::empty(), 1);
+        conn.recv(&mut buf);
+        assert_goaway(&mut conn, HttpError::ProtocolError);
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let mut conn = handshake();
+        conn.set_keepalive(Duration::from_secs(10), Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(conn.poll_keepalive(start).is_some());
+        assert!(output(&mut conn).is_empty());
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(conn.poll_keepalive(later), Some(later + Duration::from_secs(5)));
+        let frames = output(&mut conn);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, Kind::Ping);
+        assert!(!frames[0].0.flag.contains(Flag::ack()));
+
+        let data = match Payload::parse(frames[0].0, &frames[0].1).unwrap() {
+            Payload::Ping(data) => data,
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        frame(&mut buf, Payload::Ping(data), Flag::ack(), 0);
+        conn.recv(&mut buf);
+        assert!(conn.rtt().is_some());
+        assert!(!conn.is_closed());
+
+        let id = open(&mut conn, "/");
+        assert_eq!(id, StreamIdentifier(1));
+    }
+
+    #[test]
+    fn test_keepalive_timeout() {
+        let mut conn = handshake();
+        conn.set_keepalive(Duration::from_secs(10), Duration::from_secs(5));
+        let start = Instant::now();
+        conn.poll_keepalive(start + Duration::from_secs(10));
+        assert_eq!(output(&mut conn)[0].0.kind, Kind::Ping);
+
+        assert_eq!(conn.poll_keepalive(start + Duration::from_secs(15)), None);
+        assert_goaway(&mut conn, HttpError::NoError);
+    }
+
+    #[test]
+    fn test_header_table_size_setting() {
+        let mut conn = handshake();
+        let mut buf = Vec::new();
+        let settings = Setting::encode_all(&[Setting::new(SettingIdentifier::HeaderTableSize, 0)]);
+        frame(&mut buf, Payload::Settings(&settings), Flag::empty(), 0);
+        conn.recv(&mut buf);
+        output(&mut conn);
+
+        let id = open(&mut conn, "/");
+        conn.send_response(id, Response::new());
+        let frames = output(&mut conn);
+        assert_eq!(frames[0].0.kind, Kind::Headers);
+        assert_eq!(frames[0].1[0], 0x20);
+
+        let mut decoder = Decoder::new();
+        let headers = decoder.decode(&frames[0].1).unwrap();
+        assert_eq!(headers[0], (b":status".to_vec(), b"200".to_vec()));
+    }
+
+    #[test]
+    fn test_upgrade_partial_setting() {
+        let mut conn = Connection::new(HttpVersion::H2c, None, None, None);
+        let request = Request::from_header_list(vec![(&b":method"[..], &b"GET"[..]),
+                                                     (&b":path"[..], &b"/"[..])].into_iter().collect(),
+                                                Vec::new(), HttpVersion::Http11, None, None, None).unwrap();
+        assert_eq!(conn.upgrade(&[0, 3, 0], request), Err(HttpError::ProtocolError));
+    }
+}
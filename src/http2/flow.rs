@@ -0,0 +1,179 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/2 flow control windows (RFC 7540 section 6.9).
+//!
+//! Every stream has a window in each direction, and so does the connection as a whole. A DATA
+//! frame counts against both the stream's and the connection's window.
+
+use http2::HttpError;
+use http2::SizeIncrement;
+
+/// Initial size of every window unless changed by SETTINGS_INITIAL_WINDOW_SIZE.
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+
+/// Largest window size a peer may produce (2^31 - 1).
+pub const MAX_WINDOW_SIZE: u32 = 2147483647;
+
+/// A flow control window.
+///
+/// The size is signed because a SETTINGS_INITIAL_WINDOW_SIZE change can leave the send window of
+/// an open stream negative (RFC 7540 section 6.9.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Window(i64);
+
+impl Window {
+    pub fn new(size: u32) -> Window {
+        Window(size as i64)
+    }
+
+    pub fn size(&self) -> i64 {
+        self.0
+    }
+
+    /// Number of bytes that may be sent right now.
+    pub fn available(&self) -> u32 {
+        if self.0 > 0 { self.0 as u32 } else { 0 }
+    }
+
+    /// Grows the window by a WINDOW_UPDATE increment. A window may never exceed 2^31 - 1.
+    pub fn increase(&mut self, increment: u32) -> Result<(), HttpError> {
+        self.adjust(increment as i64)
+    }
+
+    /// Shrinks the window by the size of a DATA frame that was received. The peer must never send
+    /// more than the window allows.
+    pub fn consume(&mut self, amt: u32) -> Result<(), HttpError> {
+        if amt as i64 > self.0 {
+            return Err(HttpError::FlowControlError);
+        }
+        self.0 -= amt as i64;
+        Ok(())
+    }
+
+    /// Shrinks the window by the size of a DATA frame that was sent. Callers only send what
+    /// `available` allows.
+    pub fn send(&mut self, amt: u32) {
+        debug_assert!(amt <= self.available());
+        self.0 -= amt as i64;
+    }
+
+    /// Applies the difference between an old and new SETTINGS_INITIAL_WINDOW_SIZE.
+    pub fn adjust(&mut self, delta: i64) -> Result<(), HttpError> {
+        let size = self.0 + delta;
+        if size > MAX_WINDOW_SIZE as i64 {
+            return Err(HttpError::FlowControlError);
+        }
+        self.0 = size;
+        Ok(())
+    }
+}
+
+/// The receiving side of a window: tracks how much of the received data was consumed by the
+/// application and decides when to hand the capacity back to the peer with WINDOW_UPDATE.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecvWindow {
+    window: Window,
+    target: u32,
+    unreleased: u32,
+}
+
+impl RecvWindow {
+    pub fn new(size: u32) -> RecvWindow {
+        RecvWindow {
+            window: Window::new(size),
+            target: size,
+            unreleased: 0,
+        }
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Accounts for a received DATA frame. Fails with FLOW_CONTROL_ERROR if the peer overran the
+    /// window.
+    pub fn recv(&mut self, amt: u32) -> Result<(), HttpError> {
+        self.window.consume(amt)
+    }
+
+    /// Marks `amt` bytes as consumed by the application. Returns the increment to announce once
+    /// at least half of the window has been consumed, so WINDOW_UPDATE frames are batched.
+    pub fn release(&mut self, amt: u32) -> Option<SizeIncrement> {
+        self.unreleased += amt;
+        if self.unreleased == 0 || self.unreleased < self.target / 2 {
+            return None;
+        }
+
+        let increment = self.unreleased;
+        self.unreleased = 0;
+        // Cannot overflow: the window never grows past its target.
+        self.window.0 += increment as i64;
+        Some(SizeIncrement(increment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::HttpError;
+    use http2::SizeIncrement;
+    use super::{Window, RecvWindow, DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE};
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::new(DEFAULT_WINDOW_SIZE);
+        window.send(65000);
+        assert_eq!(window.available(), 535);
+
+        window.increase(1000).unwrap();
+        assert_eq!(window.available(), 1535);
+
+        assert_eq!(window.consume(2000), Err(HttpError::FlowControlError));
+        window.consume(1535).unwrap();
+        assert_eq!(window.available(), 0);
+    }
+
+    #[test]
+    fn test_window_overflow() {
+        let mut window = Window::new(MAX_WINDOW_SIZE);
+        assert_eq!(window.increase(1), Err(HttpError::FlowControlError));
+        assert_eq!(window.size(), MAX_WINDOW_SIZE as i64);
+    }
+
+    #[test]
+    fn test_negative_window() {
+        let mut window = Window::new(DEFAULT_WINDOW_SIZE);
+        window.send(60000);
+        // SETTINGS_INITIAL_WINDOW_SIZE dropped from 65535 to 50000.
+        window.adjust(50000 - DEFAULT_WINDOW_SIZE as i64).unwrap();
+        assert_eq!(window.size(), -10000);
+        assert_eq!(window.available(), 0);
+
+        window.increase(10500).unwrap();
+        assert_eq!(window.available(), 500);
+    }
+
+    #[test]
+    fn test_recv_window() {
+        let mut window = RecvWindow::new(100);
+        window.recv(40).unwrap();
+        assert_eq!(window.release(40), None);
+
+        window.recv(40).unwrap();
+        assert_eq!(window.release(40), Some(SizeIncrement(80)));
+        assert_eq!(window.window().size(), 100);
+
+        assert_eq!(window.recv(101), Err(HttpError::FlowControlError));
+    }
+}
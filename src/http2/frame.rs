@@ -82,3 +82,203 @@ impl FrameHeader {
         self.id.encode(&mut buf[5..]);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http2::kind::*;
+    use http2::flag::*;
+    use http2::payload::*;
+    use http2::frame::*;
+
+    use http2::Error;
+    use http2::ErrorCode;
+    use http2::SizeIncrement;
+    use http2::StreamIdentifier;
+    use http2::FRAME_HEADER_BYTES;
+
+    // Encodes the given frame, parses it back and checks that nothing was lost on the way.
+    fn roundtrip(frame: Frame) {
+        let mut buf = vec![0; frame.encoded_len()];
+        assert_eq!(frame.encode(&mut buf), frame.encoded_len());
+
+        let header = FrameHeader::parse(&buf).unwrap();
+        assert_eq!(header, frame.header);
+
+        let parsed = Frame::parse(header, &buf[FRAME_HEADER_BYTES..]).unwrap();
+        assert_eq!(parsed, frame);
+    }
+
+    fn header(payload: &Payload, flag: Flag, id: u32) -> FrameHeader {
+        FrameHeader {
+            length: payload.encoded_len() as u32,
+            kind: payload.kind(),
+            flag: flag,
+            id: StreamIdentifier(id),
+        }
+    }
+
+    fn frame<'a>(payload: Payload<'a>, flag: Flag, id: u32) -> Frame<'a> {
+        Frame { header: header(&payload, flag, id), payload: payload }
+    }
+
+    #[test]
+    fn test_frame_header() {
+        let header = FrameHeader {
+            length: 0x123456,
+            kind: Kind::Headers,
+            flag: Flag::end_headers() | Flag::end_stream(),
+            id: StreamIdentifier(0x7fffffff),
+        };
+        let mut buf = [0; FRAME_HEADER_BYTES];
+        header.encode(&mut buf);
+
+        assert_eq!(buf, [0x12, 0x34, 0x56, 0x1, 0x5, 0x7f, 0xff, 0xff, 0xff]);
+        assert_eq!(FrameHeader::parse(&buf).unwrap(), header);
+        assert_eq!(FrameHeader::parse(&buf[..8]), Err(Error::Short));
+    }
+
+    #[test]
+    fn test_reserved_bit_ignored() {
+        let buf = [0, 0, 0, 0x8, 0, 0x80, 0, 0, 0x3];
+        assert_eq!(FrameHeader::parse(&buf).unwrap().id, StreamIdentifier(3));
+    }
+
+    #[test]
+    fn test_data() {
+        roundtrip(frame(Payload::Data { data: b"hello world" }, Flag::end_stream(), 1));
+        roundtrip(frame(Payload::Data { data: b"" }, Flag::empty(), 3));
+    }
+
+    #[test]
+    fn test_padded_data() {
+        // length 8: pad length (1) + "hello" (5) + padding (2)
+        let buf = [0x2, b'h', b'e', b'l', b'l', b'o', 0, 0];
+        let header = FrameHeader {
+            length: 8,
+            kind: Kind::Data,
+            flag: Flag::padded(),
+            id: StreamIdentifier(1),
+        };
+
+        assert_eq!(Payload::parse(header, &buf).unwrap(), Payload::Data { data: b"hello" });
+    }
+
+    #[test]
+    fn test_too_much_padding() {
+        let buf = [0x8, 0, 0, 0, 0, 0, 0, 0];
+        let header = FrameHeader {
+            length: 8,
+            kind: Kind::Data,
+            flag: Flag::padded(),
+            id: StreamIdentifier(1),
+        };
+
+        assert_eq!(Payload::parse(header, &buf), Err(Error::TooMuchPadding(8)));
+    }
+
+    #[test]
+    fn test_headers() {
+        roundtrip(frame(Payload::Headers { priority: None, block: b"\x82\x84" },
+                        Flag::end_headers(), 1));
+
+        let mut buf = [0; 5];
+        buf[0] = 0x80;
+        buf[3] = 0x3;
+        buf[4] = 15;
+        let (_, priority) = Priority::parse(true, &buf).unwrap();
+        roundtrip(frame(Payload::Headers { priority: priority, block: b"\x82\x84" },
+                        Flag::end_headers() | Flag::priority(), 5));
+    }
+
+    #[test]
+    fn test_priority() {
+        let buf = [0x80, 0, 0, 0x1, 0xff];
+        let (rest, priority) = Priority::parse(true, &buf).unwrap();
+        assert!(rest.is_empty());
+        roundtrip(frame(Payload::Priority(priority.unwrap()), Flag::empty(), 3));
+
+        let header = FrameHeader {
+            length: 4,
+            kind: Kind::Priority,
+            flag: Flag::empty(),
+            id: StreamIdentifier(3),
+        };
+        assert_eq!(Payload::parse(header, &buf), Err(Error::InvalidPayloadLength));
+    }
+
+    #[test]
+    fn test_reset() {
+        roundtrip(frame(Payload::Reset(ErrorCode(0x8)), Flag::empty(), 1));
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = [Setting::new(SettingIdentifier::EnablePush, 0),
+                        Setting::new(SettingIdentifier::MaxFrameSize, 16384)];
+        let payload = Setting::encode_all(&settings);
+        roundtrip(frame(Payload::Settings(&payload), Flag::empty(), 0));
+        roundtrip(frame(Payload::Settings(&[]), Flag::ack(), 0));
+    }
+
+    #[test]
+    fn test_partial_settings() {
+        let header = FrameHeader {
+            length: 5,
+            kind: Kind::Settings,
+            flag: Flag::empty(),
+            id: StreamIdentifier(0),
+        };
+        assert_eq!(Payload::parse(header, &[0; 5]), Err(Error::PartialSettingLength));
+    }
+
+    #[test]
+    fn test_push_promise() {
+        roundtrip(frame(Payload::PushPromise { promised: StreamIdentifier(2), block: b"\x82\x87" },
+                        Flag::end_headers(), 1));
+    }
+
+    #[test]
+    fn test_ping() {
+        roundtrip(frame(Payload::Ping(0x0102030405060708), Flag::empty(), 0));
+        roundtrip(frame(Payload::Ping(42), Flag::ack(), 0));
+
+        let header = FrameHeader {
+            length: 4,
+            kind: Kind::Ping,
+            flag: Flag::empty(),
+            id: StreamIdentifier(0),
+        };
+        assert_eq!(Payload::parse(header, &[0; 4]), Err(Error::InvalidPayloadLength));
+    }
+
+    #[test]
+    fn test_goaway() {
+        roundtrip(frame(Payload::GoAway {
+            last: StreamIdentifier(7),
+            error: ErrorCode(0x1),
+            data: b"debug data",
+        }, Flag::empty(), 0));
+    }
+
+    #[test]
+    fn test_window_update() {
+        roundtrip(frame(Payload::WindowUpdate(SizeIncrement(65535)), Flag::empty(), 0));
+        roundtrip(frame(Payload::WindowUpdate(SizeIncrement(1)), Flag::empty(), 9));
+    }
+
+    #[test]
+    fn test_continuation() {
+        roundtrip(frame(Payload::Continuation(b"\x84"), Flag::end_headers(), 1));
+    }
+
+    #[test]
+    fn test_short_payload() {
+        let header = FrameHeader {
+            length: 10,
+            kind: Kind::Data,
+            flag: Flag::empty(),
+            id: StreamIdentifier(1),
+        };
+        assert_eq!(Payload::parse(header, &[0; 9]), Err(Error::Short));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reassembly of header blocks split over CONTINUATION frames (RFC 7540 section 6.10).
+//!
+//! A HEADERS or PUSH_PROMISE frame without END_HEADERS must be followed by CONTINUATION frames
+//! on the same stream, with no other frame in between, until one of them carries END_HEADERS.
+
+use http2::flag::*;
+use http2::frame::*;
+use http2::kind::*;
+use http2::payload::*;
+
+use http2::HttpError;
+use http2::StreamIdentifier;
+
+/// Default limit for SETTINGS_MAX_HEADER_LIST_SIZE.
+pub const DEFAULT_MAX_HEADER_LIST_SIZE: u32 = 16384;
+
+/// Per-field overhead counted towards the header list size (RFC 7540 section 6.5.2).
+const HEADER_FIELD_OVERHEAD: usize = 32;
+
+/// A complete header block along with the frame that started it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderBlock {
+    /// Header of the HEADERS or PUSH_PROMISE frame. Its flags tell whether the block ends the
+    /// stream.
+    pub header: FrameHeader,
+    /// Priority carried by the HEADERS frame.
+    pub priority: Option<Priority>,
+    /// Stream promised by a PUSH_PROMISE frame.
+    pub promised: Option<StreamIdentifier>,
+    pub block: Vec<u8>,
+}
+
+impl HeaderBlock {
+    pub fn end_stream(&self) -> bool {
+        self.header.flag.contains(Flag::end_stream())
+    }
+}
+
+/// Joins header block fragments into complete blocks.
+///
+/// A peer could otherwise keep sending CONTINUATION frames forever, so the buffered block may
+/// not grow past the maximum header list size. The compressed block is almost always smaller
+/// than the list it decodes to, so this does not cut off lists that are within the limit.
+#[derive(Clone, Debug)]
+pub struct HeaderBlockAssembler {
+    pending: Option<HeaderBlock>,
+    max_size: u32,
+}
+
+impl HeaderBlockAssembler {
+    pub fn new(max_size: u32) -> HeaderBlockAssembler {
+        HeaderBlockAssembler {
+            pending: None,
+            max_size: max_size,
+        }
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.max_size
+    }
+
+    /// The stream whose header block is incomplete, if any.
+    pub fn stream(&self) -> Option<StreamIdentifier> {
+        self.pending.as_ref().map(|pending| pending.header.id)
+    }
+
+    /// Checks that a frame may be received now: while a block is incomplete only CONTINUATION
+    /// frames on its stream are allowed, and CONTINUATION frames are never allowed otherwise.
+    pub fn check(&self, header: &FrameHeader) -> Result<(), HttpError> {
+        let continuation = header.kind == Kind::Continuation;
+        match self.stream() {
+            Some(id) if !continuation || header.id != id => Err(HttpError::ProtocolError),
+            None if continuation => Err(HttpError::ProtocolError),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes a HEADERS, PUSH_PROMISE or CONTINUATION frame. Returns the block once the frame
+    /// carrying END_HEADERS was received.
+    pub fn recv(&mut self, frame: Frame) -> Result<Option<HeaderBlock>, HttpError> {
+        try!(self.check(&frame.header));
+
+        let fragment = match frame.payload {
+            Payload::Headers { priority, block } => {
+                self.pending = Some(HeaderBlock {
+                    header: frame.header,
+                    priority: priority,
+                    promised: None,
+                    block: Vec::new(),
+                });
+                block
+            },
+            Payload::PushPromise { promised, block } => {
+                self.pending = Some(HeaderBlock {
+                    header: frame.header,
+                    priority: None,
+                    promised: Some(promised),
+                    block: Vec::new(),
+                });
+                block
+            },
+            Payload::Continuation(block) => block,
+            _ => return Err(HttpError::ProtocolError),
+        };
+
+        {
+            let pending = self.pending.as_mut().unwrap();
+            if pending.block.len() + fragment.len() > self.max_size as usize {
+                return Err(HttpError::EnhanceYourCalm);
+            }
+            pending.block.extend_from_slice(fragment);
+        }
+
+        if frame.header.flag.contains(Flag::end_headers()) {
+            Ok(self.pending.take())
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Size of a decoded header list as defined for SETTINGS_MAX_HEADER_LIST_SIZE.
+pub fn header_list_size<N: AsRef<[u8]>, V: AsRef<[u8]>>(headers: &[(N, V)]) -> usize {
+    headers.iter().map(|&(ref name, ref value)| name.as_ref().len() + value.as_ref().len() + HEADER_FIELD_OVERHEAD).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::*;
+    use http2::frame::*;
+    use http2::kind::*;
+    use http2::payload::*;
+
+    use http2::HttpError;
+    use http2::StreamIdentifier;
+    use super::{HeaderBlockAssembler, header_list_size};
+
+    fn frame(payload: Payload, flag: Flag, id: u32) -> Frame {
+        Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: payload.kind(),
+                flag: flag,
+                id: StreamIdentifier(id),
+            },
+            payload: payload,
+        }
+    }
+
+    #[test]
+    fn test_single_frame() {
+        let mut assembler = HeaderBlockAssembler::new(100);
+        let block = assembler.recv(frame(Payload::Headers { priority: None, block: b"abc" },
+                                         Flag::end_headers() | Flag::end_stream(), 1))
+                             .unwrap()
+                             .unwrap();
+        assert_eq!(block.block, b"abc".to_vec());
+        assert_eq!(block.header.kind, Kind::Headers);
+        assert!(block.end_stream());
+        assert_eq!(assembler.stream(), None);
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut assembler = HeaderBlockAssembler::new(100);
+        assert_eq!(assembler.recv(frame(Payload::Headers { priority: None, block: b"ab" },
+                                        Flag::end_stream(), 1)),
+                   Ok(None));
+        assert_eq!(assembler.stream(), Some(StreamIdentifier(1)));
+        assert_eq!(assembler.recv(frame(Payload::Continuation(b"cd"), Flag::empty(), 1)), Ok(None));
+
+        let block = assembler.recv(frame(Payload::Continuation(b"ef"), Flag::end_headers(), 1))
+                             .unwrap()
+                             .unwrap();
+        assert_eq!(block.block, b"abcdef".to_vec());
+        assert!(block.end_stream());
+        assert_eq!(assembler.stream(), None);
+    }
+
+    #[test]
+    fn test_push_promise() {
+        let mut assembler = HeaderBlockAssembler::new(100);
+        assembler.recv(frame(Payload::PushPromise { promised: StreamIdentifier(2), block: b"ab" },
+                             Flag::empty(), 1))
+                 .unwrap();
+        let block = assembler.recv(frame(Payload::Continuation(b"cd"), Flag::end_headers(), 1))
+                             .unwrap()
+                             .unwrap();
+        assert_eq!(block.promised, Some(StreamIdentifier(2)));
+        assert_eq!(block.block, b"abcd".to_vec());
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let mut assembler = HeaderBlockAssembler::new(100);
+        assembler.recv(frame(Payload::Headers { priority: None, block: b"ab" }, Flag::empty(), 1)).unwrap();
+
+        let ping = frame(Payload::Ping(0), Flag::empty(), 0);
+        assert_eq!(assembler.check(&ping.header), Err(HttpError::ProtocolError));
+        let other = frame(Payload::Continuation(b"cd"), Flag::end_headers(), 3);
+        assert_eq!(assembler.recv(other), Err(HttpError::ProtocolError));
+    }
+
+    #[test]
+    fn test_unexpected_continuation() {
+        let mut assembler = HeaderBlockAssembler::new(100);
+        assert_eq!(assembler.recv(frame(Payload::Continuation(b"ab"), Flag::end_headers(), 1)),
+                   Err(HttpError::ProtocolError));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut assembler = HeaderBlockAssembler::new(5);
+        assembler.recv(frame(Payload::Headers { priority: None, block: b"abc" }, Flag::empty(), 1)).unwrap();
+        assert_eq!(assembler.recv(frame(Payload::Continuation(b"def"), Flag::empty(), 1)),
+                   Err(HttpError::EnhanceYourCalm));
+    }
+
+    #[test]
+    fn test_header_list_size() {
+        let headers = vec![(b"name".to_vec(), b"value".to_vec()), (b"a".to_vec(), Vec::new())];
+        assert_eq!(header_list_size(&headers), 4 + 5 + 32 + 1 + 32);
+    }
+}
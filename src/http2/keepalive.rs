@@ -0,0 +1,133 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PING based keepalive (RFC 7540 section 6.7).
+//!
+//! A connection that has been idle for the ping interval gets a PING. If its ACK does not arrive
+//! within the timeout the peer is considered gone. The time the ACK took is the round-trip time.
+//!
+//! This module only keeps track of time; sending frames and closing the connection is up to the
+//! caller, which passes in the current time so the logic does not depend on a clock.
+
+use std::time::{Duration, Instant};
+
+/// How long to wait for a PING ACK unless configured otherwise.
+pub const DEFAULT_PING_TIMEOUT_SECS: u64 = 20;
+
+/// What the connection has to do next.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeepaliveAction {
+    /// Nothing until the given time.
+    Wait(Instant),
+    /// Send a PING with the given opaque data.
+    Ping(u64),
+    /// The PING was not acknowledged in time.
+    Expired,
+}
+
+#[derive(Debug)]
+pub struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    last_activity: Instant,
+    /// Opaque data and send time of the PING awaiting its ACK.
+    pending: Option<(u64, Instant)>,
+    next_data: u64,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, timeout: Duration, now: Instant) -> Keepalive {
+        Keepalive {
+            interval: interval,
+            timeout: timeout,
+            last_activity: now,
+            pending: None,
+            next_data: 1,
+        }
+    }
+
+    /// Records that a frame was received, which postpones the next PING.
+    pub fn recv(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Handles a PING ACK and returns the round-trip time if it answers our PING. ACKs with other
+    /// data are ignored.
+    pub fn ack(&mut self, data: u64, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some((pending, sent)) if pending == data => {
+                self.pending = None;
+                Some(if now > sent { now - sent } else { Duration::new(0, 0) })
+            },
+            _ => None,
+        }
+    }
+
+    /// Decides what to do at `now`. After `Ping` the PING counts as sent, so the next call
+    /// returns the time its ACK is due.
+    pub fn poll(&mut self, now: Instant) -> KeepaliveAction {
+        if let Some((_, sent)) = self.pending {
+            let due = sent + self.timeout;
+            return if now >= due { KeepaliveAction::Expired } else { KeepaliveAction::Wait(due) };
+        }
+
+        let next = self.last_activity + self.interval;
+        if now < next {
+            return KeepaliveAction::Wait(next);
+        }
+
+        let data = self.next_data;
+        self.next_data = self.next_data.wrapping_add(1);
+        self.pending = Some((data, now));
+        KeepaliveAction::Ping(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Keepalive, KeepaliveAction};
+
+    #[test]
+    fn test_ping_when_idle() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(Duration::from_secs(10), Duration::from_secs(5), start);
+        assert_eq!(keepalive.poll(start), KeepaliveAction::Wait(start + Duration::from_secs(10)));
+
+        // Activity postpones the PING.
+        keepalive.recv(start + Duration::from_secs(8));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(10)),
+                   KeepaliveAction::Wait(start + Duration::from_secs(18)));
+
+        let sent = start + Duration::from_secs(18);
+        assert_eq!(keepalive.poll(sent), KeepaliveAction::Ping(1));
+        assert_eq!(keepalive.poll(sent), KeepaliveAction::Wait(sent + Duration::from_secs(5)));
+
+        assert_eq!(keepalive.ack(2, sent + Duration::from_millis(30)), None);
+        assert_eq!(keepalive.ack(1, sent + Duration::from_millis(40)), Some(Duration::from_millis(40)));
+        assert_eq!(keepalive.ack(1, sent + Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn test_expired() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(Duration::from_secs(10), Duration::from_secs(5), start);
+        assert_eq!(keepalive.poll(start + Duration::from_secs(10)), KeepaliveAction::Ping(1));
+
+        // Other frames do not stand in for the ACK.
+        keepalive.recv(start + Duration::from_secs(12));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(15)), KeepaliveAction::Expired);
+    }
+}
@@ -18,7 +18,7 @@
 
 //! This library module provides HTTP/2 parsing and buffer frames used for HTTP/2.
 //!
-//! NB: This code is changing so please do not depend on it at this time!
+//! Built when the `http2` feature is enabled (default).
 
 
 pub const FRAME_HEADER_BYTES: usize = 9;
@@ -30,6 +30,17 @@ pub mod kind;
 pub mod flag;
 pub mod payload;
 pub mod frame;
+pub mod owned;
+pub mod codec;
+pub mod flow;
+pub mod settings;
+pub mod priority;
+pub mod header_block;
+pub mod stream;
+pub mod keepalive;
+pub mod connection;
+pub mod server;
+pub mod tls;
 
 use self::kind::*;
 use self::flag::*;
@@ -94,13 +105,6 @@ impl StreamIdentifier {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ErrorCode(pub u32);
 
-pub enum HttpError {
-    Protocol,
-    Internal,
-    FlowControlError,
-    SettingsTimeout,
-}
-
 impl ErrorCode {
     pub fn parse(buf: &[u8]) -> ErrorCode {
         ErrorCode(byteorder::BigEndian::read_u32(buf))
@@ -111,12 +115,87 @@ impl ErrorCode {
     }
 }
 
+/// The error codes of RST_STREAM and GOAWAY frames (RFC 7540 section 7).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HttpError {
+    /// Not an error, e.g. for a graceful shutdown.
+    NoError,
+    ProtocolError,
+    InternalError,
+    FlowControlError,
+    /// SETTINGS were not acknowledged in time.
+    SettingsTimeout,
+    /// A frame was received after the stream was half-closed.
+    StreamClosed,
+    FrameSizeError,
+    /// The stream was refused before any processing was done; the request can be retried.
+    RefusedStream,
+    /// The stream is no longer needed.
+    Cancel,
+    /// The HPACK context could not be kept in sync.
+    CompressionError,
+    /// The connection established by a CONNECT request was reset.
+    ConnectError,
+    /// The peer appears to be generating excessive load.
+    EnhanceYourCalm,
+    InadequateSecurity,
+    /// HTTP/1.1 must be used instead of HTTP/2.
+    Http11Required,
+    /// A code this implementation does not know. It must not trigger any special behavior.
+    Unknown(u32),
+}
+
+impl From<ErrorCode> for HttpError {
+    fn from(code: ErrorCode) -> HttpError {
+        match code.0 {
+            0x0 => HttpError::NoError,
+            0x1 => HttpError::ProtocolError,
+            0x2 => HttpError::InternalError,
+            0x3 => HttpError::FlowControlError,
+            0x4 => HttpError::SettingsTimeout,
+            0x5 => HttpError::StreamClosed,
+            0x6 => HttpError::FrameSizeError,
+            0x7 => HttpError::RefusedStream,
+            0x8 => HttpError::Cancel,
+            0x9 => HttpError::CompressionError,
+            0xa => HttpError::ConnectError,
+            0xb => HttpError::EnhanceYourCalm,
+            0xc => HttpError::InadequateSecurity,
+            0xd => HttpError::Http11Required,
+            code => HttpError::Unknown(code),
+        }
+    }
+}
+
+impl From<HttpError> for ErrorCode {
+    fn from(error: HttpError) -> ErrorCode {
+        ErrorCode(match error {
+            HttpError::NoError => 0x0,
+            HttpError::ProtocolError => 0x1,
+            HttpError::InternalError => 0x2,
+            HttpError::FlowControlError => 0x3,
+            HttpError::SettingsTimeout => 0x4,
+            HttpError::StreamClosed => 0x5,
+            HttpError::FrameSizeError => 0x6,
+            HttpError::RefusedStream => 0x7,
+            HttpError::Cancel => 0x8,
+            HttpError::CompressionError => 0x9,
+            HttpError::ConnectError => 0xa,
+            HttpError::EnhanceYourCalm => 0xb,
+            HttpError::InadequateSecurity => 0xc,
+            HttpError::Http11Required => 0xd,
+            HttpError::Unknown(code) => code,
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SizeIncrement(pub u32);
 
 impl SizeIncrement {
     pub fn parse(buf: &[u8]) -> SizeIncrement {
-        SizeIncrement(byteorder::BigEndian::read_u32(buf))
+        // The most significant bit is reserved.
+        SizeIncrement(byteorder::BigEndian::read_u32(buf) & ((1 << 31) - 1))
     }
 
     pub fn encode(&self, buf: &mut [u8]) -> usize {
@@ -144,3 +223,20 @@ pub fn encode_u64(buf: &mut [u8], val: u64) -> usize {
     byteorder::BigEndian::write_u64(buf, val);
     8
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorCode, HttpError};
+
+    #[test]
+    fn test_error_code_conversion() {
+        for code in 0..0xe {
+            let error = HttpError::from(ErrorCode(code));
+            assert!(error != HttpError::Unknown(code));
+            assert_eq!(ErrorCode::from(error), ErrorCode(code));
+        }
+        assert_eq!(HttpError::from(ErrorCode(0x1)), HttpError::ProtocolError);
+        assert_eq!(HttpError::from(ErrorCode(0xff)), HttpError::Unknown(0xff));
+        assert_eq!(ErrorCode::from(HttpError::Unknown(0xff)), ErrorCode(0xff));
+    }
+}
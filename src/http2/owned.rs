@@ -0,0 +1,372 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Owned HTTP/2 frames.
+//!
+//! `Frame` and `Payload` borrow from the buffer they were parsed from, which suits parsing in
+//! place. `OwnedFrame` holds its own data instead, can be built up frame kind by frame kind and
+//! encodes into a growable buffer, so it can be kept around, queued and sent later.
+
+use http2::kind::*;
+use http2::flag::*;
+use http2::frame::*;
+use http2::payload::*;
+
+use http2::ErrorCode;
+use http2::SizeIncrement;
+use http2::StreamIdentifier;
+use http2::FRAME_HEADER_BYTES;
+
+/// The owned counterpart of `Payload`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OwnedPayload {
+    Data {
+        data: Vec<u8>
+    },
+    Headers {
+        priority: Option<Priority>,
+        block: Vec<u8>
+    },
+    Priority(Priority),
+    Reset(ErrorCode),
+    /// The settings in wire format, see `Payload::Settings`.
+    Settings(Vec<u8>),
+    PushPromise {
+        promised: StreamIdentifier,
+        block: Vec<u8>
+    },
+    Ping(u64),
+    GoAway {
+        last: StreamIdentifier,
+        error: ErrorCode,
+        data: Vec<u8>
+    },
+    WindowUpdate(SizeIncrement),
+    Continuation(Vec<u8>),
+    Unregistered(Vec<u8>)
+}
+
+impl OwnedPayload {
+    /// Borrows the payload as a `Payload`, e.g. to encode it.
+    pub fn as_payload(&self) -> Payload {
+        match *self {
+            OwnedPayload::Data { ref data } => Payload::Data { data: data },
+            OwnedPayload::Headers { priority, ref block } => Payload::Headers { priority: priority, block: block },
+            OwnedPayload::Priority(priority) => Payload::Priority(priority),
+            OwnedPayload::Reset(error) => Payload::Reset(error),
+            OwnedPayload::Settings(ref settings) => Payload::Settings(settings),
+            OwnedPayload::PushPromise { promised, ref block } => {
+                Payload::PushPromise { promised: promised, block: block }
+            },
+            OwnedPayload::Ping(data) => Payload::Ping(data),
+            OwnedPayload::GoAway { last, error, ref data } => {
+                Payload::GoAway { last: last, error: error, data: data }
+            },
+            OwnedPayload::WindowUpdate(increment) => Payload::WindowUpdate(increment),
+            OwnedPayload::Continuation(ref block) => Payload::Continuation(block),
+            OwnedPayload::Unregistered(ref block) => Payload::Unregistered(block),
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.as_payload().kind()
+    }
+}
+
+impl<'a> From<Payload<'a>> for OwnedPayload {
+    fn from(payload: Payload<'a>) -> OwnedPayload {
+        match payload {
+            Payload::Data { data } => OwnedPayload::Data { data: data.to_vec() },
+            Payload::Headers { priority, block } => {
+                OwnedPayload::Headers { priority: priority, block: block.to_vec() }
+            },
+            Payload::Priority(priority) => OwnedPayload::Priority(priority),
+            Payload::Reset(error) => OwnedPayload::Reset(error),
+            Payload::Settings(settings) => OwnedPayload::Settings(settings.to_vec()),
+            Payload::PushPromise { promised, block } => {
+                OwnedPayload::PushPromise { promised: promised, block: block.to_vec() }
+            },
+            Payload::Ping(data) => OwnedPayload::Ping(data),
+            Payload::GoAway { last, error, data } => {
+                OwnedPayload::GoAway { last: last, error: error, data: data.to_vec() }
+            },
+            Payload::WindowUpdate(increment) => OwnedPayload::WindowUpdate(increment),
+            Payload::Continuation(block) => OwnedPayload::Continuation(block.to_vec()),
+            Payload::Unregistered(block) => OwnedPayload::Unregistered(block.to_vec()),
+        }
+    }
+}
+
+/// A frame that owns its payload.
+///
+/// Frames are built starting from the constructor for their kind, with flags and padding added
+/// builder-style:
+///
+/// ```ignore
+/// let frame = OwnedFrame::data(StreamIdentifier(1), body).end_stream().padded(16);
+/// frame.encode(&mut buf);
+/// ```
+///
+/// The frame header, including its length and the PADDED and PRIORITY flags, is derived from
+/// the payload when encoding.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OwnedFrame {
+    pub id: StreamIdentifier,
+    pub flag: Flag,
+    pub payload: OwnedPayload,
+    /// Number of padding bytes. Only DATA, HEADERS and PUSH_PROMISE frames can be padded; it is
+    /// ignored for the other kinds.
+    pub padding: Option<u8>,
+}
+
+impl OwnedFrame {
+    pub fn new(id: StreamIdentifier, payload: OwnedPayload) -> OwnedFrame {
+        OwnedFrame {
+            id: id,
+            flag: Flag::empty(),
+            payload: payload,
+            padding: None,
+        }
+    }
+
+    pub fn data(id: StreamIdentifier, data: Vec<u8>) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::Data { data: data })
+    }
+
+    pub fn headers(id: StreamIdentifier, block: Vec<u8>) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::Headers { priority: None, block: block })
+    }
+
+    pub fn priority(id: StreamIdentifier, priority: Priority) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::Priority(priority))
+    }
+
+    pub fn reset(id: StreamIdentifier, error: ErrorCode) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::Reset(error))
+    }
+
+    pub fn settings(settings: &[Setting]) -> OwnedFrame {
+        OwnedFrame::new(StreamIdentifier(0), OwnedPayload::Settings(Setting::encode_all(settings)))
+    }
+
+    pub fn settings_ack() -> OwnedFrame {
+        OwnedFrame::settings(&[]).ack()
+    }
+
+    pub fn push_promise(id: StreamIdentifier, promised: StreamIdentifier, block: Vec<u8>) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::PushPromise { promised: promised, block: block })
+    }
+
+    pub fn ping(data: u64) -> OwnedFrame {
+        OwnedFrame::new(StreamIdentifier(0), OwnedPayload::Ping(data))
+    }
+
+    pub fn go_away(last: StreamIdentifier, error: ErrorCode, data: Vec<u8>) -> OwnedFrame {
+        OwnedFrame::new(StreamIdentifier(0), OwnedPayload::GoAway { last: last, error: error, data: data })
+    }
+
+    pub fn window_update(id: StreamIdentifier, increment: SizeIncrement) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::WindowUpdate(increment))
+    }
+
+    pub fn continuation(id: StreamIdentifier, block: Vec<u8>) -> OwnedFrame {
+        OwnedFrame::new(id, OwnedPayload::Continuation(block))
+    }
+
+    pub fn end_stream(mut self) -> OwnedFrame {
+        self.flag = self.flag | Flag::end_stream();
+        self
+    }
+
+    pub fn end_headers(mut self) -> OwnedFrame {
+        self.flag = self.flag | Flag::end_headers();
+        self
+    }
+
+    pub fn ack(mut self) -> OwnedFrame {
+        self.flag = self.flag | Flag::ack();
+        self
+    }
+
+    /// Sets the priority of a HEADERS frame.
+    pub fn with_priority(mut self, priority: Priority) -> OwnedFrame {
+        if let OwnedPayload::Headers { priority: ref mut current, .. } = self.payload {
+            *current = Some(priority);
+        }
+        self
+    }
+
+    /// Pads the frame with `len` zero bytes, e.g. to hide the size of its content.
+    pub fn padded(mut self, len: u8) -> OwnedFrame {
+        self.padding = Some(len);
+        self
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.payload.kind()
+    }
+
+    /// The header the frame is sent with.
+    pub fn header(&self) -> FrameHeader {
+        let mut flag = self.flag;
+        if self.padding().is_some() {
+            flag = flag | Flag::padded();
+        }
+        if self.payload.as_payload().priority().is_some() && self.kind() == Kind::Headers {
+            flag = flag | Flag::priority();
+        }
+
+        FrameHeader {
+            length: self.payload_len() as u32,
+            kind: self.kind(),
+            flag: flag,
+            id: self.id,
+        }
+    }
+
+    /// How many bytes the frame takes up when encoded.
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER_BYTES + self.payload_len()
+    }
+
+    /// Appends the encoded frame to `buf` and returns the number of bytes written.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> usize {
+        let start = buf.len();
+        let len = self.encoded_len();
+        // The padding is already zeroed by the resize.
+        buf.resize(start + len, 0);
+
+        self.header().encode(&mut buf[start..]);
+        let mut pos = start + FRAME_HEADER_BYTES;
+        if let Some(padding) = self.padding() {
+            buf[pos] = padding;
+            pos += 1;
+        }
+        self.payload.as_payload().encode(&mut buf[pos..]);
+        len
+    }
+
+    fn padding(&self) -> Option<u8> {
+        match self.kind() {
+            Kind::Data | Kind::Headers | Kind::PushPromise => self.padding,
+            _ => None,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        let padding = self.padding().map(|len| len as usize + 1).unwrap_or(0);
+        self.payload.as_payload().encoded_len() + padding
+    }
+}
+
+impl<'a> From<Frame<'a>> for OwnedFrame {
+    fn from(frame: Frame<'a>) -> OwnedFrame {
+        let payload = OwnedPayload::from(frame.payload);
+        // Parsing strips the padding; what is left of the frame's length is the padding plus
+        // its length byte.
+        let padding = if frame.header.flag.contains(Flag::padded()) {
+            Some((frame.header.length as usize - 1 - frame.payload.encoded_len()) as u8)
+        } else {
+            None
+        };
+        let flag = frame.header.flag - Flag::padded() - Flag::priority();
+
+        OwnedFrame {
+            id: frame.header.id,
+            flag: flag,
+            payload: payload,
+            padding: padding,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::kind::*;
+    use http2::flag::*;
+    use http2::frame::*;
+    use http2::payload::*;
+
+    use http2::ErrorCode;
+    use http2::SizeIncrement;
+    use http2::StreamIdentifier;
+    use http2::FRAME_HEADER_BYTES;
+    use super::{OwnedFrame, OwnedPayload};
+
+    // Encodes the frame, parses it back and checks that nothing was lost on the way.
+    fn roundtrip(frame: OwnedFrame) -> Vec<u8> {
+        let mut buf = b"xyz".to_vec();
+        assert_eq!(frame.encode(&mut buf), frame.encoded_len());
+        assert_eq!(buf.len(), 3 + frame.encoded_len());
+
+        let buf = buf.split_off(3);
+        let header = FrameHeader::parse(&buf).unwrap();
+        assert_eq!(header, frame.header());
+        let parsed = Frame::parse(header, &buf[FRAME_HEADER_BYTES..]).unwrap();
+        assert_eq!(OwnedFrame::from(parsed), frame);
+        buf
+    }
+
+    #[test]
+    fn test_every_kind() {
+        let id = StreamIdentifier(1);
+        let priority = Priority::new(true, StreamIdentifier(3), 15);
+
+        roundtrip(OwnedFrame::data(id, b"hello".to_vec()).end_stream());
+        roundtrip(OwnedFrame::headers(id, b"\x82\x84".to_vec()).end_headers());
+        roundtrip(OwnedFrame::headers(id, b"\x82".to_vec()).with_priority(priority));
+        roundtrip(OwnedFrame::priority(id, priority));
+        roundtrip(OwnedFrame::reset(id, ErrorCode(0x8)));
+        roundtrip(OwnedFrame::settings(&[Setting::new(SettingIdentifier::EnablePush, 0)]));
+        roundtrip(OwnedFrame::settings_ack());
+        roundtrip(OwnedFrame::push_promise(id, StreamIdentifier(2), b"\x82".to_vec()).end_headers());
+        roundtrip(OwnedFrame::ping(42).ack());
+        roundtrip(OwnedFrame::go_away(StreamIdentifier(7), ErrorCode(0x1), b"debug".to_vec()));
+        roundtrip(OwnedFrame::window_update(id, SizeIncrement(1024)));
+        roundtrip(OwnedFrame::continuation(id, b"\x84".to_vec()).end_headers());
+    }
+
+    #[test]
+    fn test_priority_flag() {
+        let priority = Priority::new(false, StreamIdentifier(0), 255);
+        let frame = OwnedFrame::headers(StreamIdentifier(1), Vec::new()).with_priority(priority);
+        assert!(frame.header().flag.contains(Flag::priority()));
+        assert_eq!(frame.header().length, 5);
+    }
+
+    #[test]
+    fn test_padding() {
+        let buf = roundtrip(OwnedFrame::data(StreamIdentifier(1), b"hello".to_vec()).padded(3));
+        assert_eq!(&buf[FRAME_HEADER_BYTES..], &[3, b'h', b'e', b'l', b'l', b'o', 0, 0, 0][..]);
+
+        roundtrip(OwnedFrame::headers(StreamIdentifier(1), b"\x82".to_vec())
+                      .with_priority(Priority::new(false, StreamIdentifier(0), 15))
+                      .padded(4));
+        roundtrip(OwnedFrame::push_promise(StreamIdentifier(1), StreamIdentifier(2), b"\x82".to_vec())
+                      .padded(0));
+    }
+
+    #[test]
+    fn test_padding_ignored() {
+        let frame = OwnedFrame::ping(1).padded(10);
+        assert_eq!(frame.header().length, 8);
+        assert!(!frame.header().flag.contains(Flag::padded()));
+    }
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(OwnedFrame::window_update(StreamIdentifier(0), SizeIncrement(1)).kind(), Kind::WindowUpdate);
+        assert_eq!(OwnedPayload::Unregistered(Vec::new()).kind(), Kind::Unregistered);
+    }
+}
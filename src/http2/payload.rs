@@ -14,8 +14,6 @@
 
 //! NB: This code is changing so please do not depend on it at this time!
 
-use std::{slice, mem, fmt};
-
 use byteorder::ByteOrder;
 
 use http2::kind::*;
@@ -40,7 +38,8 @@ pub enum Payload<'a> {
     },
     Priority(Priority),
     Reset(ErrorCode),
-    Settings(&'a [Setting]),
+    /// The settings in wire format; `Setting::iter` decodes them.
+    Settings(&'a [u8]),
     PushPromise {
         promised: StreamIdentifier,
         block: &'a [u8]
@@ -111,6 +110,10 @@ impl<'a> Payload<'a> {
             Kind::Data => Payload::parse_data(header, buf, settings),
             Kind::Headers => Payload::parse_headers(header, buf, settings),
             Kind::Priority => {
+                if header.length != PRIORITY_BYTES {
+                    return Err(Error::InvalidPayloadLength)
+                }
+
                 let (_, priority) = try!(Priority::parse(true, buf));
                 Ok(Payload::Priority(priority.unwrap()))
             },
@@ -135,9 +138,7 @@ impl<'a> Payload<'a> {
                 priority_wrote + block_wrote
             },
             Payload::Reset(ref err) => { err.encode(buf) },
-            Payload::Settings(ref settings) => {
-                encode_memory(Setting::to_bytes(settings), buf)
-            },
+            Payload::Settings(ref settings) => { encode_memory(settings, buf) },
             Payload::Ping(data) => { encode_u64(buf, data) },
             Payload::GoAway { ref data, ref last, ref error } => {
                 let last_wrote = last.encode(buf);
@@ -171,7 +172,7 @@ impl<'a> Payload<'a> {
                 priority_len + block.len()
             },
             Reset(_) => 4,
-            Settings(ref settings) => settings.len() * mem::size_of::<Setting>(),
+            Settings(ref settings) => settings.len(),
             Ping(_) => 8,
             GoAway { ref data, .. } => 4 + 4 + data.len(),
             WindowUpdate(_) => 4,
@@ -228,11 +229,11 @@ impl<'a> Payload<'a> {
     #[inline]
     fn parse_settings(header: FrameHeader,
                       buf: &'a [u8]) -> Result<Payload<'a>, Error> {
-        if header.length % mem::size_of::<Setting>() as u32 != 0 {
+        if header.length % SETTING_BYTES as u32 != 0 {
             return Err(Error::PartialSettingLength)
         }
 
-        Ok(Payload::Settings(Setting::from_bytes(&buf[..header.length as usize])))
+        Ok(Payload::Settings(&buf[..header.length as usize]))
     }
 
     #[inline]
@@ -301,9 +302,39 @@ pub struct Priority {
 }
 
 impl Priority {
+    #[inline]
+    pub fn new(exclusive: bool, dependency: StreamIdentifier, weight: u8) -> Priority {
+        Priority {
+            exclusive: exclusive,
+            dependency: dependency,
+            weight: weight
+        }
+    }
+
+    /// Whether the stream becomes the sole dependency of its parent.
+    #[inline]
+    pub fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    #[inline]
+    pub fn dependency(&self) -> StreamIdentifier {
+        self.dependency
+    }
+
+    /// The weight as sent on the wire, i.e. one less than the actual weight (1 to 256).
+    #[inline]
+    pub fn weight(&self) -> u8 {
+        self.weight
+    }
+
     #[inline]
     pub fn parse(present: bool, buf: &[u8]) -> Result<(&[u8], Option<Priority>), Error> {
         if present {
+            if buf.len() < PRIORITY_BYTES as usize {
+                return Err(Error::PayloadLengthTooShort)
+            }
+
             Ok((&buf[5..], Some(Priority {
                 // Most significant bit.
                 exclusive: buf[0] & 0x7F != buf[0],
@@ -327,26 +358,27 @@ impl Priority {
     }
 }
 
-// Settings are (u16, u32) in memory.
-#[repr(packed)]
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+/// Size of a single setting on the wire: a 16-bit identifier and a 32-bit value.
+pub const SETTING_BYTES: usize = 6;
+
+/// A single setting of a SETTINGS frame. Identifiers this implementation does not know are kept
+/// as they are, so they can be passed on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Setting {
     identifier: u16,
     value: u32
 }
 
-impl fmt::Debug for Setting {
+impl Setting {
     #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(&self.identifier(), f)
+    pub fn new(identifier: SettingIdentifier, value: u32) -> Setting {
+        Setting::from_raw(identifier as u16, value)
     }
-}
 
-impl Setting {
     #[inline]
-    pub fn new(identifier: SettingIdentifier, value: u32) -> Setting {
+    pub fn from_raw(identifier: u16, value: u32) -> Setting {
         Setting {
-            identifier: identifier as u16,
+            identifier: identifier,
             value: value,
         }
     }
@@ -359,31 +391,71 @@ impl Setting {
             0x3 => Some(SettingIdentifier::MaxConcurrentStreams),
             0x4 => Some(SettingIdentifier::InitialWindowSize),
             0x5 => Some(SettingIdentifier::MaxFrameSize),
+            0x6 => Some(SettingIdentifier::MaxHeaderListSize),
             _ => None
         }
     }
 
+    /// The identifier as sent on the wire, including unknown ones.
+    #[inline]
+    pub fn raw_identifier(&self) -> u16 {
+        self.identifier
+    }
+
     #[inline]
     pub fn value(&self) -> u32 {
         self.value
     }
 
+    /// Reads a setting from the first `SETTING_BYTES` of `buf`.
+    #[inline]
+    pub fn parse(buf: &[u8]) -> Setting {
+        Setting {
+            identifier: ::byteorder::BigEndian::read_u16(buf),
+            value: ::byteorder::BigEndian::read_u32(&buf[2..]),
+        }
+    }
+
     #[inline]
-    fn to_bytes(settings: &[Setting]) -> &[u8] {
-        unsafe {
-            slice::from_raw_parts(
-                settings.as_ptr() as *const u8,
-                settings.len() * mem::size_of::<Setting>())
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        ::byteorder::BigEndian::write_u16(buf, self.identifier);
+        ::byteorder::BigEndian::write_u32(&mut buf[2..], self.value);
+        SETTING_BYTES
+    }
+
+    /// Encodes a list of settings as a SETTINGS payload.
+    pub fn encode_all(settings: &[Setting]) -> Vec<u8> {
+        let mut buf = vec![0; settings.len() * SETTING_BYTES];
+        for (setting, chunk) in settings.iter().zip(buf.chunks_mut(SETTING_BYTES)) {
+            setting.encode(chunk);
         }
+        buf
     }
 
+    /// Iterates over the settings of a SETTINGS payload. A trailing partial setting is ignored;
+    /// parsing the frame already rejects those.
     #[inline]
-    fn from_bytes(bytes: &[u8]) -> &[Setting] {
-        unsafe {
-            slice::from_raw_parts(
-                bytes.as_ptr() as *const Setting,
-                bytes.len() / mem::size_of::<Setting>())
+    pub fn iter(buf: &[u8]) -> SettingIter {
+        SettingIter { buf: buf }
+    }
+}
+
+/// Iterator over the settings of a SETTINGS payload, see `Setting::iter`.
+#[derive(Clone, Debug)]
+pub struct SettingIter<'a> {
+    buf: &'a [u8]
+}
+
+impl<'a> Iterator for SettingIter<'a> {
+    type Item = Setting;
+
+    fn next(&mut self) -> Option<Setting> {
+        if self.buf.len() < SETTING_BYTES {
+            return None;
         }
+        let setting = Setting::parse(self.buf);
+        self.buf = &self.buf[SETTING_BYTES..];
+        Some(setting)
     }
 }
 
@@ -394,7 +466,8 @@ pub enum SettingIdentifier {
     EnablePush = 0x2,
     MaxConcurrentStreams = 0x3,
     InitialWindowSize = 0x4,
-    MaxFrameSize = 0x5
+    MaxFrameSize = 0x5,
+    MaxHeaderListSize = 0x6
 }
 
 /*
@@ -410,7 +483,8 @@ fn trim_padding(settings: ParserSettings, header: FrameHeader,
                 buf: &[u8]) -> Result<&[u8], Error> {
     if settings.padding {
         let pad_length = buf[0];
-        if pad_length as u32 > header.length {
+        // The Pad Length field itself counts towards the payload length.
+        if pad_length as u32 + PADDING_BYTES > header.length {
             Err(Error::TooMuchPadding(pad_length))
         } else {
             Ok(&buf[1..header.length as usize - pad_length as usize])
@@ -0,0 +1,404 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stream dependency tree (RFC 7540 section 5.3).
+//!
+//! The tree decides which stream gets to send the next DATA frame. A stream only gets to send
+//! when none of its ancestors can, and siblings share in proportion to their weights. Sharing is
+//! done with stride scheduling: every node keeps a virtual time (`pass`) that advances by the
+//! number of bytes sent divided by its weight, and the eligible sibling with the lowest time
+//! goes next.
+//!
+//! Streams that are not open (idle streams named by PRIORITY frames or as dependencies, closed
+//! streams that are reprioritized late) only keep a node while there are few of them; past
+//! `MAX_IDLE_NODES` the oldest are dropped, as section 5.3.4 allows.
+
+use std::collections::{HashMap, VecDeque};
+
+use http2::HttpError;
+use http2::StreamIdentifier;
+use http2::payload::Priority;
+
+/// Weight of a stream that did not say otherwise.
+pub const DEFAULT_WEIGHT: u16 = 16;
+
+/// Most nodes kept for streams that are not open.
+pub const MAX_IDLE_NODES: usize = 256;
+
+/// Scales the virtual time so a full frame on a weight 256 stream still advances it.
+const STRIDE: u64 = 256;
+
+#[derive(Clone, Debug)]
+struct Node {
+    parent: u32,
+    /// Actual weight, 1 to 256.
+    weight: u16,
+    children: Vec<u32>,
+    pass: u64,
+    /// Whether the stream was opened, rather than only named in a priority.
+    open: bool,
+}
+
+impl Node {
+    fn new(parent: u32, weight: u16) -> Node {
+        Node {
+            parent: parent,
+            weight: weight,
+            children: Vec::new(),
+            pass: 0,
+            open: false,
+        }
+    }
+}
+
+/// The dependency tree of a connection. Stream 0 is the root and always present.
+#[derive(Clone, Debug)]
+pub struct PriorityTree {
+    nodes: HashMap<u32, Node>,
+    /// Streams given a node while not open, oldest first. Entries for streams that were opened
+    /// or removed since are skipped when the oldest are dropped.
+    idle: VecDeque<u32>,
+}
+
+impl PriorityTree {
+    pub fn new() -> PriorityTree {
+        let mut root = Node::new(0, DEFAULT_WEIGHT);
+        root.open = true;
+        let mut nodes = HashMap::new();
+        nodes.insert(0, root);
+        PriorityTree { nodes: nodes, idle: VecDeque::new() }
+    }
+
+    pub fn contains(&self, id: StreamIdentifier) -> bool {
+        self.nodes.contains_key(&id.0)
+    }
+
+    /// The stream the given one depends on, or `None` if it is not in the tree.
+    pub fn parent(&self, id: StreamIdentifier) -> Option<StreamIdentifier> {
+        match id.0 {
+            0 => None,
+            _ => self.nodes.get(&id.0).map(|node| StreamIdentifier(node.parent)),
+        }
+    }
+
+    /// The actual weight (1 to 256) of the stream, or `None` if it is not in the tree.
+    pub fn weight(&self, id: StreamIdentifier) -> Option<u16> {
+        self.nodes.get(&id.0).map(|node| node.weight)
+    }
+
+    /// Adds a stream opened by HEADERS, with the priority the frame carried if any. A stream
+    /// that is already in the tree (e.g. because of an earlier PRIORITY frame) keeps its place
+    /// unless a new priority is given.
+    pub fn insert(&mut self, id: StreamIdentifier, priority: Option<Priority>) -> Result<(), HttpError> {
+        match priority {
+            Some(priority) => try!(self.reprioritize(id, priority)),
+            None => self.ensure(id.0),
+        }
+        self.nodes.get_mut(&id.0).unwrap().open = true;
+        Ok(())
+    }
+
+    /// Applies a PRIORITY frame, or the priority of a HEADERS frame, to a stream.
+    pub fn reprioritize(&mut self, id: StreamIdentifier, priority: Priority) -> Result<(), HttpError> {
+        let id = id.0;
+        let dependency = priority.dependency().0;
+        if id == 0 || id == dependency {
+            return Err(HttpError::ProtocolError);
+        }
+
+        self.ensure(id);
+        // A dependency on a stream that is not in the tree gives that stream default priority.
+        self.ensure(dependency);
+
+        // If the new parent depends on the stream, it is first moved up to where the stream
+        // currently is (section 5.3.3).
+        if self.is_descendant(dependency, id) {
+            let parent = self.nodes[&id].parent;
+            self.detach(dependency);
+            self.attach(dependency, parent);
+        }
+
+        self.detach(id);
+        if priority.exclusive() {
+            let children = {
+                let node = self.nodes.get_mut(&dependency).unwrap();
+                ::std::mem::replace(&mut node.children, Vec::new())
+            };
+            for &child in &children {
+                self.nodes.get_mut(&child).unwrap().parent = id;
+            }
+            self.nodes.get_mut(&id).unwrap().children.extend(children);
+        }
+        self.nodes.get_mut(&id).unwrap().weight = priority.weight() as u16 + 1;
+        self.attach(id, dependency);
+
+        // The two streams just added are the newest, so they are not the ones dropped.
+        while self.idle.len() > MAX_IDLE_NODES {
+            let oldest = self.idle.pop_front().unwrap();
+            if self.nodes.get(&oldest).map_or(false, |node| !node.open) {
+                self.remove(StreamIdentifier(oldest));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a closed stream. Its children move up to its parent and share its weight in
+    /// proportion to their own (section 5.3.4).
+    pub fn remove(&mut self, id: StreamIdentifier) {
+        if id.0 == 0 {
+            return;
+        }
+        let node = match self.nodes.remove(&id.0) {
+            Some(node) => node,
+            None => return,
+        };
+        self.nodes.get_mut(&node.parent).unwrap().children.retain(|&child| child != id.0);
+
+        let total: u32 = node.children.iter().map(|child| self.nodes[child].weight as u32).sum();
+        for &child in &node.children {
+            {
+                let child = self.nodes.get_mut(&child).unwrap();
+                let weight = child.weight as u32 * node.weight as u32 / total;
+                child.weight = if weight == 0 { 1 } else { weight as u16 };
+            }
+            self.attach(child, node.parent);
+        }
+    }
+
+    /// Picks the stream that should send next among those for which `ready` returns true.
+    pub fn next<F>(&self, ready: F) -> Option<StreamIdentifier>
+        where F: Fn(StreamIdentifier) -> bool
+    {
+        self.pick(&ready).map(StreamIdentifier)
+    }
+
+    /// Accounts for `len` bytes sent on the stream, for it and all its ancestors.
+    pub fn charge(&mut self, id: StreamIdentifier, len: usize) {
+        let mut id = id.0;
+        while id != 0 {
+            let node = match self.nodes.get_mut(&id) {
+                Some(node) => node,
+                None => return,
+            };
+            node.pass += len as u64 * STRIDE / node.weight as u64;
+            id = node.parent;
+        }
+    }
+
+    /// Walks the tree depth first, siblings in the order of their virtual time, and returns the
+    /// first ready stream. The peer decides how deep the tree is, so the walk keeps its own stack.
+    fn pick<F>(&self, ready: &F) -> Option<u32>
+        where F: Fn(StreamIdentifier) -> bool
+    {
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            if id != 0 && ready(StreamIdentifier(id)) {
+                return Some(id);
+            }
+            // The stream itself cannot send, so its dependents may.
+            let mut children: Vec<(u64, u32)> = self.nodes[&id].children
+                                                    .iter()
+                                                    .map(|&child| (self.nodes[&child].pass, child))
+                                                    .collect();
+            children.sort();
+            stack.extend(children.into_iter().rev().map(|(_, child)| child));
+        }
+        None
+    }
+
+    /// Adds the stream below the root with default priority if it is not in the tree yet.
+    fn ensure(&mut self, id: u32) {
+        if !self.nodes.contains_key(&id) {
+            self.nodes.insert(id, Node::new(0, DEFAULT_WEIGHT));
+            self.attach(id, 0);
+            self.idle.push_back(id);
+        }
+    }
+
+    fn is_descendant(&self, mut id: u32, ancestor: u32) -> bool {
+        while id != 0 {
+            id = self.nodes[&id].parent;
+            if id == ancestor {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn detach(&mut self, id: u32) {
+        let parent = self.nodes[&id].parent;
+        self.nodes.get_mut(&parent).unwrap().children.retain(|&child| child != id);
+    }
+
+    fn attach(&mut self, id: u32, parent: u32) {
+        // Start level with the siblings rather than at zero, or a new stream would get the
+        // parent's whole share until it caught up with them.
+        let pass = self.nodes[&parent].children
+                       .iter()
+                       .map(|child| self.nodes[child].pass)
+                       .min()
+                       .unwrap_or(0);
+
+        self.nodes.get_mut(&parent).unwrap().children.push(id);
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.parent = parent;
+        node.pass = pass;
+    }
+}
+
+impl Default for PriorityTree {
+    fn default() -> PriorityTree {
+        PriorityTree::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use http2::HttpError;
+    use http2::StreamIdentifier;
+    use http2::payload::Priority;
+    use super::{PriorityTree, DEFAULT_WEIGHT, MAX_IDLE_NODES};
+
+    fn priority(exclusive: bool, dependency: u32, weight: u16) -> Option<Priority> {
+        Some(Priority::new(exclusive, StreamIdentifier(dependency), (weight - 1) as u8))
+    }
+
+    fn parent(tree: &PriorityTree, id: u32) -> u32 {
+        tree.parent(StreamIdentifier(id)).unwrap().0
+    }
+
+    #[test]
+    fn test_default_priority() {
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), None).unwrap();
+        assert_eq!(parent(&tree, 1), 0);
+        assert_eq!(tree.weight(StreamIdentifier(1)), Some(DEFAULT_WEIGHT));
+    }
+
+    #[test]
+    fn test_exclusive() {
+        // RFC 7540 section 5.3.1: D becomes the sole child of A.
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), None).unwrap();
+        tree.insert(StreamIdentifier(3), priority(false, 1, 16)).unwrap();
+        tree.insert(StreamIdentifier(5), priority(false, 1, 16)).unwrap();
+        tree.insert(StreamIdentifier(7), priority(true, 1, 32)).unwrap();
+
+        assert_eq!(parent(&tree, 7), 1);
+        assert_eq!(parent(&tree, 3), 7);
+        assert_eq!(parent(&tree, 5), 7);
+        assert_eq!(tree.weight(StreamIdentifier(7)), Some(32));
+    }
+
+    #[test]
+    fn test_reprioritize_onto_descendant() {
+        // RFC 7540 section 5.3.3: A is made to depend on its descendant D.
+        let (a, b, c, d, e, f) = (1, 3, 5, 7, 9, 11);
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(a), None).unwrap();
+        tree.insert(StreamIdentifier(b), priority(false, a, 16)).unwrap();
+        tree.insert(StreamIdentifier(c), priority(false, a, 16)).unwrap();
+        tree.insert(StreamIdentifier(d), priority(false, c, 16)).unwrap();
+        tree.insert(StreamIdentifier(e), priority(false, c, 16)).unwrap();
+        tree.insert(StreamIdentifier(f), priority(false, d, 16)).unwrap();
+
+        tree.reprioritize(StreamIdentifier(a), priority(false, d, 16).unwrap()).unwrap();
+        assert_eq!(parent(&tree, d), 0);
+        assert_eq!(parent(&tree, a), d);
+        assert_eq!(parent(&tree, f), d);
+        assert_eq!(parent(&tree, b), a);
+        assert_eq!(parent(&tree, c), a);
+        assert_eq!(parent(&tree, e), c);
+
+        let mut tree = tree.clone();
+        tree.reprioritize(StreamIdentifier(a), priority(true, d, 16).unwrap()).unwrap();
+        assert_eq!(parent(&tree, f), a);
+    }
+
+    #[test]
+    fn test_self_dependency() {
+        let mut tree = PriorityTree::new();
+        assert_eq!(tree.insert(StreamIdentifier(1), priority(false, 1, 16)), Err(HttpError::ProtocolError));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), priority(false, 0, 64)).unwrap();
+        tree.insert(StreamIdentifier(3), priority(false, 1, 30)).unwrap();
+        tree.insert(StreamIdentifier(5), priority(false, 1, 10)).unwrap();
+
+        tree.remove(StreamIdentifier(1));
+        assert!(!tree.contains(StreamIdentifier(1)));
+        assert_eq!(parent(&tree, 3), 0);
+        assert_eq!(parent(&tree, 5), 0);
+        assert_eq!(tree.weight(StreamIdentifier(3)), Some(48));
+        assert_eq!(tree.weight(StreamIdentifier(5)), Some(16));
+    }
+
+    #[test]
+    fn test_dependents_wait_for_parent() {
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), None).unwrap();
+        tree.insert(StreamIdentifier(3), priority(false, 1, 256)).unwrap();
+
+        assert_eq!(tree.next(|_| true), Some(StreamIdentifier(1)));
+        assert_eq!(tree.next(|id| id.0 == 3), Some(StreamIdentifier(3)));
+        assert_eq!(tree.next(|_| false), None);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), priority(false, 0, 200)).unwrap();
+        tree.insert(StreamIdentifier(3), priority(false, 0, 50)).unwrap();
+
+        let mut sent = HashMap::new();
+        for _ in 0..100 {
+            let id = tree.next(|_| true).unwrap();
+            tree.charge(id, 1000);
+            *sent.entry(id.0).or_insert(0) += 1;
+        }
+        assert_eq!(sent[&1], 80);
+        assert_eq!(sent[&3], 20);
+    }
+
+    #[test]
+    fn test_idle_nodes_are_capped() {
+        let mut tree = PriorityTree::new();
+        tree.insert(StreamIdentifier(1), None).unwrap();
+        // Each idle stream depends on the one before it, down from the open stream.
+        for i in 1..10000 {
+            tree.reprioritize(StreamIdentifier(2 * i + 1), priority(false, 2 * i - 1, 16).unwrap()).unwrap();
+        }
+        assert!(tree.contains(StreamIdentifier(1)));
+        assert!(tree.contains(StreamIdentifier(19999)));
+        assert!(!tree.contains(StreamIdentifier(3)));
+        assert_eq!(tree.nodes.len(), MAX_IDLE_NODES + 2);
+    }
+
+    #[test]
+    fn test_deep_tree() {
+        let mut tree = PriorityTree::new();
+        // Each stream takes the one before it as its only child.
+        for i in 0..100000 {
+            tree.insert(StreamIdentifier(2 * i + 1), priority(true, 0, 16)).unwrap();
+        }
+        assert_eq!(parent(&tree, 1), 3);
+        assert_eq!(tree.next(|id| id.0 == 1), Some(StreamIdentifier(1)));
+        assert_eq!(tree.next(|_| false), None);
+    }
+}
@@ -0,0 +1,709 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/2 server protocol for tokio-proto.
+//!
+//! HTTP/2 streams map directly onto tokio-proto's multiplex request ids, so `Http2Proto`
+//! implements `multiplex::ServerProto` and can be handed to `TcpServer` the same way as
+//! `HttpProto`. Handlers and the `Router` are shared between both protocols.
+//!
+//! A cleartext connection may start out as HTTP/1.x. The transport looks at the first bytes:
+//! the HTTP/2 connection preface means the client has prior knowledge of HTTP/2, anything else
+//! is served as HTTP/1.x until a request asks to upgrade with `Upgrade: h2c` (RFC 7540
+//! section 3.2). HTTP/1.x requests get increasing request ids and their responses are written
+//! back in request order.
+//!
+//! Being a plain multiplex proto, the transport only hands out requests whose body has been read
+//! in full, and takes responses with their body in a buffer. That holds for the HTTP/1.x
+//! fallback too: only `HttpProto` streams request and response bodies.
+//!
+//! With `Http2Config::ping_interval` set, idle HTTP/2 connections are checked with PING frames
+//! and closed if the client stops answering.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{task, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use rustc_serialize::base64::FromBase64;
+use tokio_core::io::{Codec, EasyBuf, Io};
+use tokio_core::net::TcpStream;
+use tokio_proto::multiplex::{RequestId, ServerProto};
+use tokio_timer::{Sleep, Timer};
+use unicase::UniCase;
+
+use http::HttpCodec;
+use http2::StreamIdentifier;
+use http2::connection::{Connection, DEFAULT_MAX_CONCURRENT_STREAMS, PREFACE};
+use http2::settings::Settings;
+use http2::header_block::DEFAULT_MAX_HEADER_LIST_SIZE;
+use http2::keepalive::DEFAULT_PING_TIMEOUT_SECS;
+use hpack::IndexingPolicy;
+use HttpVersion;
+use Request;
+use Response;
+use Router;
+use Logger;
+
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Response that switches an upgraded connection over to HTTP/2.
+const SWITCHING_PROTOCOLS: &'static [u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+                                              Connection: Upgrade\r\n\
+                                              Upgrade: h2c\r\n\r\n";
+
+/// Proto for serving cleartext HTTP/2 (h2c) connections, either with prior knowledge or upgraded
+/// from HTTP/1.1. Connections that never upgrade are served as HTTP/1.x.
+///
+/// Request bodies are buffered whole before the service is called, and responses are sent from
+/// their `body` buffer, including on connections served as HTTP/1.x. Use `HttpProto` where
+/// bodies have to be streamed.
+///
+/// Create it with `new` and set the public fields afterwards.
+pub struct Http2Proto {
+    pub logger: Option<Logger>,
+    pub router: Option<Router>,
+    /// Settings for the connections served as HTTP/2.
+    pub http2: Http2Config,
+    timer: Timer,
+}
+
+/// HTTP/2 connection settings, shared by `Http2Proto` and `HttpsProto`.
+#[derive(Clone, Default)]
+pub struct Http2Config {
+    /// Limit for the size of request header lists. Defaults to
+    /// `http2::header_block::DEFAULT_MAX_HEADER_LIST_SIZE`.
+    pub max_header_list_size: Option<u32>,
+    /// Idle time after which a PING checks that the client is still there. Keepalive is off if
+    /// `None`.
+    pub ping_interval: Option<Duration>,
+    /// How long to wait for the PING ACK before closing the connection. Defaults to
+    /// `http2::keepalive::DEFAULT_PING_TIMEOUT_SECS`.
+    pub ping_timeout: Option<Duration>,
+    /// Overrides for how response header fields are compressed, by header name. Credentials
+    /// and cookies are never indexed unless overridden here.
+    pub indexing_policies: Vec<(String, IndexingPolicy)>,
+}
+
+impl Http2Config {
+    /// Creates a connection that announces and enforces these settings.
+    pub fn connection(&self,
+                      version: HttpVersion,
+                      remote_addr: Option<SocketAddr>,
+                      router: Option<Router>,
+                      logger: Option<Logger>)
+                      -> Connection {
+        let mut settings = Settings::default();
+        settings.max_header_list_size = Some(self.max_header_list_size.unwrap_or(DEFAULT_MAX_HEADER_LIST_SIZE));
+        settings.max_concurrent_streams = Some(DEFAULT_MAX_CONCURRENT_STREAMS);
+        let mut conn = Connection::with_settings(settings, version, remote_addr, router, logger);
+        if let Some(interval) = self.ping_interval {
+            let timeout = self.ping_timeout.unwrap_or(Duration::from_secs(DEFAULT_PING_TIMEOUT_SECS));
+            conn.set_keepalive(interval, timeout);
+        }
+        for &(ref name, policy) in &self.indexing_policies {
+            conn.set_indexing_policy(name, policy);
+        }
+        conn
+    }
+}
+
+impl Http2Proto {
+    /// Creates the proto with default settings. It starts the timer thread that keeps track of
+    /// keepalive deadlines for all of its connections.
+    pub fn new() -> Http2Proto {
+        Http2Proto {
+            logger: None,
+            router: None,
+            http2: Http2Config::default(),
+            timer: Timer::default(),
+        }
+    }
+}
+
+impl ServerProto<TcpStream> for Http2Proto {
+    type Request = Request;
+    type Response = Response;
+    type Transport = Http2Transport<TcpStream>;
+    type BindTransport = io::Result<Http2Transport<TcpStream>>;
+
+    fn bind_transport(&self, io: TcpStream) -> io::Result<Http2Transport<TcpStream>> {
+        let addr = try!(io.peer_addr());
+        let conn = self.http2.connection(HttpVersion::H2c, Some(addr), self.router.clone(), self.logger.clone());
+        let codec = HttpCodec::new(Some(addr), self.router.clone(), self.logger.clone());
+        let mut transport = Http2Transport::detect(io, conn, codec);
+        transport.set_timer(self.timer.clone());
+        Ok(transport)
+    }
+}
+
+/// The protocol a transport is speaking.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Waiting for enough bytes to tell the HTTP/2 preface from an HTTP/1.x request.
+    Detect,
+    Http1,
+    Http2,
+}
+
+/// HTTP/1.x side of a transport that has not switched to HTTP/2.
+struct Http1 {
+    codec: HttpCodec,
+    /// Request id given to the next request.
+    next_id: RequestId,
+    /// Request id whose response is written next.
+    next_response: RequestId,
+    /// Responses that are ready before those of earlier requests.
+    responses: BTreeMap<RequestId, Response>,
+    /// Answer to a rejected request, which takes the place of request `next_id`.
+    rejection: Option<Response>,
+}
+
+/// Drives a `Connection` over an I/O object: yields `(RequestId, Request)` pairs for every
+/// complete stream and accepts `(RequestId, Response)` pairs to send back.
+pub struct Http2Transport<T> {
+    io: T,
+    mode: Mode,
+    /// The HTTP/2 connection; `None` if the transport only ever speaks HTTP/1.x.
+    conn: Option<Connection>,
+    http1: Option<Http1>,
+    rd: EasyBuf,
+    /// HTTP/1.x output, written before anything the connection produces.
+    wr: Vec<u8>,
+    eof: bool,
+    timer: Option<Timer>,
+    /// Wakes the task up when the connection's next keepalive deadline is reached.
+    sleep: Option<(Instant, Sleep)>,
+}
+
+impl<T: Io> Http2Transport<T> {
+    /// Creates a transport for a connection known to speak HTTP/2 from the start.
+    pub fn new(io: T, conn: Connection) -> Http2Transport<T> {
+        Http2Transport {
+            io: io,
+            mode: Mode::Http2,
+            conn: Some(conn),
+            http1: None,
+            rd: EasyBuf::new(),
+            wr: Vec::new(),
+            eof: false,
+            timer: None,
+            sleep: None,
+        }
+    }
+
+    /// Creates a transport that serves HTTP/1.x with `codec` unless the client sends the HTTP/2
+    /// preface or upgrades to h2c.
+    pub fn detect(io: T, conn: Connection, codec: HttpCodec) -> Http2Transport<T> {
+        let mut transport = Http2Transport::new(io, conn);
+        transport.mode = Mode::Detect;
+        transport.http1 = Some(Http1::new(codec));
+        transport
+    }
+
+    /// Creates a transport that only serves HTTP/1.x, e.g. over TLS when the client did not
+    /// negotiate h2. Upgrading to h2c is not possible.
+    pub fn http1(io: T, codec: HttpCodec) -> Http2Transport<T> {
+        Http2Transport {
+            io: io,
+            mode: Mode::Http1,
+            conn: None,
+            http1: Some(Http1::new(codec)),
+            rd: EasyBuf::new(),
+            wr: Vec::new(),
+            eof: false,
+            timer: None,
+            sleep: None,
+        }
+    }
+
+    /// Sets the timer that wakes the transport up for keepalive PINGs. Without one, keepalive
+    /// deadlines are only checked when the socket becomes ready.
+    pub fn set_timer(&mut self, timer: Timer) {
+        self.timer = Some(timer);
+    }
+
+    /// Reads whatever is available from the socket into the read buffer.
+    fn fill(&mut self) -> Poll<usize, io::Error> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        match self.io.read(&mut chunk) {
+            Ok(n) => {
+                self.rd.get_mut().extend_from_slice(&chunk[..n]);
+                Ok(Async::Ready(n))
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes as much of the pending output as the socket accepts.
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        while !self.wr.is_empty() {
+            match self.io.write(&self.wr) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write response")),
+                Ok(n) => { self.wr.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        // The connection queues its SETTINGS right away; they only go out once HTTP/2 is spoken.
+        if self.mode == Mode::Http2 {
+            let conn = self.conn.as_mut().unwrap();
+            while !conn.pending_output().is_empty() {
+                match self.io.write(conn.pending_output()) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame")),
+                    Ok(n) => conn.consume_output(n),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decides between HTTP/1.x and HTTP/2 once the buffered bytes either match the whole
+    /// preface or stop matching it.
+    fn detect_protocol(&mut self) {
+        let len = cmp::min(self.rd.len(), PREFACE.len());
+        if self.rd.as_slice()[..len] != PREFACE[..len] {
+            self.mode = Mode::Http1;
+        } else if len == PREFACE.len() {
+            self.switch_to_http2();
+        }
+    }
+
+    /// Hands the connection, including anything already buffered, over to HTTP/2.
+    fn switch_to_http2(&mut self) {
+        self.mode = Mode::Http2;
+        self.http1 = None;
+        self.conn.as_mut().unwrap().recv(&mut self.rd.get_mut());
+    }
+
+    /// Lets the connection send a keepalive PING or time out, and arranges for the task to be
+    /// woken up when the next deadline is reached.
+    fn poll_keepalive(&mut self) -> io::Result<()> {
+        loop {
+            let now = Instant::now();
+            let deadline = match self.conn.as_mut().unwrap().poll_keepalive(now) {
+                Some(deadline) => deadline,
+                None => {
+                    self.sleep = None;
+                    return Ok(());
+                },
+            };
+            let timer = match self.timer {
+                Some(ref timer) => timer,
+                None => return Ok(()),
+            };
+            if self.sleep.as_ref().map(|sleep| sleep.0) != Some(deadline) {
+                self.sleep = Some((deadline, timer.sleep(deadline - now)));
+            }
+            match self.sleep.as_mut().unwrap().1.poll() {
+                Ok(Async::Ready(())) => self.sleep = None,
+                Ok(Async::NotReady) => return Ok(()),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+
+    /// Decodes the next HTTP/1.x request, switching to HTTP/2 if it asks for an upgrade.
+    fn poll_http1(&mut self) -> io::Result<Option<(RequestId, Request)>> {
+        let http1 = self.http1.as_mut().unwrap();
+        // Nothing more is read once either side has asked for the connection to be closed.
+        if http1.codec.is_closing() {
+            self.eof = true;
+            return Ok(None);
+        }
+        let req = match http1.codec.decode(&mut self.rd) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                // Nothing more is read once a request is rejected.
+                http1.rejection = Some(try!(http1.codec.reject(&e).ok_or(e)));
+                http1.write_rejection(&mut self.wr);
+                self.eof = true;
+                return Ok(None);
+            },
+        };
+
+        // The 101 response has to come right after the responses to earlier requests, so only
+        // upgrade when none are outstanding.
+        if let Some(ref mut conn) = self.conn {
+            if http1.next_response == http1.next_id {
+                if let Some(settings) = upgrade_settings(&req) {
+                    if conn.upgrade(&settings, req.clone()).is_ok() {
+                        self.wr.extend_from_slice(SWITCHING_PROTOCOLS);
+                        self.mode = Mode::Http2;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        let id = http1.next_id;
+        http1.next_id += 1;
+        Ok(Some((id, req)))
+    }
+}
+
+impl Http1 {
+    fn new(codec: HttpCodec) -> Http1 {
+        Http1 {
+            codec: codec,
+            next_id: 0,
+            next_response: 0,
+            responses: BTreeMap::new(),
+            rejection: None,
+        }
+    }
+
+    /// Writes the answer to a rejected request once all earlier requests have been answered.
+    fn write_rejection(&mut self, wr: &mut Vec<u8>) {
+        if self.next_response == self.next_id {
+            if let Some(res) = self.rejection.take() {
+                self.codec.encode_rejection(&res, wr);
+            }
+        }
+    }
+}
+
+/// Returns the decoded HTTP2-Settings of a request asking to upgrade to h2c, or `None` if the
+/// request is to be served as HTTP/1.x.
+fn upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    let upgrade = req.header("Upgrade").unwrap_or("");
+    if !upgrade.split(',').any(|token| UniCase(token.trim()) == UniCase("h2c")) {
+        return None;
+    }
+    // The value is base64url without padding, which `from_base64` accepts.
+    req.header("HTTP2-Settings").and_then(|value| value.trim().from_base64().ok())
+}
+
+impl<T: Io> Stream for Http2Transport<T> {
+    type Item = (RequestId, Request);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(RequestId, Request)>, io::Error> {
+        loop {
+            match self.mode {
+                Mode::Detect => {
+                    self.detect_protocol();
+                    if self.mode != Mode::Detect {
+                        continue;
+                    }
+                },
+                Mode::Http1 => {
+                    if let Some(item) = try!(self.poll_http1()) {
+                        return Ok(Async::Ready(Some(item)));
+                    }
+                    if self.mode == Mode::Http2 {
+                        self.switch_to_http2();
+                        continue;
+                    }
+                },
+                Mode::Http2 => {
+                    if let Some((id, req)) = self.conn.as_mut().unwrap().poll_request() {
+                        return Ok(Async::Ready(Some((id.0 as RequestId, req))));
+                    }
+                    try!(self.poll_keepalive());
+                },
+            }
+
+            // SETTINGS ACKs and the like are produced while reading, so push them out here
+            // rather than waiting for the next response.
+            try!(self.flush());
+
+            if self.eof || (self.mode == Mode::Http2 && self.conn.as_ref().unwrap().is_closed()) {
+                return Ok(Async::Ready(None));
+            }
+
+            if try_ready!(self.fill()) == 0 {
+                self.eof = true;
+                continue;
+            }
+
+            if self.mode == Mode::Http2 {
+                self.conn.as_mut().unwrap().recv(&mut self.rd.get_mut());
+            }
+        }
+    }
+}
+
+impl<T: Io> Sink for Http2Transport<T> {
+    type SinkItem = (RequestId, Response);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: (RequestId, Response)) -> StartSend<(RequestId, Response), io::Error> {
+        let (id, res) = item;
+
+        if let Some(ref mut http1) = self.http1 {
+            http1.responses.insert(id, res);
+            while let Some(res) = http1.responses.remove(&http1.next_response) {
+                try!(http1.codec.encode(res, &mut self.wr));
+                http1.next_response += 1;
+            }
+            http1.write_rejection(&mut self.wr);
+            // A response that closes the connection has to stop the reading too.
+            if http1.codec.is_closing() && !self.eof {
+                task::park().unpark();
+            }
+            return Ok(AsyncSink::Ready);
+        }
+
+        let conn = self.conn.as_mut().unwrap();
+        conn.send_response(StreamIdentifier(id as u32), res);
+        // A response can queue pushed requests without anything arriving on the socket, so
+        // make sure the dispatcher polls for them.
+        if conn.has_pending_requests() {
+            task::park().unpark();
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+
+    use futures::{Async, Sink, Stream};
+    use tokio_core::io::Io;
+
+    use http::HttpCodec;
+    use http2::flag::*;
+    use http2::frame::*;
+    use http2::kind::*;
+    use http2::payload::*;
+    use http2::StreamIdentifier;
+    use http2::connection::{Connection, PREFACE};
+    use hpack::Encoder;
+    use HttpVersion;
+    use Response;
+    use super::{Http2Transport, SWITCHING_PROTOCOLS};
+
+    // A socket with everything the client sends already buffered.
+    struct Mock {
+        rd: Vec<u8>,
+        wr: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.rd.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"));
+            }
+            let n = (&self.rd[..]).read(buf).unwrap();
+            self.rd.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.wr.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    fn transport(rd: Vec<u8>) -> Http2Transport<Mock> {
+        let conn = Connection::new(HttpVersion::H2c, None, None, None);
+        Http2Transport::detect(Mock { rd: rd, wr: Vec::new() }, conn, HttpCodec::new(None, None, None))
+    }
+
+    fn frame(buf: &mut Vec<u8>, payload: Payload, flag: Flag, id: u32) {
+        let frame = Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: payload.kind(),
+                flag: flag,
+                id: StreamIdentifier(id),
+            },
+            payload: payload,
+        };
+        let start = buf.len();
+        buf.resize(start + frame.encoded_len(), 0);
+        frame.encode(&mut buf[start..]);
+    }
+
+    #[test]
+    fn test_prior_knowledge() {
+        let mut rd = PREFACE.to_vec();
+        frame(&mut rd, Payload::Settings(&[]), Flag::empty(), 0);
+        let block = Encoder::new().encode(vec![(&b":method"[..], &b"GET"[..]),
+                                               (b":scheme", b"http"),
+                                               (b":authority", b"localhost"),
+                                               (b":path", b"/")]);
+        frame(&mut rd, Payload::Headers { priority: None, block: &block },
+              Flag::end_headers() | Flag::end_stream(), 1);
+
+        let mut transport = transport(rd);
+        match transport.poll().unwrap() {
+            Async::Ready(Some((id, req))) => {
+                assert_eq!(id, 1);
+                assert_eq!(req.http_version(), HttpVersion::H2c);
+                assert_eq!(req.path(), "/");
+            },
+            _ => panic!("expected a request"),
+        }
+        transport.poll_complete().unwrap();
+        // The server's SETTINGS come first.
+        let header = FrameHeader::parse(&transport.io.wr).unwrap();
+        assert_eq!(header.kind, Kind::Settings);
+    }
+
+    #[test]
+    fn test_http1() {
+        let rd = b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        let mut transport = transport(rd);
+
+        let mut ids = Vec::new();
+        while let Async::Ready(Some((id, req))) = transport.poll().unwrap() {
+            assert_eq!(req.http_version(), HttpVersion::Http11);
+            ids.push(id);
+        }
+        assert_eq!(ids, vec![0, 1]);
+
+        // Responses go out in request order even if the second one is ready first.
+        transport.start_send((1, Response::new().with_body(b"b".to_vec()))).unwrap();
+        assert!(transport.wr.is_empty());
+        transport.start_send((0, Response::new().with_body(b"a".to_vec()))).unwrap();
+        transport.poll_complete().unwrap();
+
+        let out = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200"));
+        assert!(out.find("\r\n\r\na").unwrap() < out.find("\r\n\r\nb").unwrap());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut rd = b"GET /a HTTP/1.1\r\n\
+                       Host: localhost\r\n\
+                       Connection: Upgrade, HTTP2-Settings\r\n\
+                       Upgrade: h2c\r\n\
+                       HTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n".to_vec();
+        rd.extend_from_slice(PREFACE);
+        frame(&mut rd, Payload::Settings(&[]), Flag::empty(), 0);
+
+        let mut transport = transport(rd);
+        match transport.poll().unwrap() {
+            Async::Ready(Some((id, req))) => {
+                assert_eq!(id, 1);
+                assert_eq!(req.path(), "/a");
+            },
+            _ => panic!("expected the upgrade request"),
+        }
+        transport.poll_complete().unwrap();
+
+        let out = transport.io.wr.clone();
+        assert!(out.starts_with(SWITCHING_PROTOCOLS));
+        let header = FrameHeader::parse(&out[SWITCHING_PROTOCOLS.len()..]).unwrap();
+        assert_eq!(header.kind, Kind::Settings);
+        assert!(!header.flag.contains(Flag::ack()));
+
+        // The response to the upgrade request is sent on stream 1.
+        transport.io.wr.clear();
+        transport.start_send((1, Response::new())).unwrap();
+        transport.poll_complete().unwrap();
+        let header = FrameHeader::parse(&transport.io.wr).unwrap();
+        assert_eq!(header.kind, Kind::Headers);
+        assert_eq!(header.id, StreamIdentifier(1));
+    }
+
+    #[test]
+    fn test_upgrade_with_bad_settings() {
+        let rd = b"GET / HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   Upgrade: h2c\r\n\
+                   HTTP2-Settings: AAMAA\r\n\r\n".to_vec();
+        let mut transport = transport(rd);
+        match transport.poll().unwrap() {
+            Async::Ready(Some((id, req))) => {
+                assert_eq!(id, 0);
+                assert_eq!(req.http_version(), HttpVersion::Http11);
+            },
+            _ => panic!("expected an HTTP/1.1 request"),
+        }
+    }
+
+    #[test]
+    fn test_http1_only() {
+        let rd = b"GET / HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   Upgrade: h2c\r\n\
+                   HTTP2-Settings: \r\n\r\n".to_vec();
+        let mut codec = HttpCodec::new(None, None, None);
+        codec.set_scheme("https");
+        let mut transport = Http2Transport::http1(Mock { rd: rd, wr: Vec::new() }, codec);
+
+        match transport.poll().unwrap() {
+            Async::Ready(Some((id, req))) => {
+                assert_eq!(id, 0);
+                assert_eq!(req.scheme(), "https");
+                assert_eq!(req.uri(), "https://localhost/");
+            },
+            _ => panic!("expected an HTTP/1.1 request"),
+        }
+        assert!(transport.wr.is_empty());
+    }
+
+    #[test]
+    fn test_http1_rejection() {
+        let mut rd = b"GET / HTTP/1.1\r\nHost: localhost\r\n".to_vec();
+        rd.extend(vec![b'a'; 64]);
+        rd.extend_from_slice(b": b\r\n\r\n");
+        let mut codec = HttpCodec::new(None, None, None);
+        codec.set_max_head_size(64);
+        let mut transport = Http2Transport::http1(Mock { rd: rd, wr: Vec::new() }, codec);
+
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected the connection to end"),
+        }
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(wr.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_http1_connection_close() {
+        let rd = b"GET / HTTP/1.0\r\n\r\nGET /next HTTP/1.0\r\n\r\n".to_vec();
+        let mut transport = Http2Transport::http1(Mock { rd: rd, wr: Vec::new() }, HttpCodec::new(None, None, None));
+
+        let id = match transport.poll().unwrap() {
+            Async::Ready(Some((id, _))) => id,
+            _ => panic!("expected a request"),
+        };
+        match transport.poll().unwrap() {
+            Async::Ready(None) => {},
+            _ => panic!("expected nothing more to be read"),
+        }
+        transport.start_send((id, Response::new())).unwrap();
+        transport.poll_complete().unwrap();
+        let wr = String::from_utf8(transport.io.wr.clone()).unwrap();
+        assert!(wr.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(wr.contains("\r\nConnection: close\r\n"));
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection settings (RFC 7540 section 6.5).
+//!
+//! Each endpoint has its own set of settings. SETTINGS frames change them one value at a time,
+//! starting from the initial values defined by the RFC.
+
+use http2::payload::*;
+use http2::flow::{DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE};
+
+use http2::HttpError;
+
+/// Initial value of SETTINGS_HEADER_TABLE_SIZE.
+pub const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+
+/// Initial value of SETTINGS_MAX_FRAME_SIZE; also the smallest value allowed.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+
+/// Largest value allowed for SETTINGS_MAX_FRAME_SIZE (2^24 - 1).
+pub const MAX_FRAME_SIZE: u32 = 16777215;
+
+/// The settings of one endpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    /// `None` means unlimited.
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    /// `None` means unlimited.
+    pub max_header_list_size: Option<u32>,
+}
+
+impl Default for Settings {
+    /// The initial values, in effect until a SETTINGS frame changes them.
+    fn default() -> Settings {
+        Settings {
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Applies a single setting. Values out of range are a connection error; unknown settings
+    /// are ignored as the RFC requires.
+    pub fn apply(&mut self, setting: Setting) -> Result<(), HttpError> {
+        let value = setting.value();
+        match setting.identifier() {
+            Some(SettingIdentifier::HeaderTableSize) => self.header_table_size = value,
+            Some(SettingIdentifier::EnablePush) => {
+                self.enable_push = match value {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(HttpError::ProtocolError),
+                };
+            },
+            Some(SettingIdentifier::MaxConcurrentStreams) => self.max_concurrent_streams = Some(value),
+            Some(SettingIdentifier::InitialWindowSize) => {
+                if value > MAX_WINDOW_SIZE {
+                    return Err(HttpError::FlowControlError);
+                }
+                self.initial_window_size = value;
+            },
+            Some(SettingIdentifier::MaxFrameSize) => {
+                if value < DEFAULT_MAX_FRAME_SIZE || value > MAX_FRAME_SIZE {
+                    return Err(HttpError::ProtocolError);
+                }
+                self.max_frame_size = value;
+            },
+            Some(SettingIdentifier::MaxHeaderListSize) => self.max_header_list_size = Some(value),
+            None => {},
+        }
+        Ok(())
+    }
+
+    /// Applies the settings of a SETTINGS payload in order. On error the settings before the
+    /// invalid one stay applied, which does not matter as the connection is closed anyway.
+    pub fn apply_all(&mut self, payload: &[u8]) -> Result<(), HttpError> {
+        for setting in Setting::iter(payload) {
+            try!(self.apply(setting));
+        }
+        Ok(())
+    }
+
+    /// The settings that differ from the initial values, i.e. what a SETTINGS frame has to carry
+    /// to announce these settings.
+    pub fn changes(&self) -> Vec<Setting> {
+        let initial = Settings::default();
+        let mut changes = Vec::new();
+
+        if self.header_table_size != initial.header_table_size {
+            changes.push(Setting::new(SettingIdentifier::HeaderTableSize, self.header_table_size));
+        }
+        if self.enable_push != initial.enable_push {
+            changes.push(Setting::new(SettingIdentifier::EnablePush, self.enable_push as u32));
+        }
+        if let Some(max) = self.max_concurrent_streams {
+            changes.push(Setting::new(SettingIdentifier::MaxConcurrentStreams, max));
+        }
+        if self.initial_window_size != initial.initial_window_size {
+            changes.push(Setting::new(SettingIdentifier::InitialWindowSize, self.initial_window_size));
+        }
+        if self.max_frame_size != initial.max_frame_size {
+            changes.push(Setting::new(SettingIdentifier::MaxFrameSize, self.max_frame_size));
+        }
+        if let Some(max) = self.max_header_list_size {
+            changes.push(Setting::new(SettingIdentifier::MaxHeaderListSize, max));
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::payload::*;
+    use http2::HttpError;
+    use super::Settings;
+
+    #[test]
+    fn test_wire_format() {
+        let setting = Setting::new(SettingIdentifier::InitialWindowSize, 0x01020304);
+        let buf = Setting::encode_all(&[setting]);
+        assert_eq!(buf, vec![0x0, 0x4, 0x1, 0x2, 0x3, 0x4]);
+        assert_eq!(Setting::parse(&buf), setting);
+
+        let unknown = Setting::parse(&[0xab, 0xcd, 0, 0, 0, 1]);
+        assert_eq!(unknown.identifier(), None);
+        assert_eq!(unknown.raw_identifier(), 0xabcd);
+        assert_eq!(Setting::encode_all(&[unknown]), vec![0xab, 0xcd, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut settings = Settings::default();
+        let changes = [Setting::new(SettingIdentifier::HeaderTableSize, 0),
+                       Setting::new(SettingIdentifier::EnablePush, 0),
+                       Setting::new(SettingIdentifier::MaxConcurrentStreams, 100),
+                       Setting::new(SettingIdentifier::InitialWindowSize, 1 << 20),
+                       Setting::new(SettingIdentifier::MaxFrameSize, 1 << 20),
+                       Setting::new(SettingIdentifier::MaxHeaderListSize, 8192),
+                       Setting::from_raw(0xff, 1)];
+        settings.apply_all(&Setting::encode_all(&changes)).unwrap();
+
+        assert_eq!(settings, Settings {
+            header_table_size: 0,
+            enable_push: false,
+            max_concurrent_streams: Some(100),
+            initial_window_size: 1 << 20,
+            max_frame_size: 1 << 20,
+            max_header_list_size: Some(8192),
+        });
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let cases = [(Setting::new(SettingIdentifier::EnablePush, 2), HttpError::ProtocolError),
+                     (Setting::new(SettingIdentifier::InitialWindowSize, 1 << 31), HttpError::FlowControlError),
+                     (Setting::new(SettingIdentifier::MaxFrameSize, 16383), HttpError::ProtocolError),
+                     (Setting::new(SettingIdentifier::MaxFrameSize, 1 << 24), HttpError::ProtocolError)];
+        for &(setting, error) in &cases {
+            assert_eq!(Settings::default().apply(setting), Err(error));
+        }
+
+        let mut settings = Settings::default();
+        settings.apply(Setting::new(SettingIdentifier::InitialWindowSize, (1 << 31) - 1)).unwrap();
+        settings.apply(Setting::new(SettingIdentifier::MaxFrameSize, (1 << 24) - 1)).unwrap();
+    }
+
+    #[test]
+    fn test_changes() {
+        assert!(Settings::default().changes().is_empty());
+
+        let mut settings = Settings::default();
+        settings.enable_push = false;
+        settings.max_header_list_size = Some(16384);
+        let mut applied = Settings::default();
+        applied.apply_all(&Setting::encode_all(&settings.changes())).unwrap();
+        assert_eq!(applied, settings);
+    }
+}
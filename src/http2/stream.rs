@@ -0,0 +1,144 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-stream state of an HTTP/2 connection (RFC 7540 section 5.1).
+
+use http2::HttpError;
+use http2::StreamIdentifier;
+use http2::flow::{Window, RecvWindow};
+use Request;
+use http::HeaderList;
+
+/// The states a stream moves through. Only the transitions a server can take are modelled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StreamState {
+    Idle,
+    ReservedLocal,
+    Open,
+    HalfClosedLocal,
+    HalfClosedRemote,
+    Closed,
+}
+
+/// A single stream within a connection: its state plus the request being received on it.
+#[derive(Debug)]
+pub struct Stream {
+    pub id: StreamIdentifier,
+    pub state: StreamState,
+    /// Decoded request header list, kept until the request is complete.
+    pub headers: HeaderList,
+    /// Request body received so far.
+    pub body: Vec<u8>,
+    /// The request once it was handed to the service; used for the access log, and to send
+    /// the response to a HEAD request without its body.
+    pub request: Option<Request>,
+    /// How much DATA we may still send on the stream.
+    pub send_window: Window,
+    /// How much DATA the peer may still send on the stream.
+    pub recv_window: RecvWindow,
+    /// Response body waiting for flow control window to be sent.
+    pub send_buf: Vec<u8>,
+    /// Whether `send_buf` holds the end of the response, i.e. END_STREAM goes on its last frame.
+    pub send_eos: bool,
+}
+
+impl Stream {
+    pub fn new(id: StreamIdentifier, send_window: u32, recv_window: u32) -> Stream {
+        Stream {
+            id: id,
+            state: StreamState::Idle,
+            headers: HeaderList::new(),
+            body: Vec::new(),
+            request: None,
+            send_window: Window::new(send_window),
+            recv_window: RecvWindow::new(recv_window),
+            send_buf: Vec::new(),
+            send_eos: false,
+        }
+    }
+
+    /// A HEADERS frame was received on the stream.
+    pub fn recv_headers(&mut self, end_stream: bool) -> Result<(), HttpError> {
+        self.state = match self.state {
+            StreamState::Idle if end_stream => StreamState::HalfClosedRemote,
+            StreamState::Idle => StreamState::Open,
+            // Trailers.
+            StreamState::Open if end_stream => StreamState::HalfClosedRemote,
+            StreamState::HalfClosedLocal if end_stream => StreamState::Closed,
+            StreamState::Open | StreamState::HalfClosedLocal => return Err(HttpError::ProtocolError),
+            _ => return Err(HttpError::StreamClosed),
+        };
+        Ok(())
+    }
+
+    /// A DATA frame was received on the stream.
+    pub fn recv_data(&mut self, end_stream: bool) -> Result<(), HttpError> {
+        match self.state {
+            StreamState::Open | StreamState::HalfClosedLocal => {},
+            StreamState::Idle => return Err(HttpError::ProtocolError),
+            _ => return Err(HttpError::StreamClosed),
+        }
+        if end_stream {
+            self.close_remote();
+        }
+        Ok(())
+    }
+
+    /// The response HEADERS were sent on the stream.
+    pub fn send_headers(&mut self) {
+        // A pushed stream opens when its response starts; the peer never sends on it.
+        if self.state == StreamState::ReservedLocal {
+            self.state = StreamState::HalfClosedRemote;
+        }
+    }
+
+    /// The frame carrying END_STREAM was sent on the stream.
+    pub fn send_end_stream(&mut self) {
+        self.state = match self.state {
+            StreamState::Open => StreamState::HalfClosedLocal,
+            _ => StreamState::Closed,
+        };
+    }
+
+    /// RST_STREAM was sent or received.
+    pub fn reset(&mut self) {
+        self.state = StreamState::Closed;
+    }
+
+    /// Whether the peer has finished sending, i.e. the request is complete.
+    pub fn is_remote_closed(&self) -> bool {
+        self.state == StreamState::HalfClosedRemote || self.state == StreamState::Closed
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == StreamState::Closed
+    }
+
+    /// Whether the stream can send a DATA frame right now, given how much of the connection
+    /// window is left.
+    pub fn can_send(&self, connection_window: u32) -> bool {
+        if self.send_buf.is_empty() {
+            // Only an empty frame carrying END_STREAM is left, which costs no window.
+            return self.send_eos;
+        }
+        connection_window > 0 && self.send_window.available() > 0
+    }
+
+    fn close_remote(&mut self) {
+        self.state = match self.state {
+            StreamState::HalfClosedLocal => StreamState::Closed,
+            _ => StreamState::HalfClosedRemote,
+        };
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTPS server protocol.
+//!
+//! `HttpsProto` terminates TLS with a PKCS#12 identity and picks the protocol the client
+//! negotiated with ALPN (RFC 7301): `h2` is served as HTTP/2, anything else as HTTP/1.1. Both run
+//! on `Http2Transport`, so a single `multiplex::ServerProto` covers the two.
+//!
+//! native-tls only exposes ALPN through its OpenSSL backend, and openssl only with its `v102` or
+//! `v110` feature, which the manifest turns on. On platforms using another backend no protocol
+//! is negotiated and every connection is served as HTTP/1.1.
+
+use std::io;
+use std::sync::Arc;
+
+use futures::{future, Future};
+use native_tls::{Pkcs12, TlsAcceptor, TlsAcceptorBuilder};
+use tokio_core::net::TcpStream;
+use tokio_proto::multiplex::ServerProto;
+use tokio_timer::Timer;
+use tokio_tls::{TlsAcceptorExt, TlsStream};
+
+use http::HttpCodec;
+use http2::server::{Http2Config, Http2Transport};
+use HttpVersion;
+use Request;
+use Response;
+use Router;
+use Logger;
+
+/// ALPN protocol identifier of HTTP/2 over TLS.
+pub const ALPN_H2: &'static [u8] = b"h2";
+/// ALPN protocol identifier of HTTP/1.1.
+pub const ALPN_HTTP11: &'static [u8] = b"http/1.1";
+
+/// Proto for serving HTTPS connections.
+///
+/// Request bodies are buffered whole before the service is called, and responses are sent from
+/// their `body` buffer, over HTTP/1.1 as well as HTTP/2; unlike `HttpProto`, this proto does not
+/// stream them.
+pub struct HttpsProto {
+    pub logger: Option<Logger>,
+    pub router: Option<Router>,
+    /// Settings for the connections that negotiated `h2`.
+    pub http2: Http2Config,
+    acceptor: Arc<TlsAcceptor>,
+    timer: Timer,
+}
+
+impl HttpsProto {
+    /// Creates the proto from a DER encoded PKCS#12 archive holding the server's certificate and
+    /// private key.
+    pub fn new(der: &[u8], password: &str) -> ::Result<HttpsProto> {
+        let identity = try!(Pkcs12::from_der(der, password));
+        let mut builder = try!(TlsAcceptor::builder(identity));
+        try!(set_alpn_protocols(&mut builder, &[ALPN_H2, ALPN_HTTP11]));
+
+        Ok(HttpsProto {
+            logger: None,
+            router: None,
+            http2: Http2Config::default(),
+            acceptor: Arc::new(try!(builder.build())),
+            timer: Timer::default(),
+        })
+    }
+}
+
+impl ServerProto<TcpStream> for HttpsProto {
+    type Request = Request;
+    type Response = Response;
+    type Transport = Http2Transport<TlsStream<TcpStream>>;
+    type BindTransport = Box<Future<Item = Http2Transport<TlsStream<TcpStream>>, Error = io::Error>>;
+
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        let addr = match io.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let router = self.router.clone();
+        let logger = self.logger.clone();
+        let http2 = self.http2.clone();
+        let timer = self.timer.clone();
+
+        Box::new(self.acceptor.accept_async(io).map(move |io| {
+            if negotiated_protocol(&io) == Some(ALPN_H2.to_vec()) {
+                let conn = http2.connection(HttpVersion::H2, Some(addr), router, logger);
+                let mut transport = Http2Transport::new(io, conn);
+                transport.set_timer(timer);
+                transport
+            } else {
+                let mut codec = HttpCodec::new(Some(addr), router, logger);
+                codec.set_scheme("https");
+                Http2Transport::http1(io, codec)
+            }
+        }))
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+fn set_alpn_protocols(builder: &mut TlsAcceptorBuilder, protocols: &[&[u8]]) -> io::Result<()> {
+    use native_tls::backend::openssl::TlsAcceptorBuilderExt;
+
+    builder.builder_mut()
+           .builder_mut()
+           .set_alpn_protocols(protocols)
+           .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "windows"))]
+fn set_alpn_protocols(_: &mut TlsAcceptorBuilder, _: &[&[u8]]) -> io::Result<()> {
+    Ok(())
+}
+
+/// The protocol selected during the handshake, if any.
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+fn negotiated_protocol(io: &TlsStream<TcpStream>) -> Option<Vec<u8>> {
+    use native_tls::backend::openssl::TlsStreamExt;
+
+    io.get_ref().raw_stream().ssl().selected_alpn_protocol().map(|p| p.to_vec())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "windows"))]
+fn negotiated_protocol(_: &TlsStream<TcpStream>) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(all(test, not(any(target_os = "macos", target_os = "ios", target_os = "windows"))))]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::net::TcpStream as StdTcpStream;
+    use std::path::Path;
+    use std::thread;
+
+    use futures::{Future, Stream};
+    use native_tls::{Certificate, TlsConnector};
+    use native_tls::backend::openssl::TlsConnectorBuilderExt;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_tls::TlsAcceptorExt;
+
+    use super::{negotiated_protocol, HttpsProto, ALPN_H2, ALPN_HTTP11};
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name);
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    /// Accepts a connection from a client offering `protocols` with the proto's acceptor, and
+    /// returns the protocol the handshake settled on.
+    fn negotiate(protocols: &'static [&'static [u8]]) -> Option<Vec<u8>> {
+        let proto = HttpsProto::new(&fixture("identity.p12"), "test").unwrap();
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut builder = TlsConnector::builder().unwrap();
+            builder.add_root_certificate(Certificate::from_der(&fixture("cert.der")).unwrap()).unwrap();
+            if !protocols.is_empty() {
+                builder.builder_mut().builder_mut().set_alpn_protocols(protocols).unwrap();
+            }
+            let connector = builder.build().unwrap();
+            connector.connect("localhost", StdTcpStream::connect(addr).unwrap()).unwrap()
+        });
+
+        let (io, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e)).unwrap();
+        let io = core.run(proto.acceptor.accept_async(io.unwrap().0)).unwrap();
+        client.join().unwrap();
+        negotiated_protocol(&io)
+    }
+
+    #[test]
+    fn test_alpn_h2() {
+        assert_eq!(negotiate(&[ALPN_H2, ALPN_HTTP11]), Some(ALPN_H2.to_vec()));
+    }
+
+    #[test]
+    fn test_alpn_http11_fallback() {
+        assert_eq!(negotiate(&[ALPN_HTTP11]), Some(ALPN_HTTP11.to_vec()));
+        // A client that does not use ALPN is served as HTTP/1.1 as well.
+        assert_eq!(negotiate(&[]), None);
+    }
+}
@@ -37,6 +37,12 @@
 //! This library supplies the required modules to implement HTTP/2 which includes the HPACK header
 //! compression that includes the Huffman encoding/decoding features. This version will support
 //! Multiplexing which is required for HTTP/2.
+//!
+//! The `http2` (frame parsing) and `hpack` modules are built with the `http2` cargo feature,
+//! which is enabled by default. `Http2Proto` serves HTTP/2 connections through tokio-proto's
+//! multiplex protocol using the same `Router` and handlers as `HttpProto`.
+//! `HttpsProto` terminates TLS and serves HTTP/2 or HTTP/1.1 depending on what the client
+//! negotiated with ALPN.
 
 #[macro_use] extern crate log;
 #[macro_use] extern crate bitflags;
@@ -58,7 +64,7 @@ extern crate tempdir;
 // extern crate multipart;
 
 // extern crate cookie;
-extern crate futures;
+#[macro_use] extern crate futures;
 extern crate futures_cpupool;
 extern crate httparse;
 extern crate net2;
@@ -71,10 +77,12 @@ extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_tls;
+extern crate tokio_timer;
 
-// For now...
-// pub mod http2;
-// pub mod hpack;
+#[cfg(feature = "http2")]
+pub mod http2;
+#[cfg(feature = "http2")]
+pub mod hpack;
 
 pub mod http;
 pub mod version;
@@ -90,6 +98,10 @@ pub use error::{Result, Error};
 pub use url::Url;
 pub use method::Method;
 pub use http::{Request, Response};
+#[cfg(feature = "http2")]
+pub use http2::server::{Http2Config, Http2Proto};
+#[cfg(feature = "http2")]
+pub use http2::tls::HttpsProto;
 pub use router::route::route::Route;
 pub use router::Router;
 pub use router::builder::RouterBuilder;
@@ -103,6 +115,13 @@ pub type Headers = Vec<(String, String)>;
 // NOTE: May want to add an `enum` of options as the second param for a Handler that allows for flexible options
 // pub enum Options...
 // (i.e., pub type Handler = fn(Request, Options) -> Response;)
+/// Builds the response to a request the `Router` matched it with.
+///
+/// Handlers read the body with `Request::payload` or `Read`, which give the body alone over
+/// HTTP/1.x and HTTP/2 alike, once it is buffered. `Http2Proto` and `HttpsProto` buffer bodies
+/// before the request is handed out. `HttpProto` streams them, so there a handler has to be
+/// called with the request that `Request::buffer_body` resolves to. Never wait on `take_body`
+/// inside a handler: the body cannot arrive until the handler returns.
 pub type Handler = fn(Request, String) -> Response;
 
 
@@ -104,6 +104,16 @@ impl Router {
         }
     }
 
+    /// Finds the paths to push along with the response for the given method and path, as set
+    /// with `RouteBuilder::push`.
+    pub fn find_pushes(&self, method: Method, request_path: &str) -> Vec<String> {
+        self.find_matching_routes(request_path)
+            .iter()
+            .filter(|route| route.method == method)
+            .flat_map(|route| route.pushes.iter().cloned())
+            .collect()
+    }
+
     /// Returns vector of `Route`s that match to given path.
     fn find_matching_routes(&self, request_path: &str) -> Vec<&Route> {
         self.routes.iter()
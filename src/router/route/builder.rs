@@ -26,6 +26,15 @@ impl RouteBuilder {
         }
     }
 
+    /// Pushes the resource at `path` to HTTP/2 clients along with every response of the route.
+    ///
+    /// Route::get("/").push("/app.css").using(index_handler);
+    ///
+    pub fn push(mut self, path: &str) -> RouteBuilder {
+        self.route.pushes.push(path.to_string());
+        self
+    }
+
     /// Completes the building process by taking the handler to process the request.
     ///
     /// Returns created route.
@@ -34,7 +34,10 @@ pub struct Route {
     ///
     /// This should be method that accepts Request and responds with Response:
     ///
-    pub handler: Handler
+    pub handler: Handler,
+
+    /// Paths pushed to HTTP/2 clients whenever this route is served
+    pub pushes: Vec<String>,
 }
 
 impl Route {
@@ -88,7 +91,8 @@ impl Default for Route {
         Route {
             method: Method::Get,
             path: RequestPath::new("/"),
-            handler: handlers::not_implemented_handler
+            handler: handlers::not_implemented_handler,
+            pushes: Vec::new(),
         }
     }
 }